@@ -0,0 +1,219 @@
+//! Running host commands (`zfs`, `zonecfg`, `zoneadm`, ...) behind a trait, so the logic
+//! driving them can be tested without the tools.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Mutex;
+
+use crate::error::{Result, VmError};
+
+/// Exit status and output of a finished command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// Exit code, `None` if the process was killed by a signal.
+    pub status: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    /// Successful exit with the given stdout.
+    pub fn ok(stdout: impl Into<String>) -> Self {
+        Self {
+            status: Some(0),
+            stdout: stdout.into(),
+            stderr: String::new(),
+        }
+    }
+
+    /// Failed exit with the given code and stderr.
+    pub fn failed(code: i32, stderr: impl Into<String>) -> Self {
+        Self {
+            status: Some(code),
+            stdout: String::new(),
+            stderr: stderr.into(),
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.status == Some(0)
+    }
+}
+
+/// Runs external commands.
+pub trait CommandRunner: Send + Sync {
+    /// Run `program` with `args` and collect its output, whatever the exit status.
+    fn output(
+        &self,
+        program: &str,
+        args: &[&str],
+    ) -> impl Future<Output = Result<CommandOutput>> + Send;
+
+    /// Run `program` with `args`, turning a non-zero exit into [`VmError::CommandFailed`].
+    fn run(
+        &self,
+        program: &str,
+        args: &[&str],
+    ) -> impl Future<Output = Result<CommandOutput>> + Send {
+        async move {
+            let output = self.output(program, args).await?;
+            if output.success() {
+                Ok(output)
+            } else {
+                Err(VmError::CommandFailed {
+                    command: command_line(program, args),
+                    status: output.status,
+                    stderr: output.stderr.trim().to_string(),
+                })
+            }
+        }
+    }
+}
+
+fn command_line(program: &str, args: &[&str]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().copied())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Runs commands on the host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    async fn output(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let output = tokio::process::Command::new(program)
+            .args(args)
+            .output()
+            .await
+            .map_err(|e| VmError::CommandFailed {
+                command: command_line(program, args),
+                status: None,
+                stderr: format!("cannot run {program}: {e}"),
+            })?;
+        Ok(CommandOutput {
+            status: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+/// Fake runner that records every command and answers from scripted responses.
+///
+/// Commands without a matching response succeed with empty output.
+#[derive(Debug, Default)]
+pub struct RecordingRunner {
+    calls: Mutex<Vec<String>>,
+    responses: Mutex<Vec<(String, VecDeque<CommandOutput>)>>,
+}
+
+impl RecordingRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer commands whose command line starts with `prefix` (e.g. `"zfs list"`) with
+    /// `output`. Registering the same prefix again queues further answers, used in order; the
+    /// last one keeps being repeated. Longer prefixes take precedence.
+    pub fn on(self, prefix: &str, output: CommandOutput) -> Self {
+        {
+            let mut responses = self.responses.lock().unwrap();
+            match responses.iter_mut().find(|(p, _)| p == prefix) {
+                Some((_, queue)) => queue.push_back(output),
+                None => responses.push((prefix.to_string(), VecDeque::from([output]))),
+            }
+        }
+        self
+    }
+
+    /// Command lines run so far.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl CommandRunner for RecordingRunner {
+    async fn output(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let line = command_line(program, args);
+        self.calls.lock().unwrap().push(line.clone());
+
+        let mut responses = self.responses.lock().unwrap();
+        let matching = responses
+            .iter_mut()
+            .filter(|(prefix, _)| line.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len());
+        Ok(match matching {
+            Some((_, queue)) if queue.len() > 1 => queue.pop_front().unwrap(),
+            Some((_, queue)) => queue[0].clone(),
+            None => CommandOutput::ok(""),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn failure_carries_command_and_stderr() {
+        let runner = RecordingRunner::new().on(
+            "zoneadm -z web boot",
+            CommandOutput::failed(1, "zoneadm: zone 'web': must be installed\n"),
+        );
+        let err = runner
+            .run("zoneadm", &["-z", "web", "boot"])
+            .await
+            .unwrap_err();
+        match err {
+            VmError::CommandFailed {
+                command,
+                status,
+                stderr,
+            } => {
+                assert_eq!(command, "zoneadm -z web boot");
+                assert_eq!(status, Some(1));
+                assert_eq!(stderr, "zoneadm: zone 'web': must be installed");
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+        assert_eq!(runner.calls(), ["zoneadm -z web boot"]);
+    }
+
+    #[tokio::test]
+    async fn responses_match_longest_prefix_and_queue() {
+        let runner = RecordingRunner::new()
+            .on("zfs", CommandOutput::failed(1, "no"))
+            .on("zfs list rpool", CommandOutput::ok("first"))
+            .on("zfs list rpool", CommandOutput::ok("second"));
+
+        let out = runner.output("zfs", &["list", "rpool"]).await.unwrap();
+        assert_eq!(out.stdout, "first");
+        let out = runner.output("zfs", &["list", "rpool"]).await.unwrap();
+        assert_eq!(out.stdout, "second");
+        let out = runner.output("zfs", &["list", "rpool"]).await.unwrap();
+        assert_eq!(out.stdout, "second");
+        assert!(!runner.output("zfs", &["get"]).await.unwrap().success());
+        assert!(runner.output("zoneadm", &[]).await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn system_runner_reports_exit_codes() {
+        let out = SystemRunner
+            .output("sh", &["-c", "echo hi; exit 3"])
+            .await
+            .unwrap();
+        assert_eq!(out.status, Some(3));
+        assert_eq!(out.stdout, "hi\n");
+
+        let err = SystemRunner
+            .run("/nonexistent/tool", &[])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, VmError::CommandFailed { status: None, .. }),
+            "got: {err}"
+        );
+    }
+}
@@ -0,0 +1,497 @@
+//! Process launchers for the QEMU backend.
+//!
+//! By default QEMU daemonizes itself and records its PID in `qemu.pid`. Alternatively it can be
+//! started as a `systemd-run --user` transient unit, in which case systemd supervises the process
+//! and keeps its output in the journal, and liveness is answered by the unit status instead of a
+//! (reusable) PID.
+
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use tracing::{debug, info, warn};
+
+use crate::error::{Result, VmError, format_log_tail};
+use crate::types::VmHandle;
+
+/// Environment variable selecting the launcher for newly started VMs.
+pub const LAUNCHER_ENV: &str = "VMCTL_QEMU_LAUNCHER";
+
+/// File in the VM work directory that receives QEMU's command line and output.
+pub const QEMU_LOG: &str = "qemu.log";
+
+/// File mode creation mask for the QEMU process, keeping its sockets and logs owner-only.
+const QEMU_UMASK: libc::mode_t = 0o077;
+
+/// Number of log lines embedded in spawn/connect diagnostics.
+const LOG_TAIL_LINES: usize = 20;
+
+/// How QEMU processes are started and supervised.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Launcher {
+    /// `qemu -daemonize -pidfile qemu.pid` — QEMU detaches itself, nobody supervises it.
+    #[default]
+    Daemonize,
+    /// `systemd-run --user --unit vmctl-<name>` — QEMU runs in the foreground of a transient unit.
+    Systemd,
+}
+
+impl FromStr for Launcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "daemonize" | "daemon" | "" => Ok(Self::Daemonize),
+            "systemd" | "systemd-run" => Ok(Self::Systemd),
+            other => Err(format!(
+                "unknown QEMU launcher '{other}' (expected \"daemonize\" or \"systemd\")"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Launcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Daemonize => write!(f, "daemonize"),
+            Self::Systemd => write!(f, "systemd"),
+        }
+    }
+}
+
+/// Result of launching a QEMU process.
+#[derive(Debug, Clone, Default)]
+pub struct Launched {
+    /// PID of the QEMU process, if it could be determined.
+    pub pid: Option<u32>,
+    /// Name of the transient systemd unit (systemd launcher only).
+    pub unit: Option<String>,
+}
+
+impl Launcher {
+    /// Read the launcher from `VMCTL_QEMU_LAUNCHER`, falling back to [`Launcher::Daemonize`].
+    pub fn from_env() -> Self {
+        match std::env::var(LAUNCHER_ENV) {
+            Ok(v) => v.parse().unwrap_or_else(|e| {
+                warn!(error = %e, "ignoring {LAUNCHER_ENV}");
+                Self::Daemonize
+            }),
+            Err(_) => Self::Daemonize,
+        }
+    }
+
+    /// The launcher that was used to start an existing VM.
+    ///
+    /// Handles without a recorded systemd unit were started with `-daemonize`.
+    pub fn for_handle(vm: &VmHandle) -> Self {
+        if vm.systemd_unit.is_some() {
+            Self::Systemd
+        } else {
+            Self::Daemonize
+        }
+    }
+
+    /// Launch QEMU with the given arguments and return once the process is up.
+    ///
+    /// The full command line is appended to `qemu.log` in the work directory. With the daemonize
+    /// launcher QEMU's stdout/stderr go there as well; with systemd they go to the unit's journal.
+    pub async fn launch(&self, binary: &Path, args: &[String], vm: &VmHandle) -> Result<Launched> {
+        let log_path = vm.work_dir.join(QEMU_LOG);
+        match self {
+            Self::Daemonize => {
+                let mut args = args.to_vec();
+                args.extend([
+                    "-daemonize".into(),
+                    "-pidfile".into(),
+                    vm.work_dir.join("qemu.pid").display().to_string(),
+                ]);
+                debug!(args = ?args, "QEMU command line");
+
+                let mut log = open_log(&log_path, binary, &args)?;
+                let stderr = log.try_clone()?;
+                let _ = writeln!(log);
+
+                // QEMU reports startup errors before it daemonizes, so the parent's output is
+                // exactly what we want to keep.
+                let mut cmd = tokio::process::Command::new(binary);
+                cmd.args(&args)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::from(log))
+                    .stderr(Stdio::from(stderr));
+                // Sockets, pidfile and console log created by QEMU are private to the owner.
+                // SAFETY: umask(2) is async-signal-safe and touches no memory of the parent.
+                unsafe {
+                    cmd.pre_exec(|| {
+                        libc::umask(QEMU_UMASK);
+                        Ok(())
+                    });
+                }
+                let status = cmd.status().await.map_err(|e| VmError::QemuSpawnFailed {
+                    source: e,
+                    log_tail: String::new(),
+                })?;
+
+                if !status.success() {
+                    return Err(VmError::QemuSpawnFailed {
+                        source: std::io::Error::other(format!("QEMU exited with status {status}")),
+                        log_tail: format_log_tail(&log_path, LOG_TAIL_LINES),
+                    });
+                }
+
+                Ok(Launched {
+                    pid: read_pid(&vm.work_dir).await,
+                    unit: None,
+                })
+            }
+            Self::Systemd => {
+                let unit = unit_name(&vm.name);
+
+                // A failed unit of the same name would block the new one.
+                let _ = systemctl(&["reset-failed", &unit]).await;
+
+                // A VM with a TPM goes down with its emulator instead of running on without it.
+                let swtpm_unit = vm.tpm.map(|_| super::swtpm::unit_name(&vm.name));
+                let run_args = systemd_run_args(
+                    &unit,
+                    &format!("vmctl VM {}", vm.name),
+                    swtpm_unit.as_deref(),
+                    binary,
+                    args,
+                );
+                debug!(args = ?run_args, "systemd-run command line");
+
+                let mut log = open_log(&log_path, Path::new("systemd-run"), &run_args)?;
+
+                let output = tokio::process::Command::new("systemd-run")
+                    .args(&run_args)
+                    .stdin(Stdio::null())
+                    .output()
+                    .await
+                    .map_err(|e| VmError::QemuSpawnFailed {
+                        source: e,
+                        log_tail: String::new(),
+                    })?;
+                let _ = log.write_all(&output.stderr);
+                let _ = writeln!(log);
+
+                if !output.status.success() {
+                    return Err(VmError::QemuSpawnFailed {
+                        source: std::io::Error::other(format!(
+                            "systemd-run exited with status {}: {}",
+                            output.status,
+                            String::from_utf8_lossy(&output.stderr).trim()
+                        )),
+                        log_tail: self.failure_log(vm).await,
+                    });
+                }
+
+                info!(name = %vm.name, unit = %unit, "QEMU: launched as transient systemd unit");
+
+                Ok(Launched {
+                    pid: unit_main_pid(&unit).await,
+                    unit: Some(unit),
+                })
+            }
+        }
+    }
+
+    /// Collect diagnostics after QEMU failed to come up and return the formatted log tail.
+    ///
+    /// For systemd-launched VMs the unit's journal is appended to `qemu.log` first, so the log
+    /// in the work directory always holds the reason for the failure.
+    pub async fn failure_log(&self, vm: &VmHandle) -> String {
+        let log_path = vm.work_dir.join(QEMU_LOG);
+        if let Self::Systemd = self {
+            let unit = vm
+                .systemd_unit
+                .clone()
+                .unwrap_or_else(|| unit_name(&vm.name));
+            let journal = tokio::process::Command::new("journalctl")
+                .args(["--user", "--unit", &unit, "--output=cat", "--no-pager"])
+                .args(["--lines", &LOG_TAIL_LINES.to_string()])
+                .stdin(Stdio::null())
+                .output()
+                .await;
+            if let Ok(out) = journal {
+                if let Ok(mut f) = std::fs::OpenOptions::new().append(true).open(&log_path) {
+                    let _ = writeln!(f, "--- journal of {unit} ---");
+                    let _ = f.write_all(&out.stdout);
+                }
+            }
+        }
+        format_log_tail(&log_path, LOG_TAIL_LINES)
+    }
+
+    /// PID of the running QEMU process, if any.
+    pub async fn pid(&self, vm: &VmHandle) -> Option<u32> {
+        match self {
+            Self::Daemonize => read_pid(&vm.work_dir).await,
+            Self::Systemd => match vm.systemd_unit {
+                Some(ref unit) => unit_main_pid(unit).await,
+                None => None,
+            },
+        }
+    }
+
+    /// Whether the QEMU process is still running.
+    pub async fn is_running(&self, vm: &VmHandle) -> bool {
+        match self {
+            Self::Daemonize => match read_pid(&vm.work_dir).await {
+                Some(pid) => pid_alive(pid),
+                None => false,
+            },
+            Self::Systemd => match vm.systemd_unit {
+                Some(ref unit) => systemctl(&["is-active", "--quiet", unit])
+                    .await
+                    .map(|o| o.status.success())
+                    .unwrap_or(false),
+                None => false,
+            },
+        }
+    }
+
+    /// Ask the QEMU process to terminate (SIGTERM).
+    pub async fn terminate(&self, vm: &VmHandle) {
+        self.send_signal(vm, libc::SIGTERM, "SIGTERM").await;
+    }
+
+    /// Forcefully kill the QEMU process (SIGKILL).
+    pub async fn kill(&self, vm: &VmHandle) {
+        self.send_signal(vm, libc::SIGKILL, "SIGKILL").await;
+    }
+
+    async fn send_signal(&self, vm: &VmHandle, signal: i32, signal_name: &str) {
+        match self {
+            Self::Daemonize => {
+                if let Some(pid) = read_pid(&vm.work_dir).await {
+                    unsafe {
+                        libc::kill(pid as i32, signal);
+                    }
+                }
+            }
+            Self::Systemd => {
+                if let Some(ref unit) = vm.systemd_unit {
+                    let _ = systemctl(&["kill", &format!("--signal={signal_name}"), unit]).await;
+                }
+            }
+        }
+    }
+
+    /// Release launcher bookkeeping for a VM that is being destroyed.
+    pub async fn cleanup(&self, vm: &VmHandle) {
+        if let (Self::Systemd, Some(unit)) = (self, &vm.systemd_unit) {
+            let _ = systemctl(&["stop", unit]).await;
+            let _ = systemctl(&["reset-failed", unit]).await;
+        }
+    }
+}
+
+/// Transient unit name for a VM: `vmctl-<name>.service`, with characters systemd does not
+/// accept in unit names replaced by `-`.
+pub fn unit_name(vm_name: &str) -> String {
+    let sanitized: String = vm_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("vmctl-{sanitized}.service")
+}
+
+/// Build the `systemd-run` argument list that runs `binary` (QEMU, or a helper such as swtpm)
+/// in the foreground of a transient unit. The unit is stopped together with `binds_to`.
+pub(super) fn systemd_run_args(
+    unit: &str,
+    description: &str,
+    binds_to: Option<&str>,
+    binary: &Path,
+    program_args: &[String],
+) -> Vec<String> {
+    let mut args: Vec<String> = vec![
+        "--user".into(),
+        format!("--unit={unit}"),
+        format!("--description={description}"),
+        // Garbage-collect the unit once the process exits, even if it failed.
+        "--collect".into(),
+        "--quiet".into(),
+        // Give the process a chance to flush on SIGTERM before systemd escalates.
+        "--property=KillMode=mixed".into(),
+        format!("--property=UMask={QEMU_UMASK:04o}"),
+    ];
+    if let Some(other) = binds_to {
+        args.extend([
+            format!("--property=BindsTo={other}"),
+            format!("--property=After={other}"),
+        ]);
+    }
+    args.extend(["--".into(), binary.display().to_string()]);
+    args.extend(program_args.iter().cloned());
+    args
+}
+
+/// Open `qemu.log` for appending and record the command about to be run.
+fn open_log(path: &Path, program: &Path, args: &[String]) -> Result<std::fs::File> {
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    writeln!(f, "=== launch at unix time {now} ===")?;
+    writeln!(f, "$ {}", shell_join(program, args))?;
+    Ok(f)
+}
+
+/// Render a command line so it can be copied into a shell.
+fn shell_join(program: &Path, args: &[String]) -> String {
+    std::iter::once(program.display().to_string())
+        .chain(args.iter().cloned())
+        .map(|a| {
+            if !a.is_empty()
+                && a.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_=,.:/+@%".contains(c))
+            {
+                a
+            } else {
+                format!("'{}'", a.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Read PID from the pidfile in the work directory.
+async fn read_pid(work_dir: &Path) -> Option<u32> {
+    read_pidfile(&work_dir.join("qemu.pid")).await
+}
+
+/// Read a PID from a pidfile.
+pub(super) async fn read_pidfile(path: &Path) -> Option<u32> {
+    tokio::fs::read_to_string(path)
+        .await
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+}
+
+/// Check if a process with the given PID is alive.
+pub(super) fn pid_alive(pid: u32) -> bool {
+    // Signal 0 checks if process exists without sending a signal
+    unsafe { libc::kill(pid as i32, 0) == 0 }
+}
+
+/// Query the main PID of a user unit. Returns `None` if the unit has no running process.
+async fn unit_main_pid(unit: &str) -> Option<u32> {
+    let output = systemctl(&["show", "--property=MainPID", "--value", unit])
+        .await
+        .ok()?;
+    match String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<u32>()
+    {
+        Ok(0) | Err(_) => None,
+        Ok(pid) => Some(pid),
+    }
+}
+
+pub(super) async fn systemctl(args: &[&str]) -> std::io::Result<std::process::Output> {
+    tokio::process::Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_launcher() {
+        assert_eq!("systemd".parse::<Launcher>().unwrap(), Launcher::Systemd);
+        assert_eq!(
+            "Daemonize".parse::<Launcher>().unwrap(),
+            Launcher::Daemonize
+        );
+        assert!("runit".parse::<Launcher>().is_err());
+    }
+
+    #[test]
+    fn unit_name_is_sanitized() {
+        assert_eq!(unit_name("web-1"), "vmctl-web-1.service");
+        assert_eq!(unit_name("my vm/2"), "vmctl-my-vm-2.service");
+    }
+
+    #[test]
+    fn systemd_run_keeps_qemu_in_foreground() {
+        let args = systemd_run_args(
+            "vmctl-web.service",
+            "vmctl VM web",
+            None,
+            Path::new("qemu-system-x86_64"),
+            &["-m".into(), "1024M".into()],
+        );
+        assert!(args.contains(&"--unit=vmctl-web.service".to_string()));
+        let sep = args.iter().position(|a| a == "--").unwrap();
+        assert_eq!(
+            &args[sep + 1..],
+            &["qemu-system-x86_64", "-m", "1024M"].map(String::from)
+        );
+        assert!(!args.iter().any(|a| a == "-daemonize"));
+        assert!(args.contains(&"--property=UMask=0077".to_string()));
+        assert!(!args.iter().any(|a| a.starts_with("--property=BindsTo")));
+    }
+
+    #[test]
+    fn systemd_run_binds_qemu_to_swtpm() {
+        let args = systemd_run_args(
+            "vmctl-web.service",
+            "vmctl VM web",
+            Some("vmctl-web-swtpm.service"),
+            Path::new("qemu-system-x86_64"),
+            &[],
+        );
+        let sep = args.iter().position(|a| a == "--").unwrap();
+        assert!(args[..sep].contains(&"--property=BindsTo=vmctl-web-swtpm.service".to_string()));
+        assert!(args[..sep].contains(&"--property=After=vmctl-web-swtpm.service".to_string()));
+    }
+
+    #[test]
+    fn shell_join_quotes_when_needed() {
+        let line = shell_join(
+            Path::new("qemu-system-x86_64"),
+            &["-name".into(), "my vm".into(), "-m".into(), "1024M".into()],
+        );
+        assert_eq!(line, "qemu-system-x86_64 -name 'my vm' -m 1024M");
+    }
+
+    #[test]
+    fn log_tail_is_embedded_in_help() {
+        use miette::Diagnostic;
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join(QEMU_LOG);
+        std::fs::write(
+            &log,
+            "$ qemu-system-x86_64 ...\nfailed to create tun device\n",
+        )
+        .unwrap();
+
+        let err = VmError::QemuSpawnFailed {
+            source: std::io::Error::other("QEMU exited with status 1"),
+            log_tail: String::new(),
+        }
+        .with_log_tail(format_log_tail(&log, LOG_TAIL_LINES));
+        let help = err.help().unwrap().to_string();
+        assert!(help.contains("failed to create tun device"), "got: {help}");
+    }
+}
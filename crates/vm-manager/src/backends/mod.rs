@@ -1,12 +1,22 @@
+pub mod command;
 pub mod noop;
 
+#[cfg(target_os = "linux")]
+pub mod launcher;
 #[cfg(target_os = "linux")]
 pub mod qemu;
 #[cfg(target_os = "linux")]
 pub mod qmp;
+#[cfg(target_os = "linux")]
+pub mod sandbox;
+#[cfg(target_os = "linux")]
+pub mod swtpm;
 
 #[cfg(target_os = "illumos")]
 pub mod propolis;
+pub mod propolis_api;
+pub mod zone;
+pub mod zvol;
 
 use std::time::Duration;
 
@@ -32,9 +42,17 @@ impl RouterHypervisor {
     pub fn new(bridge: Option<String>, zfs_pool: Option<String>) -> Self {
         #[cfg(target_os = "linux")]
         {
+            let config = crate::config::GlobalConfig::load().unwrap_or_else(|e| {
+                tracing::warn!(error = %e, "ignoring unreadable global config");
+                Default::default()
+            });
             RouterHypervisor {
                 noop: noop::NoopBackend,
-                qemu: Some(qemu::QemuBackend::new(None, None, bridge)),
+                qemu: Some(
+                    qemu::QemuBackend::new(None, None, bridge)
+                        .with_launcher(launcher::Launcher::from_env())
+                        .with_sandbox(config.sandbox),
+                ),
             }
         }
         #[cfg(target_os = "illumos")]
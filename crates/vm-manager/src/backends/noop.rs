@@ -33,6 +33,11 @@ impl Hypervisor for NoopBackend {
             network: spec.network.clone(),
             ssh_host_port: None,
             mac_addr: None,
+            systemd_unit: None,
+            sandbox: Default::default(),
+            qemu: Default::default(),
+            tpm: None,
+            propolis_addr: None,
         })
     }
 
@@ -92,6 +97,9 @@ mod tests {
             network: NetworkConfig::None,
             cloud_init: None,
             ssh: None,
+            sandbox: Default::default(),
+            qemu: Default::default(),
+            tpm: None,
         }
     }
 
@@ -158,6 +166,11 @@ mod tests {
             network: NetworkConfig::User,
             ssh_host_port: Some(10022),
             mac_addr: Some("52:54:00:ab:cd:ef".into()),
+            systemd_unit: None,
+            sandbox: Default::default(),
+            qemu: Default::default(),
+            tpm: None,
+            propolis_addr: None,
         };
         let json = serde_json::to_string_pretty(&handle).unwrap();
         let parsed: VmHandle = serde_json::from_str(&json).unwrap();
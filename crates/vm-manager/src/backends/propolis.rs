@@ -7,87 +7,106 @@ use std::time::Duration;
 
 use tracing::{info, warn};
 
+use super::command::{CommandRunner, SystemRunner};
+use super::propolis_api::{
+    InstanceSpecBuilder, InstanceState, InstanceStateRequested, PropolisClient, spec::vnic_name,
+};
+use super::{zone, zvol};
 use crate::error::{Result, VmError};
 use crate::traits::{ConsoleEndpoint, Hypervisor};
-use crate::types::{BackendTag, NetworkConfig, VmHandle, VmSpec, VmState};
+use crate::types::{BackendTag, VmHandle, VmSpec, VmState};
+
+/// propolis-server address of VMs prepared before addresses were allocated per VM.
+const DEFAULT_PROPOLIS_ADDR: &str = "127.0.0.1:12400";
+
+/// How long to wait for a requested instance state transition.
+const STATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Cloud-init seed ISO, relative to the zone root.
+const SEED_ISO: &str = "vmctl/seed.iso";
 
 /// Propolis backend for illumos zones.
-pub struct PropolisBackend {
+///
+/// Zone and ZFS commands go through `R`, which tests replace with a
+/// [`RecordingRunner`](super::command::RecordingRunner).
+pub struct PropolisBackend<R: CommandRunner = SystemRunner> {
     data_dir: PathBuf,
     zfs_pool: String,
+    runner: R,
 }
 
 impl PropolisBackend {
     pub fn new(data_dir: Option<PathBuf>, zfs_pool: String) -> Self {
+        Self::with_runner(data_dir, zfs_pool, SystemRunner)
+    }
+}
+
+impl<R: CommandRunner> PropolisBackend<R> {
+    pub fn with_runner(data_dir: Option<PathBuf>, zfs_pool: String, runner: R) -> Self {
         let data_dir = data_dir.unwrap_or_else(|| PathBuf::from("/var/lib/vmctl/vms"));
-        Self { data_dir, zfs_pool }
+        Self {
+            data_dir,
+            zfs_pool,
+            runner,
+        }
     }
 
     fn work_dir(&self, name: &str) -> PathBuf {
         self.data_dir.join(name)
     }
 
-    /// Run a shell command and return (success, stdout, stderr).
-    async fn run_cmd(cmd: &str, args: &[&str]) -> Result<(bool, String, String)> {
-        let output = tokio::process::Command::new(cmd)
-            .args(args)
-            .output()
-            .await?;
-        Ok((
-            output.status.success(),
-            String::from_utf8_lossy(&output.stdout).into_owned(),
-            String::from_utf8_lossy(&output.stderr).into_owned(),
-        ))
+    /// Dataset of the VM's boot zvol.
+    fn vm_dataset(&self, name: &str) -> String {
+        format!("{}/vms/{name}", self.zfs_pool)
     }
 
-    /// Poll propolis-server until it responds, up to timeout.
-    async fn wait_for_propolis(addr: &str, timeout: Duration) -> Result<()> {
-        let client = reqwest::Client::new();
-        let deadline = tokio::time::Instant::now() + timeout;
-        let url = format!("http://{addr}/instance");
+    /// Raw device of the VM's boot zvol.
+    fn zvol_path(&self, name: &str) -> PathBuf {
+        zvol::device_path(&self.vm_dataset(name))
+    }
 
-        loop {
-            if let Ok(resp) = client.get(&url).send().await {
-                if resp.status().is_success() || resp.status().as_u16() == 404 {
-                    return Ok(());
-                }
-            }
-            if tokio::time::Instant::now() >= deadline {
-                return Err(VmError::PropolisUnreachable {
-                    addr: addr.into(),
-                    source: Box::new(std::io::Error::new(
-                        std::io::ErrorKind::TimedOut,
-                        "propolis-server did not become available",
-                    )),
-                });
-            }
-            tokio::time::sleep(Duration::from_millis(500)).await;
-        }
+    /// Client for the VM's propolis-server.
+    fn client(vm: &VmHandle) -> PropolisClient {
+        let addr = vm.propolis_addr.as_deref().unwrap_or(DEFAULT_PROPOLIS_ADDR);
+        PropolisClient::new(&vm.name, addr)
     }
 }
 
-impl Hypervisor for PropolisBackend {
+impl<R: CommandRunner> Hypervisor for PropolisBackend<R> {
     async fn prepare(&self, spec: &VmSpec) -> Result<VmHandle> {
         let work_dir = self.work_dir(&spec.name);
         tokio::fs::create_dir_all(&work_dir).await?;
 
-        // Clone ZFS dataset for the VM disk
-        let base_dataset = format!("{}/images/{}", self.zfs_pool, spec.name);
-        let vm_dataset = format!("{}/vms/{}", self.zfs_pool, spec.name);
+        // Import the image into a zvol (once per image) and clone it for the VM disk
+        let snapshot = zvol::import(&self.runner, &self.zfs_pool, &spec.image_path).await?;
+        zvol::clone(
+            &self.runner,
+            &snapshot,
+            &self.vm_dataset(&spec.name),
+            spec.disk_gb,
+        )
+        .await?;
 
-        let (ok, _, stderr) = Self::run_cmd(
-            "zfs",
-            &["clone", &format!("{base_dataset}@latest"), &vm_dataset],
+        let vnic_name = vnic_name(&spec.name, &spec.network);
+
+        // Configure and install the zone, which also settles the propolis-server address
+        let propolis_addr = zone::provision(
+            &self.runner,
+            &spec.name,
+            &work_dir,
+            &vnic_name,
+            &self.zvol_path(&spec.name),
+            &self.data_dir,
         )
         .await?;
-        if !ok {
-            warn!(name = %spec.name, stderr = %stderr, "ZFS clone failed (may already exist)");
-        }
 
-        // Create cloud-init seed ISO if configured
+        // Create cloud-init seed ISO if configured, inside the zone for propolis-server to open
         let mut seed_iso_path = None;
         if let Some(ref ci) = spec.cloud_init {
-            let iso_path = work_dir.join("seed.iso");
+            let iso_path = zone::root(&work_dir).join(SEED_ISO);
+            if let Some(parent) = iso_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
             let instance_id = ci.instance_id.as_deref().unwrap_or(&spec.name);
             let hostname = ci.hostname.as_deref().unwrap_or(&spec.name);
             let meta_data = format!("instance-id: {instance_id}\nlocal-hostname: {hostname}\n");
@@ -99,35 +118,12 @@ impl Hypervisor for PropolisBackend {
             seed_iso_path = Some(iso_path);
         }
 
-        // Determine VNIC name
-        let vnic_name = match &spec.network {
-            NetworkConfig::Vnic { name } => name.clone(),
-            _ => format!("vnic_{}", spec.name),
-        };
-
-        // Configure and install zone
-        let zone_name = &spec.name;
-        let zonecfg_cmds = format!(
-            "create -b; set brand=nebula-vm; set zonepath={work_dir}; set ip-type=exclusive; \
-             add net; set physical={vnic_name}; end; commit",
-            work_dir = work_dir.display()
-        );
-        let (ok, _, stderr) = Self::run_cmd("zonecfg", &["-z", zone_name, &zonecfg_cmds]).await?;
-        if !ok {
-            warn!(name = %zone_name, stderr = %stderr, "zonecfg failed (zone may already exist)");
-        }
-
-        let (ok, _, stderr) = Self::run_cmd("zoneadm", &["-z", zone_name, "install"]).await?;
-        if !ok {
-            warn!(name = %zone_name, stderr = %stderr, "zone install failed");
-        }
-
         let handle = VmHandle {
             id: format!("propolis-{}", uuid::Uuid::new_v4()),
             name: spec.name.clone(),
             backend: BackendTag::Propolis,
             work_dir,
-            overlay_path: None,
+            overlay_path: Some(self.zvol_path(&spec.name)),
             seed_iso_path,
             pid: None,
             qmp_socket: None,
@@ -139,91 +135,105 @@ impl Hypervisor for PropolisBackend {
             network: spec.network.clone(),
             ssh_host_port: None,
             mac_addr: None,
+            systemd_unit: None,
+            sandbox: Default::default(),
+            qemu: Default::default(),
+            tpm: None,
+            propolis_addr: Some(propolis_addr),
         };
 
-        info!(name = %spec.name, id = %handle.id, "Propolis: prepared");
+        info!(name = %spec.name, id = %handle.id, addr = ?handle.propolis_addr, "Propolis: prepared");
         Ok(handle)
     }
 
     async fn start(&self, vm: &VmHandle) -> Result<VmHandle> {
-        // Boot zone
-        let (ok, _, stderr) = Self::run_cmd("zoneadm", &["-z", &vm.name, "boot"]).await?;
-        if !ok {
-            return Err(VmError::QemuSpawnFailed {
-                source: std::io::Error::other(format!("zone boot failed: {stderr}")),
-            });
-        }
+        zone::boot(&self.runner, &vm.name).await?;
 
-        // The brand boot script starts propolis-server inside the zone.
+        // The brand boot script starts propolis-server inside the zone on the address in the
+        // zone configuration, which may have been changed since prepare.
         // Wait for it to become available.
-        let propolis_addr = format!("127.0.0.1:12400"); // default propolis port
-        Self::wait_for_propolis(&propolis_addr, Duration::from_secs(30)).await?;
+        let mut vm = vm.clone();
+        if let Some(addr) = zone::attr(&self.runner, &vm.name, zone::ADDR_ATTR).await? {
+            vm.propolis_addr = Some(addr);
+        }
+        let client = Self::client(&vm);
+        client.wait_until_up(Duration::from_secs(30)).await?;
 
         // PUT /instance with instance spec
-        let client = reqwest::Client::new();
-        let instance_spec = serde_json::json!({
-            "properties": {
-                "id": vm.id,
-                "name": vm.name,
-                "description": "managed by vmctl"
-            },
-            "nics": [],
-            "disks": [],
-            "boot_settings": {
-                "order": [{"name": "disk0"}]
-            }
-        });
-
-        client
-            .put(format!("http://{propolis_addr}/instance"))
-            .json(&instance_spec)
-            .send()
-            .await
-            .map_err(|e| VmError::PropolisUnreachable {
-                addr: propolis_addr.clone(),
-                source: Box::new(e),
-            })?;
-
-        // PUT /instance/state → Run
-        client
-            .put(format!("http://{propolis_addr}/instance/state"))
-            .json(&serde_json::json!("Run"))
-            .send()
-            .await
-            .map_err(|e| VmError::PropolisUnreachable {
-                addr: propolis_addr.clone(),
-                source: Box::new(e),
-            })?;
-
-        info!(name = %vm.name, "Propolis: started");
-        Ok(vm.clone())
-    }
+        let mut builder = InstanceSpecBuilder::from_handle(&vm);
+        if vm.overlay_path.is_none() {
+            // Handles created before the disk path was recorded.
+            builder = builder.boot_disk(self.zvol_path(&vm.name));
+        }
+        client.ensure(&builder.build()?).await?;
+        client.request_state(InstanceStateRequested::Run).await?;
 
-    async fn stop(&self, vm: &VmHandle, _timeout: Duration) -> Result<VmHandle> {
-        let propolis_addr = "127.0.0.1:12400";
-        let client = reqwest::Client::new();
+        info!(name = %vm.name, addr = client.addr(), "Propolis: started");
+        Ok(vm)
+    }
 
-        // PUT /instance/state → Stop
-        let _ = client
-            .put(format!("http://{propolis_addr}/instance/state"))
-            .json(&serde_json::json!("Stop"))
-            .send()
-            .await;
+    async fn stop(&self, vm: &VmHandle, timeout: Duration) -> Result<VmHandle> {
+        // propolis-server has no ACPI power button request; Stop is the shutdown it offers, and a
+        // guest that powers itself off ends up Stopped as well. Follow the state monitor until
+        // the instance gets there, and only halt the zone underneath it after that or on timeout.
+        let client = Self::client(vm);
+        match client.request_state(InstanceStateRequested::Stop).await {
+            Ok(()) => match client.wait_for_terminal(timeout).await {
+                Ok(Some(state)) => info!(name = %vm.name, %state, "Propolis: instance stopped"),
+                Ok(None) => warn!(
+                    name = %vm.name,
+                    timeout_secs = timeout.as_secs(),
+                    "Propolis: instance did not stop in time, halting zone"
+                ),
+                Err(e) => {
+                    warn!(name = %vm.name, error = %e, "Propolis: lost state monitor, halting zone")
+                }
+            },
+            Err(e) => {
+                warn!(name = %vm.name, error = %e, "Propolis: stop request failed, halting zone")
+            }
+        }
 
-        // Halt the zone
-        let _ = Self::run_cmd("zoneadm", &["-z", &vm.name, "halt"]).await;
+        zone::halt(&self.runner, &vm.name).await?;
 
         info!(name = %vm.name, "Propolis: stopped");
         Ok(vm.clone())
     }
 
     async fn suspend(&self, vm: &VmHandle) -> Result<VmHandle> {
-        info!(name = %vm.name, "Propolis: suspend (not yet implemented)");
-        Ok(vm.clone())
+        // The instance state API only accepts Run, Stop and Reboot: there is no way to pause
+        // vCPUs of a running instance, so suspending is refused in every state.
+        let state = Self::client(vm).state().await?;
+        Err(VmError::InvalidState {
+            name: vm.name.clone(),
+            state: format!("{state} (propolis-server cannot pause instances)"),
+        })
     }
 
     async fn resume(&self, vm: &VmHandle) -> Result<VmHandle> {
-        info!(name = %vm.name, "Propolis: resume (not yet implemented)");
+        let client = Self::client(vm);
+        match client.state().await? {
+            InstanceState::Running => {}
+            // Ensured but never run, e.g. after a start that was interrupted.
+            InstanceState::Creating => {
+                client.request_state(InstanceStateRequested::Run).await?;
+                client
+                    .wait_for_state(InstanceState::Running, STATE_TIMEOUT)
+                    .await?;
+            }
+            InstanceState::Starting => {
+                client
+                    .wait_for_state(InstanceState::Running, STATE_TIMEOUT)
+                    .await?;
+            }
+            state => {
+                return Err(VmError::InvalidState {
+                    name: vm.name.clone(),
+                    state: state.to_string(),
+                });
+            }
+        }
+        info!(name = %vm.name, "Propolis: resumed");
         Ok(vm.clone())
     }
 
@@ -231,13 +241,8 @@ impl Hypervisor for PropolisBackend {
         // Stop first
         self.stop(&vm, Duration::from_secs(10)).await?;
 
-        // Uninstall and delete zone
-        let _ = Self::run_cmd("zoneadm", &["-z", &vm.name, "uninstall", "-F"]).await;
-        let _ = Self::run_cmd("zonecfg", &["-z", &vm.name, "delete", "-F"]).await;
-
-        // Destroy ZFS dataset
-        let vm_dataset = format!("{}/vms/{}", self.zfs_pool, vm.name);
-        let _ = Self::run_cmd("zfs", &["destroy", "-r", &vm_dataset]).await;
+        zone::remove(&self.runner, &vm.name).await?;
+        zvol::destroy(&self.runner, &self.vm_dataset(&vm.name)).await?;
 
         // Remove work directory
         let _ = tokio::fs::remove_dir_all(&vm.work_dir).await;
@@ -247,16 +252,17 @@ impl Hypervisor for PropolisBackend {
     }
 
     async fn state(&self, vm: &VmHandle) -> Result<VmState> {
-        let (ok, stdout, _) = Self::run_cmd("zoneadm", &["-z", &vm.name, "list", "-p"]).await?;
-        if !ok {
+        let Some(zone_state) = zone::state(&self.runner, &vm.name).await? else {
             return Ok(VmState::Destroyed);
-        }
-
-        // Output format: zoneid:zonename:state:zonepath:uuid:brand:ip-type
-        let state_field = stdout.split(':').nth(2).unwrap_or("").trim();
+        };
 
-        Ok(match state_field {
-            "running" => VmState::Running,
+        Ok(match zone_state.as_str() {
+            // The zone may outlive the instance inside it.
+            "running" => match Self::client(vm).state().await {
+                Ok(InstanceState::Failed) => VmState::Failed,
+                Ok(InstanceState::Stopped | InstanceState::Destroyed) => VmState::Stopped,
+                _ => VmState::Running,
+            },
             "installed" => VmState::Prepared,
             "configured" => VmState::Prepared,
             _ => VmState::Stopped,
@@ -265,34 +271,18 @@ impl Hypervisor for PropolisBackend {
 
     async fn guest_ip(&self, vm: &VmHandle) -> Result<String> {
         // For exclusive-IP zones, the IP is configured inside the zone.
-        // Try to query it via zlogin.
-        let (ok, stdout, _) = Self::run_cmd(
-            "zlogin",
-            &[&vm.name, "ipadm", "show-addr", "-p", "-o", "ADDR"],
-        )
-        .await?;
-
-        if ok {
-            for line in stdout.lines() {
-                let addr = line
-                    .trim()
-                    .trim_end_matches(|c: char| c == '/' || c.is_ascii_digit());
-                let addr = line.split('/').next().unwrap_or("").trim();
-                if !addr.is_empty() && addr != "127.0.0.1" && addr.contains('.') {
-                    return Ok(addr.to_string());
-                }
-            }
-        }
-
-        Err(VmError::IpDiscoveryTimeout {
-            name: vm.name.clone(),
-        })
+        zone::guest_ip(&self.runner, &vm.name)
+            .await?
+            .ok_or_else(|| VmError::IpDiscoveryTimeout {
+                name: vm.name.clone(),
+            })
     }
 
     fn console_endpoint(&self, vm: &VmHandle) -> Result<ConsoleEndpoint> {
         // Propolis serial console is available via WebSocket
         Ok(ConsoleEndpoint::WebSocket(format!(
-            "ws://127.0.0.1:12400/instance/serial"
+            "ws://{}/instance/serial",
+            Self::client(vm).addr()
         )))
     }
 }
@@ -0,0 +1,329 @@
+//! HTTP client for one VM's propolis-server.
+
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use tracing::warn;
+
+use super::spec::InstanceEnsureRequest;
+use super::state::{
+    InstanceGetResponse, InstanceState, InstanceStateMonitorRequest, InstanceStateMonitorResponse,
+    InstanceStateRequested,
+};
+use crate::error::{Result, VmError};
+
+/// Interval between polls while waiting for the server or a state.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Client for the propolis-server serving VM `name` at `addr`.
+///
+/// Transport failures and unexpected responses map to [`VmError::PropolisUnreachable`];
+/// state changes the server refuses map to [`VmError::InvalidState`].
+#[derive(Debug, Clone)]
+pub struct PropolisClient {
+    http: reqwest::Client,
+    name: String,
+    addr: String,
+}
+
+impl PropolisClient {
+    pub fn new(name: impl Into<String>, addr: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            name: name.into(),
+            addr: addr.into(),
+        }
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    fn unreachable(&self, source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> VmError {
+        VmError::PropolisUnreachable {
+            addr: self.addr.clone(),
+            source: source.into(),
+        }
+    }
+
+    /// Poll until the server answers `GET /instance`, with or without an instance.
+    pub async fn wait_until_up(&self, timeout: Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Ok(resp) = self.http.get(self.url("/instance")).send().await {
+                if resp.status().is_success() || resp.status() == StatusCode::NOT_FOUND {
+                    return Ok(());
+                }
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(self.unreachable(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "propolis-server did not become available",
+                )));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// `GET /instance`.
+    pub async fn instance(&self) -> Result<InstanceGetResponse> {
+        self.http
+            .get(self.url("/instance"))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| self.unreachable(e))?
+            .json()
+            .await
+            .map_err(|e| self.unreachable(e))
+    }
+
+    /// Current instance state.
+    pub async fn state(&self) -> Result<InstanceState> {
+        Ok(self.instance().await?.instance.state)
+    }
+
+    /// `PUT /instance`: create the instance from `spec`.
+    pub async fn ensure(&self, spec: &InstanceEnsureRequest) -> Result<()> {
+        let resp = self
+            .http
+            .put(self.url("/instance"))
+            .json(spec)
+            .send()
+            .await
+            .map_err(|e| self.unreachable(e))?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            return Err(
+                self.unreachable(format!("PUT /instance returned {status}: {}", body.trim()))
+            );
+        }
+        Ok(())
+    }
+
+    /// `PUT /instance/state`. A refused request is an [`VmError::InvalidState`] carrying the
+    /// current state.
+    pub async fn request_state(&self, requested: InstanceStateRequested) -> Result<()> {
+        let resp = self
+            .http
+            .put(self.url("/instance/state"))
+            .json(&requested)
+            .send()
+            .await
+            .map_err(|e| self.unreachable(e))?;
+
+        if !resp.status().is_success() {
+            let state = match self.state().await {
+                Ok(state) => state.to_string(),
+                Err(_) => "unknown".into(),
+            };
+            warn!(name = %self.name, %requested, status = %resp.status(), %state, "Propolis: state change rejected");
+            return Err(VmError::InvalidState {
+                name: self.name.clone(),
+                state,
+            });
+        }
+        Ok(())
+    }
+
+    /// `GET /instance/state-monitor`: block until the state generation reaches `generation`,
+    /// then return the current generation and state.
+    pub async fn state_monitor(&self, generation: u64) -> Result<InstanceStateMonitorResponse> {
+        self.http
+            .get(self.url("/instance/state-monitor"))
+            .json(&InstanceStateMonitorRequest { generation })
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| self.unreachable(e))?
+            .json()
+            .await
+            .map_err(|e| self.unreachable(e))
+    }
+
+    /// Follow the state monitor until the instance reaches a terminal state and return that
+    /// state, or `None` if `timeout` passes first.
+    pub async fn wait_for_terminal(&self, timeout: Duration) -> Result<Option<InstanceState>> {
+        let follow = async {
+            let mut generation = 0;
+            loop {
+                let resp = self.state_monitor(generation).await?;
+                if resp.state.is_terminal() {
+                    return Ok(resp.state);
+                }
+                generation = resp.generation + 1;
+            }
+        };
+        match tokio::time::timeout(timeout, follow).await {
+            Ok(result) => result.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Poll until the instance reaches `target`. Ending up in a terminal state other than
+    /// `target` is an [`VmError::InvalidState`].
+    pub async fn wait_for_state(&self, target: InstanceState, timeout: Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let state = self.state().await?;
+            if state == target {
+                return Ok(());
+            }
+            if state.is_terminal() {
+                return Err(VmError::InvalidState {
+                    name: self.name.clone(),
+                    state: state.to_string(),
+                });
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(self.unreachable(format!(
+                    "instance did not reach {target} within {}s (still {state})",
+                    timeout.as_secs()
+                )));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::propolis_api::InstanceSpecBuilder;
+    use crate::backends::propolis_api::mock::FakePropolis;
+
+    fn spec() -> InstanceEnsureRequest {
+        InstanceSpecBuilder::new(uuid::Uuid::new_v4(), "web")
+            .boot_disk("/dev/zvol/rdsk/rpool/vms/web")
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn ensure_and_run() {
+        let fake = FakePropolis::start().await;
+        let client = PropolisClient::new("web", fake.addr());
+
+        client.wait_until_up(Duration::from_secs(5)).await.unwrap();
+        client.ensure(&spec()).await.unwrap();
+        assert_eq!(client.state().await.unwrap(), InstanceState::Creating);
+
+        client
+            .request_state(InstanceStateRequested::Run)
+            .await
+            .unwrap();
+        client
+            .wait_for_state(InstanceState::Running, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(fake.ensured().unwrap().properties.name, "web");
+        assert!(
+            fake.requests()
+                .contains(&r#"PUT /instance/state "Run""#.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_transition_is_invalid_state() {
+        let fake = FakePropolis::start().await;
+        let client = PropolisClient::new("web", fake.addr());
+        client.ensure(&spec()).await.unwrap();
+        fake.set_state(InstanceState::Stopped);
+
+        let err = client
+            .request_state(InstanceStateRequested::Run)
+            .await
+            .unwrap_err();
+        match err {
+            VmError::InvalidState { name, state } => {
+                assert_eq!(name, "web");
+                assert_eq!(state, "Stopped");
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn waiting_ends_in_terminal_state() {
+        let fake = FakePropolis::start().await;
+        let client = PropolisClient::new("web", fake.addr());
+        client.ensure(&spec()).await.unwrap();
+        fake.set_state(InstanceState::Failed);
+
+        let err = client
+            .wait_for_state(InstanceState::Running, Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, VmError::InvalidState { .. }), "got: {err}");
+    }
+
+    #[tokio::test]
+    async fn monitor_follows_stop() {
+        let fake = FakePropolis::start().await;
+        let client = PropolisClient::new("web", fake.addr());
+        client.ensure(&spec()).await.unwrap();
+        fake.set_state(InstanceState::Running);
+
+        client
+            .request_state(InstanceStateRequested::Stop)
+            .await
+            .unwrap();
+        let state = client
+            .wait_for_terminal(Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(state, Some(InstanceState::Stopped));
+        assert!(
+            fake.requests()
+                .iter()
+                .any(|r| r.starts_with("GET /instance/state-monitor"))
+        );
+    }
+
+    #[tokio::test]
+    async fn monitor_gives_up_after_timeout() {
+        let fake = FakePropolis::start().await;
+        let client = PropolisClient::new("web", fake.addr());
+        client.ensure(&spec()).await.unwrap();
+        fake.set_state(InstanceState::Running);
+        fake.stall(true);
+
+        client
+            .request_state(InstanceStateRequested::Stop)
+            .await
+            .unwrap();
+        let state = client
+            .wait_for_terminal(Duration::from_millis(300))
+            .await
+            .unwrap();
+        assert_eq!(state, None);
+    }
+
+    #[tokio::test]
+    async fn missing_instance_and_dead_server_are_unreachable() {
+        let fake = FakePropolis::start().await;
+        let client = PropolisClient::new("web", fake.addr());
+        let err = client.state().await.unwrap_err();
+        assert!(
+            matches!(err, VmError::PropolisUnreachable { .. }),
+            "got: {err}"
+        );
+
+        let addr = fake.addr().to_string();
+        drop(fake);
+        let client = PropolisClient::new("web", addr);
+        let err = client
+            .wait_until_up(Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, VmError::PropolisUnreachable { .. }),
+            "got: {err}"
+        );
+    }
+}
@@ -0,0 +1,235 @@
+//! In-process stand-in for propolis-server, for tests.
+//!
+//! Serves just enough HTTP/1.1 for [`PropolisClient`](super::PropolisClient) and follows the
+//! instance lifecycle: transitional states (`Starting`, `Stopping`, `Rebooting`) settle after
+//! they have been reported once, unless the fake is stalled. Every state change bumps the
+//! generation that `GET /instance/state-monitor` blocks on.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::spec::InstanceEnsureRequest;
+use super::state::{
+    Instance, InstanceGetResponse, InstanceState, InstanceStateMonitorRequest,
+    InstanceStateMonitorResponse, InstanceStateRequested,
+};
+
+#[derive(Default)]
+struct Inner {
+    ensured: Option<InstanceEnsureRequest>,
+    state: Option<InstanceState>,
+    generation: u64,
+    stalled: bool,
+    requests: Vec<String>,
+}
+
+impl Inner {
+    fn set_state(&mut self, state: InstanceState) {
+        self.state = Some(state);
+        self.generation += 1;
+    }
+
+    /// Move a transitional state on to where it leads.
+    fn settle(&mut self) {
+        if self.stalled {
+            return;
+        }
+        let next = match self.state {
+            Some(InstanceState::Starting | InstanceState::Rebooting) => InstanceState::Running,
+            Some(InstanceState::Stopping) => InstanceState::Stopped,
+            _ => return,
+        };
+        self.set_state(next);
+    }
+}
+
+pub struct FakePropolis {
+    addr: String,
+    inner: Arc<Mutex<Inner>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl FakePropolis {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let inner = Arc::new(Mutex::new(Inner::default()));
+        let shared = inner.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, shared.clone()));
+            }
+        });
+        Self { addr, inner, task }
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// The spec of the last `PUT /instance`.
+    pub fn ensured(&self) -> Option<InstanceEnsureRequest> {
+        self.inner.lock().unwrap().ensured.clone()
+    }
+
+    pub fn set_state(&self, state: InstanceState) {
+        self.inner.lock().unwrap().set_state(state);
+    }
+
+    /// Keep transitional states from settling, like a guest that never finishes shutting down.
+    pub fn stall(&self, stalled: bool) {
+        self.inner.lock().unwrap().stalled = stalled;
+    }
+
+    /// Requests received so far, as `METHOD /path` plus the body if any.
+    pub fn requests(&self) -> Vec<String> {
+        self.inner.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for FakePropolis {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(mut stream: TcpStream, inner: Arc<Mutex<Inner>>) {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let head_end = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    };
+    let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+    let content_length = head
+        .lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while data.len() < head_end + content_length {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    }
+    let body = String::from_utf8_lossy(&data[head_end..head_end + content_length]).into_owned();
+
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    inner.lock().unwrap().record(method, path, &body);
+    // The state monitor blocks until the generation it asks for is reached.
+    let (status, response) = loop {
+        if let Some(reply) = handle(&mut inner.lock().unwrap(), method, path, &body) {
+            break reply;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    let reply = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+        response.len()
+    );
+    let _ = stream.write_all(reply.as_bytes()).await;
+}
+
+impl Inner {
+    fn record(&mut self, method: &str, path: &str, body: &str) {
+        let record = if body.is_empty() {
+            format!("{method} {path}")
+        } else {
+            format!("{method} {path} {body}")
+        };
+        self.requests.push(record);
+    }
+}
+
+/// Response to one request, or `None` if it has to wait for a state change.
+fn handle(
+    inner: &mut Inner,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> Option<(&'static str, String)> {
+    let not_found = || ("404 Not Found", r#"{"message":"no instance"}"#.to_string());
+    Some(match (method, path) {
+        ("GET", "/instance") => {
+            let (Some(spec), Some(state)) = (&inner.ensured, inner.state) else {
+                return Some(not_found());
+            };
+            let response = InstanceGetResponse {
+                instance: Instance {
+                    properties: spec.properties.clone(),
+                    state,
+                },
+            };
+            inner.settle();
+            ("200 OK", serde_json::to_string(&response).unwrap())
+        }
+        ("GET", "/instance/state-monitor") => {
+            let Some(state) = inner.state else {
+                return Some(not_found());
+            };
+            let Ok(request) = serde_json::from_str::<InstanceStateMonitorRequest>(body) else {
+                return Some(("400 Bad Request", r#"{"message":"bad request"}"#.into()));
+            };
+            if inner.generation < request.generation {
+                inner.settle();
+                if inner.generation < request.generation {
+                    return None;
+                }
+            }
+            let response = InstanceStateMonitorResponse {
+                generation: inner.generation,
+                state: inner.state.unwrap_or(state),
+            };
+            ("200 OK", serde_json::to_string(&response).unwrap())
+        }
+        ("PUT", "/instance") => match serde_json::from_str(body) {
+            Ok(spec) => {
+                inner.ensured = Some(spec);
+                inner.set_state(InstanceState::Creating);
+                ("200 OK", "{}".into())
+            }
+            Err(e) => ("400 Bad Request", format!(r#"{{"message":"{e}"}}"#)),
+        },
+        ("PUT", "/instance/state") => {
+            let Some(state) = inner.state else {
+                return Some(not_found());
+            };
+            let Ok(requested) = serde_json::from_str::<InstanceStateRequested>(body) else {
+                return Some(("400 Bad Request", r#"{"message":"bad request"}"#.into()));
+            };
+            use InstanceState::*;
+            let next = match (requested, state) {
+                (InstanceStateRequested::Run, Creating) => Some(Starting),
+                (InstanceStateRequested::Run, Starting | Running) => Some(state),
+                (InstanceStateRequested::Stop, s) if s.is_terminal() => Some(s),
+                (InstanceStateRequested::Stop, _) => Some(Stopping),
+                (InstanceStateRequested::Reboot, Running) => Some(Rebooting),
+                _ => None,
+            };
+            match next {
+                Some(next) if next == state => ("204 No Content", String::new()),
+                Some(next) => {
+                    inner.set_state(next);
+                    ("204 No Content", String::new())
+                }
+                None => (
+                    "409 Conflict",
+                    format!(r#"{{"message":"cannot {requested} while {state}"}}"#),
+                ),
+            }
+        }
+        _ => ("404 Not Found", r#"{"message":"not found"}"#.into()),
+    })
+}
@@ -0,0 +1,20 @@
+//! Client for the propolis-server HTTP API, with its request and response types.
+//!
+//! Only the subset vmctl uses is modelled. Nothing here is illumos-specific, so the client
+//! builds and is tested on every platform.
+
+pub mod client;
+#[cfg(test)]
+pub(crate) mod mock;
+pub mod spec;
+pub mod state;
+
+pub use client::PropolisClient;
+pub use spec::{
+    BootDeclaration, BootSettings, DiskRequest, InstanceEnsureRequest, InstanceProperties,
+    InstanceSpecBuilder, NetworkInterfaceRequest, Slot, VolumeConstructionRequest,
+};
+pub use state::{
+    Instance, InstanceGetResponse, InstanceState, InstanceStateMonitorRequest,
+    InstanceStateMonitorResponse, InstanceStateRequested,
+};
@@ -0,0 +1,329 @@
+//! Instance specification sent with `PUT /instance`.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::backends::zone;
+use crate::error::{Result, VmError};
+use crate::types::{NetworkConfig, VmHandle};
+
+/// Block size of file-backed disks.
+const BLOCK_SIZE: u32 = 512;
+
+/// Name of the boot disk in the spec and the boot order.
+const BOOT_DISK: &str = "disk0";
+
+/// Name of the cloud-init seed disk.
+const SEED_DISK: &str = "cloud-init";
+
+/// Body of `PUT /instance`: creates the instance (or verifies an existing one matches).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceEnsureRequest {
+    pub properties: InstanceProperties,
+    pub vcpus: u8,
+    /// Guest memory in MiB.
+    pub memory: u64,
+    #[serde(default)]
+    pub nics: Vec<NetworkInterfaceRequest>,
+    #[serde(default)]
+    pub disks: Vec<DiskRequest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_settings: Option<BootSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceProperties {
+    pub id: Uuid,
+    pub name: String,
+    pub description: String,
+    pub image_id: Uuid,
+    pub bootrom_id: Uuid,
+}
+
+/// PCI slot of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Slot(pub u8);
+
+/// A virtio NIC backed by a VNIC inside the zone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkInterfaceRequest {
+    pub interface_id: Uuid,
+    /// Name of the VNIC.
+    pub name: String,
+    pub slot: Slot,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskRequest {
+    pub name: String,
+    pub slot: Slot,
+    pub read_only: bool,
+    /// Emulated device, `virtio` or `nvme`.
+    pub device: String,
+    pub volume_construction_request: VolumeConstructionRequest,
+}
+
+/// Where a disk's data comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VolumeConstructionRequest {
+    /// A file or block device, such as a zvol, visible to propolis-server.
+    File {
+        id: Uuid,
+        block_size: u32,
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootSettings {
+    pub order: Vec<BootDeclaration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootDeclaration {
+    pub name: String,
+}
+
+/// Builds an [`InstanceEnsureRequest`] for a VM.
+#[derive(Debug, Clone)]
+pub struct InstanceSpecBuilder {
+    id: Uuid,
+    name: String,
+    description: String,
+    vcpus: u16,
+    memory_mb: u64,
+    boot_disk: Option<PathBuf>,
+    seed_iso: Option<PathBuf>,
+    vnic: Option<String>,
+}
+
+impl InstanceSpecBuilder {
+    pub fn new(id: Uuid, name: impl Into<String>) -> Self {
+        Self {
+            id,
+            name: name.into(),
+            description: "managed by vmctl".into(),
+            vcpus: 1,
+            memory_mb: 1024,
+            boot_disk: None,
+            seed_iso: None,
+            vnic: None,
+        }
+    }
+
+    /// Start from the resources recorded in `vm`: vCPUs, memory, the raw disk in
+    /// `overlay_path`, the seed ISO and the VNIC. Paths are given as propolis-server sees
+    /// them inside the VM's zone, whose zonepath is the work directory.
+    pub fn from_handle(vm: &VmHandle) -> Self {
+        let in_zone = |path: &Path| zone::in_zone(&vm.work_dir, path);
+        let mut builder = Self::new(instance_id(vm), &vm.name)
+            .vcpus(vm.vcpus)
+            .memory_mb(vm.memory_mb)
+            .vnic(vnic_name(&vm.name, &vm.network));
+        builder.boot_disk = vm.overlay_path.as_deref().map(in_zone);
+        builder.seed_iso = vm.seed_iso_path.as_deref().map(in_zone);
+        builder
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = description.into();
+        self
+    }
+
+    pub fn vcpus(mut self, vcpus: u16) -> Self {
+        self.vcpus = vcpus;
+        self
+    }
+
+    pub fn memory_mb(mut self, memory_mb: u64) -> Self {
+        self.memory_mb = memory_mb;
+        self
+    }
+
+    /// Boot from this zvol device or raw disk image.
+    pub fn boot_disk(mut self, path: impl Into<PathBuf>) -> Self {
+        self.boot_disk = Some(path.into());
+        self
+    }
+
+    /// Attach a cloud-init seed ISO as a read-only second disk.
+    pub fn seed_iso(mut self, path: impl Into<PathBuf>) -> Self {
+        self.seed_iso = Some(path.into());
+        self
+    }
+
+    /// Attach a virtio NIC backed by this VNIC.
+    pub fn vnic(mut self, name: impl Into<String>) -> Self {
+        self.vnic = Some(name.into());
+        self
+    }
+
+    pub fn build(self) -> Result<InstanceEnsureRequest> {
+        let invalid = |detail: String| VmError::PropolisSpecInvalid {
+            name: self.name.clone(),
+            detail,
+        };
+        let vcpus = u8::try_from(self.vcpus)
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| invalid(format!("{} vCPUs, must be 1-255", self.vcpus)))?;
+        let boot_disk = self
+            .boot_disk
+            .as_deref()
+            .ok_or_else(|| invalid("no boot disk".into()))?;
+
+        let mut disks = vec![file_disk(BOOT_DISK, 0, boot_disk, false)];
+        if let Some(ref iso) = self.seed_iso {
+            disks.push(file_disk(SEED_DISK, 1, iso, true));
+        }
+
+        let nics = self
+            .vnic
+            .iter()
+            .map(|vnic| NetworkInterfaceRequest {
+                interface_id: Uuid::new_v4(),
+                name: vnic.clone(),
+                slot: Slot(0),
+            })
+            .collect();
+
+        Ok(InstanceEnsureRequest {
+            properties: InstanceProperties {
+                id: self.id,
+                name: self.name.clone(),
+                description: self.description.clone(),
+                image_id: Uuid::nil(),
+                bootrom_id: Uuid::nil(),
+            },
+            vcpus,
+            memory: self.memory_mb,
+            nics,
+            disks,
+            boot_settings: Some(BootSettings {
+                order: vec![BootDeclaration {
+                    name: BOOT_DISK.into(),
+                }],
+            }),
+        })
+    }
+}
+
+fn file_disk(name: &str, slot: u8, path: &Path, read_only: bool) -> DiskRequest {
+    DiskRequest {
+        name: name.into(),
+        slot: Slot(slot),
+        read_only,
+        device: "virtio".into(),
+        volume_construction_request: VolumeConstructionRequest::File {
+            id: Uuid::new_v4(),
+            block_size: BLOCK_SIZE,
+            path: path.to_path_buf(),
+        },
+    }
+}
+
+/// Instance UUID of a VM: the UUID part of its `propolis-<uuid>` handle ID.
+pub fn instance_id(vm: &VmHandle) -> Uuid {
+    vm.id
+        .strip_prefix("propolis-")
+        .and_then(|s| Uuid::parse_str(s).ok())
+        .unwrap_or_else(Uuid::new_v4)
+}
+
+/// VNIC the zone gets for `network`: the configured one, or `vnic_<name>`.
+pub fn vnic_name(name: &str, network: &NetworkConfig) -> String {
+    match network {
+        NetworkConfig::Vnic { name } => name.clone(),
+        _ => format!("vnic_{name}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle() -> VmHandle {
+        serde_json::from_value(serde_json::json!({
+            "id": "propolis-6f1c7a8e-2d43-4f0e-9b1a-3c5d7e9f0a12",
+            "name": "web",
+            "backend": "propolis",
+            "work_dir": "/vms/web",
+            "overlay_path": "/dev/zvol/rdsk/rpool/vms/web",
+            "seed_iso_path": "/vms/web/root/vmctl/seed.iso",
+            "vcpus": 4,
+            "memory_mb": 4096,
+            "network": {"type": "vnic", "name": "vnic0"}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn maps_handle_resources() {
+        let req = InstanceSpecBuilder::from_handle(&handle()).build().unwrap();
+        assert_eq!(
+            req.properties.id.to_string(),
+            "6f1c7a8e-2d43-4f0e-9b1a-3c5d7e9f0a12"
+        );
+        assert_eq!(req.vcpus, 4);
+        assert_eq!(req.memory, 4096);
+        assert_eq!(req.nics.len(), 1);
+        assert_eq!(req.nics[0].name, "vnic0");
+
+        assert_eq!(req.disks.len(), 2);
+        assert_eq!(req.disks[0].name, "disk0");
+        assert!(!req.disks[0].read_only);
+        assert_eq!(req.disks[1].name, "cloud-init");
+        assert_eq!(req.disks[1].slot, Slot(1));
+        assert!(req.disks[1].read_only);
+        assert_eq!(req.boot_settings.unwrap().order[0].name, "disk0");
+    }
+
+    #[test]
+    fn serializes_to_propolis_wire_format() {
+        let req = InstanceSpecBuilder::from_handle(&handle()).build().unwrap();
+        let json = serde_json::to_value(&req).unwrap();
+        assert_eq!(json["vcpus"], 4);
+        assert_eq!(json["nics"][0]["slot"], 0);
+        let vcr = &json["disks"][0]["volume_construction_request"];
+        assert_eq!(vcr["type"], "file");
+        assert_eq!(vcr["block_size"], 512);
+        assert_eq!(vcr["path"], "/dev/zvol/rdsk/rpool/vms/web");
+        let seed = &json["disks"][1]["volume_construction_request"];
+        assert_eq!(seed["path"], "/vmctl/seed.iso");
+        assert_eq!(json["boot_settings"]["order"][0]["name"], "disk0");
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let req = InstanceSpecBuilder::from_handle(&handle()).build().unwrap();
+        let json = serde_json::to_string(&req).unwrap();
+        let back: InstanceEnsureRequest = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, req);
+    }
+
+    #[test]
+    fn default_vnic_and_no_seed() {
+        let mut vm = handle();
+        vm.network = NetworkConfig::User;
+        vm.seed_iso_path = None;
+        let req = InstanceSpecBuilder::from_handle(&vm).build().unwrap();
+        assert_eq!(req.nics[0].name, "vnic_web");
+        assert_eq!(req.disks.len(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_resources() {
+        let mut vm = handle();
+        vm.vcpus = 300;
+        assert!(InstanceSpecBuilder::from_handle(&vm).build().is_err());
+
+        vm.vcpus = 2;
+        vm.overlay_path = None;
+        let err = InstanceSpecBuilder::from_handle(&vm).build().unwrap_err();
+        assert!(err.to_string().contains("no boot disk"), "got: {err}");
+    }
+}
@@ -0,0 +1,127 @@
+//! Instance state, as reported by `GET /instance` and `GET /instance/state-monitor` and requested
+//! with `PUT /instance/state`.
+
+use serde::{Deserialize, Serialize};
+
+use super::spec::InstanceProperties;
+
+/// Lifecycle state of a propolis-server instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InstanceState {
+    Creating,
+    Starting,
+    Running,
+    Stopping,
+    Stopped,
+    Rebooting,
+    Migrating,
+    Repairing,
+    Failed,
+    Destroyed,
+    /// A state added by a newer propolis-server.
+    #[serde(other)]
+    Unknown,
+}
+
+impl InstanceState {
+    /// The instance can never run again from this state.
+    pub fn is_terminal(self) -> bool {
+        matches!(self, Self::Stopped | Self::Failed | Self::Destroyed)
+    }
+}
+
+impl std::fmt::Display for InstanceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Body of `PUT /instance/state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InstanceStateRequested {
+    Run,
+    Stop,
+    Reboot,
+}
+
+impl std::fmt::Display for InstanceStateRequested {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Response of `GET /instance`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceGetResponse {
+    pub instance: Instance,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Instance {
+    pub properties: InstanceProperties,
+    pub state: InstanceState,
+}
+
+/// Body of `GET /instance/state-monitor`: the state generation to wait for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceStateMonitorRequest {
+    #[serde(rename = "gen")]
+    pub generation: u64,
+}
+
+/// Response of `GET /instance/state-monitor`. The generation increases with every state change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceStateMonitorResponse {
+    #[serde(rename = "gen")]
+    pub generation: u64,
+    pub state: InstanceState,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_instance_response() {
+        let json = r#"{
+            "instance": {
+                "properties": {
+                    "id": "6f1c7a8e-2d43-4f0e-9b1a-3c5d7e9f0a12",
+                    "name": "web",
+                    "description": "managed by vmctl",
+                    "image_id": "00000000-0000-0000-0000-000000000000",
+                    "bootrom_id": "00000000-0000-0000-0000-000000000000"
+                },
+                "state": "Running",
+                "disks": [],
+                "nics": []
+            }
+        }"#;
+        let resp: InstanceGetResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(resp.instance.state, InstanceState::Running);
+        assert_eq!(resp.instance.properties.name, "web");
+    }
+
+    #[test]
+    fn parses_state_monitor_response() {
+        let json = r#"{"gen": 7, "state": "Stopping", "migration": {"migration_in": null, "migration_out": null}}"#;
+        let resp: InstanceStateMonitorResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(resp.generation, 7);
+        assert_eq!(resp.state, InstanceState::Stopping);
+    }
+
+    #[test]
+    fn unknown_states_are_tolerated() {
+        let state: InstanceState = serde_json::from_str(r#""Hibernating""#).unwrap();
+        assert_eq!(state, InstanceState::Unknown);
+        assert!(!state.is_terminal());
+    }
+
+    #[test]
+    fn requested_state_wire_format() {
+        assert_eq!(
+            serde_json::to_string(&InstanceStateRequested::Run).unwrap(),
+            r#""Run""#
+        );
+    }
+}
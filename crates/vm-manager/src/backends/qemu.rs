@@ -1,15 +1,18 @@
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::time::Duration;
 
-use tracing::{debug, info, warn};
+use tracing::{info, warn};
 
 use crate::cloudinit;
 use crate::error::{Result, VmError};
 use crate::image;
 use crate::traits::{ConsoleEndpoint, Hypervisor};
-use crate::types::{BackendTag, NetworkConfig, VmHandle, VmSpec, VmState};
+use crate::types::{BackendTag, NetworkConfig, SandboxConfig, VmHandle, VmSpec, VmState};
 
+use super::launcher::Launcher;
 use super::qmp::QmpClient;
+use super::sandbox::{self, QemuCaps};
+use super::swtpm;
 
 /// QEMU-KVM backend for Linux.
 ///
@@ -18,6 +21,8 @@ pub struct QemuBackend {
     qemu_binary: PathBuf,
     data_dir: PathBuf,
     default_bridge: Option<String>,
+    launcher: Launcher,
+    sandbox: SandboxConfig,
 }
 
 impl QemuBackend {
@@ -36,9 +41,23 @@ impl QemuBackend {
             qemu_binary: qemu_binary.unwrap_or_else(|| "qemu-system-x86_64".into()),
             data_dir,
             default_bridge,
+            launcher: Launcher::default(),
+            sandbox: SandboxConfig::default(),
         }
     }
 
+    /// Use the given launcher for VMs started by this backend.
+    pub fn with_launcher(mut self, launcher: Launcher) -> Self {
+        self.launcher = launcher;
+        self
+    }
+
+    /// Sandbox defaults for VMs whose VMFile does not override them.
+    pub fn with_sandbox(mut self, sandbox: SandboxConfig) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
     fn work_dir(&self, name: &str) -> PathBuf {
         self.data_dir.join(name)
     }
@@ -52,21 +71,6 @@ impl QemuBackend {
         )
     }
 
-    /// Read PID from the pidfile in the work directory.
-    async fn read_pid(work_dir: &Path) -> Option<u32> {
-        let pid_path = work_dir.join("qemu.pid");
-        tokio::fs::read_to_string(&pid_path)
-            .await
-            .ok()
-            .and_then(|s| s.trim().parse().ok())
-    }
-
-    /// Check if a process with the given PID is alive.
-    fn pid_alive(pid: u32) -> bool {
-        // Signal 0 checks if process exists without sending a signal
-        unsafe { libc::kill(pid as i32, 0) == 0 }
-    }
-
     /// Derive a deterministic SSH host port from the VM name (range 10022..10122).
     fn ssh_port_for_name(name: &str) -> u16 {
         use std::collections::hash_map::DefaultHasher;
@@ -107,10 +111,12 @@ impl Hypervisor for QemuBackend {
     async fn prepare(&self, spec: &VmSpec) -> Result<VmHandle> {
         let work_dir = self.work_dir(&spec.name);
         tokio::fs::create_dir_all(&work_dir).await?;
+        sandbox::restrict(&work_dir, 0o700).await?;
 
         // Create QCOW2 overlay
         let overlay = work_dir.join("overlay.qcow2");
         image::create_overlay(&spec.image_path, &overlay, spec.disk_gb).await?;
+        sandbox::restrict(&overlay, 0o600).await?;
 
         // Generate cloud-init seed ISO if configured
         let mut seed_iso_path = None;
@@ -121,6 +127,7 @@ impl Hypervisor for QemuBackend {
             let meta_data = format!("instance-id: {instance_id}\nlocal-hostname: {hostname}\n");
 
             cloudinit::create_nocloud_iso_raw(&ci.user_data, meta_data.as_bytes(), &iso_path)?;
+            sandbox::restrict(&iso_path, 0o600).await?;
             seed_iso_path = Some(iso_path);
         }
 
@@ -152,6 +159,11 @@ impl Hypervisor for QemuBackend {
             network: spec.network.clone(),
             ssh_host_port,
             mac_addr: Some(mac_addr),
+            systemd_unit: None,
+            sandbox: spec.sandbox.clone(),
+            qemu: spec.qemu.clone(),
+            tpm: spec.tpm,
+            propolis_addr: None,
         };
 
         info!(
@@ -278,39 +290,57 @@ impl Hypervisor for QemuBackend {
             ]);
         }
 
-        // Daemonize and pidfile
-        args.extend([
-            "-daemonize".into(),
-            "-pidfile".into(),
-            vm.work_dir.join("qemu.pid").display().to_string(),
-        ]);
+        // Extra arguments from the VMFile, validated against the managed ones at parse time
+        args.extend(vm.qemu.to_args());
+
+        // Hardening: VMFile settings override the global config, unset options use defaults
+        let policy = vm.sandbox.or(&self.sandbox);
+        let caps = QemuCaps::probe(&self.qemu_binary).await;
+        let needs_spawn = sandbox::needs_spawn(&vm.network, self.launcher);
+        args.extend(sandbox::qemu_args(
+            &vm.name,
+            &policy,
+            &caps,
+            sandbox::is_root(),
+            &vm.work_dir,
+            needs_spawn,
+        )?);
+
+        // Emulated TPM, started last so nothing above can fail and leave it behind
+        if let Some(model) = vm.tpm {
+            swtpm::start(vm, self.launcher).await?;
+            args.extend(swtpm::qemu_args(vm, model));
+        }
 
         info!(
             name = %vm.name,
             vcpus = vm.vcpus,
             memory_mb = vm.memory_mb,
             binary = %self.qemu_binary.display(),
+            launcher = %self.launcher,
             "QEMU: starting"
         );
-        debug!(args = ?args, "QEMU command line");
-
-        let status = tokio::process::Command::new(&self.qemu_binary)
-            .args(&args)
-            .status()
-            .await
-            .map_err(|e| VmError::QemuSpawnFailed { source: e })?;
-
-        if !status.success() {
-            return Err(VmError::QemuSpawnFailed {
-                source: std::io::Error::other(format!("QEMU exited with status {}", status)),
-            });
-        }
-
-        // Read PID from pidfile
-        let pid = Self::read_pid(&vm.work_dir).await;
 
-        // Wait for QMP socket and verify + query VNC
-        let mut qmp = QmpClient::connect(qmp_sock, Duration::from_secs(10)).await?;
+        let launched = match self.launcher.launch(&self.qemu_binary, &args, vm).await {
+            Ok(launched) => launched,
+            Err(e) => {
+                swtpm::stop(vm).await;
+                return Err(e);
+            }
+        };
+        let pid = launched.pid;
+
+        // Wait for QMP socket and verify + query VNC. If QEMU died right after startup the
+        // reason is in its log, so attach that to the error.
+        let mut qmp = match QmpClient::connect(qmp_sock, Duration::from_secs(10)).await {
+            Ok(qmp) => qmp,
+            Err(e) => {
+                let mut failed = vm.clone();
+                failed.systemd_unit = launched.unit;
+                swtpm::stop(vm).await;
+                return Err(e.with_log_tail(self.launcher.failure_log(&failed).await));
+            }
+        };
         let qmp_status = qmp.query_status().await?;
         let vnc_addr = qmp.query_vnc().await.unwrap_or(None);
 
@@ -325,6 +355,7 @@ impl Hypervisor for QemuBackend {
         let mut updated = vm.clone();
         updated.pid = pid;
         updated.vnc_addr = vnc_addr;
+        updated.systemd_unit = launched.unit;
 
         Ok(updated)
     }
@@ -340,18 +371,11 @@ impl Hypervisor for QemuBackend {
         }
 
         // Wait for process to exit
+        let launcher = Launcher::for_handle(vm);
         let start = tokio::time::Instant::now();
         loop {
-            if let Some(pid) = Self::read_pid(&vm.work_dir).await {
-                if !Self::pid_alive(pid) {
-                    info!(name = %vm.name, "QEMU: process exited after ACPI shutdown");
-                    let mut updated = vm.clone();
-                    updated.pid = None;
-                    updated.vnc_addr = None;
-                    return Ok(updated);
-                }
-            } else {
-                // No PID file, process likely already gone
+            if !launcher.is_running(vm).await {
+                info!(name = %vm.name, "QEMU: process exited after ACPI shutdown");
                 let mut updated = vm.clone();
                 updated.pid = None;
                 updated.vnc_addr = None;
@@ -365,22 +389,16 @@ impl Hypervisor for QemuBackend {
         }
 
         // SIGTERM fallback
-        if let Some(pid) = Self::read_pid(&vm.work_dir).await {
-            if Self::pid_alive(pid) {
-                warn!(name = %vm.name, pid, "QEMU: ACPI shutdown timed out, sending SIGTERM");
-                unsafe {
-                    libc::kill(pid as i32, libc::SIGTERM);
-                }
-                tokio::time::sleep(Duration::from_secs(3)).await;
-            }
+        if launcher.is_running(vm).await {
+            warn!(name = %vm.name, %launcher, "QEMU: ACPI shutdown timed out, sending SIGTERM");
+            launcher.terminate(vm).await;
+            tokio::time::sleep(Duration::from_secs(3)).await;
+        }
 
-            // SIGKILL if still alive
-            if Self::pid_alive(pid) {
-                warn!(name = %vm.name, pid, "QEMU: SIGTERM failed, sending SIGKILL");
-                unsafe {
-                    libc::kill(pid as i32, libc::SIGKILL);
-                }
-            }
+        // SIGKILL if still alive
+        if launcher.is_running(vm).await {
+            warn!(name = %vm.name, %launcher, "QEMU: SIGTERM failed, sending SIGKILL");
+            launcher.kill(vm).await;
         }
 
         let mut updated = vm.clone();
@@ -418,6 +436,9 @@ impl Hypervisor for QemuBackend {
             }
         }
 
+        Launcher::for_handle(&vm).cleanup(&vm).await;
+        swtpm::stop(&vm).await;
+
         // Remove work directory
         let _ = tokio::fs::remove_dir_all(&vm.work_dir).await;
         info!(name = %vm.name, "QEMU: destroyed");
@@ -425,24 +446,26 @@ impl Hypervisor for QemuBackend {
     }
 
     async fn state(&self, vm: &VmHandle) -> Result<VmState> {
-        // Check if process is alive
-        if let Some(pid) = Self::read_pid(&vm.work_dir).await {
-            if Self::pid_alive(pid) {
-                // Try QMP for detailed state
-                if let Some(ref qmp_sock) = vm.qmp_socket {
-                    if let Ok(mut qmp) = QmpClient::connect(qmp_sock, Duration::from_secs(2)).await
-                    {
-                        if let Ok(status) = qmp.query_status().await {
-                            return Ok(match status.as_str() {
-                                "running" => VmState::Running,
-                                "paused" | "suspended" => VmState::Stopped,
-                                _ => VmState::Running,
-                            });
-                        }
+        // Check if process is alive (pidfile or systemd unit status)
+        if Launcher::for_handle(vm).is_running(vm).await {
+            // A VM whose TPM emulator died can no longer use its TPM.
+            if vm.tpm.is_some() && !swtpm::is_running(vm).await {
+                warn!(name = %vm.name, "swtpm: emulator is not running");
+                return Ok(VmState::Failed);
+            }
+            // Try QMP for detailed state
+            if let Some(ref qmp_sock) = vm.qmp_socket {
+                if let Ok(mut qmp) = QmpClient::connect(qmp_sock, Duration::from_secs(2)).await {
+                    if let Ok(status) = qmp.query_status().await {
+                        return Ok(match status.as_str() {
+                            "running" => VmState::Running,
+                            "paused" | "suspended" => VmState::Stopped,
+                            _ => VmState::Running,
+                        });
                     }
                 }
-                return Ok(VmState::Running);
             }
+            return Ok(VmState::Running);
         }
 
         // Check if work dir exists (prepared but not running)
@@ -39,6 +39,7 @@ impl QmpClient {
                         return Err(VmError::QmpConnectionFailed {
                             path: socket_path.into(),
                             source: e,
+                            log_tail: String::new(),
                         });
                     }
                     let remaining = deadline.duration_since(tokio::time::Instant::now());
@@ -0,0 +1,337 @@
+//! Hardening of the QEMU process: seccomp filter, privilege drop and chroot.
+//!
+//! Which options are available depends on the QEMU build and version, so the binary is probed
+//! before each start. Defaults enable whatever is supported; options requested explicitly (in a
+//! VMFile or the global config) that cannot be honoured are an error rather than silently
+//! dropped.
+
+use std::path::Path;
+use std::process::Stdio;
+
+use tracing::warn;
+
+use super::launcher::Launcher;
+use crate::error::{Result, VmError};
+use crate::types::{NetworkConfig, SandboxConfig};
+
+/// Sandboxing features supported by a QEMU binary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QemuCaps {
+    /// `-sandbox` works (QEMU built with libseccomp).
+    pub seccomp: bool,
+    /// `-run-with user=` (QEMU >= 9.1).
+    pub run_with_user: bool,
+    /// `-run-with chroot=` (QEMU >= 8.1).
+    pub run_with_chroot: bool,
+    /// Legacy `-runas`.
+    pub runas: bool,
+    /// Legacy `-chroot`.
+    pub chroot: bool,
+}
+
+impl QemuCaps {
+    /// Probe the given QEMU binary. Failures to run it yield no capabilities.
+    pub async fn probe(binary: &Path) -> Self {
+        let help = tokio::process::Command::new(binary)
+            .arg("-help")
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+            .unwrap_or_default();
+        let mut caps = Self::from_help(&help);
+
+        // `-sandbox` is listed in the help even when seccomp support was compiled out, but such
+        // builds reject the option while parsing the command line, before `-version` exits.
+        caps.seccomp = tokio::process::Command::new(binary)
+            .args(["-sandbox", "on", "-version"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await
+            .is_ok_and(|s| s.success());
+
+        caps
+    }
+
+    /// Derive capabilities from `qemu-system-* -help` output.
+    pub fn from_help(help: &str) -> Self {
+        let option = |name: &str| {
+            help.lines()
+                .find(|l| l.split_whitespace().next() == Some(name))
+        };
+        let run_with = option("-run-with").unwrap_or_default();
+        Self {
+            seccomp: option("-sandbox").is_some(),
+            run_with_user: run_with.contains("user="),
+            run_with_chroot: run_with.contains("chroot="),
+            runas: option("-runas").is_some(),
+            chroot: option("-chroot").is_some(),
+        }
+    }
+}
+
+/// Whether the current process runs as root.
+pub fn is_root() -> bool {
+    // SAFETY: geteuid(2) cannot fail and has no side effects.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Whether QEMU must be allowed to create processes after installing its seccomp filter.
+///
+/// The filter is installed while the command line is parsed, before `-daemonize` forks, so the
+/// daemonize launcher needs it as much as the bridge helper for TAP networking does. Only QEMU
+/// running in the foreground of a systemd unit without TAP networking can deny spawning.
+pub fn needs_spawn(network: &NetworkConfig, launcher: Launcher) -> bool {
+    matches!(network, NetworkConfig::Tap { .. }) || launcher == Launcher::Daemonize
+}
+
+/// Build the QEMU arguments implementing `policy`.
+///
+/// `needs_spawn` must be set when QEMU has to create processes after startup, which the seccomp
+/// filter would otherwise forbid; see [`needs_spawn`].
+pub fn qemu_args(
+    name: &str,
+    policy: &SandboxConfig,
+    caps: &QemuCaps,
+    root: bool,
+    work_dir: &Path,
+    needs_spawn: bool,
+) -> Result<Vec<String>> {
+    let unavailable = |detail: String| VmError::SandboxUnavailable {
+        name: name.into(),
+        detail,
+    };
+    let mut args = Vec::new();
+
+    if let Some(ref user) = policy.user {
+        if !root {
+            return Err(unavailable(format!(
+                "dropping privileges to user '{user}' requires running as root"
+            )));
+        }
+        if !caps.run_with_user && !caps.runas {
+            return Err(unavailable(
+                "this QEMU supports neither -run-with user= nor -runas".into(),
+            ));
+        }
+    }
+
+    if policy.seccomp.unwrap_or(true) {
+        if caps.seccomp {
+            // With a privilege drop QEMU itself must still call setuid(), so only its children
+            // are denied new privileges.
+            let elevate = if policy.user.is_some() {
+                "children"
+            } else {
+                "deny"
+            };
+            let spawn = if needs_spawn { "allow" } else { "deny" };
+            args.extend([
+                "-sandbox".into(),
+                format!(
+                    "on,obsolete=deny,elevateprivileges={elevate},spawn={spawn},resourcecontrol=deny"
+                ),
+            ]);
+        } else if policy.seccomp == Some(true) {
+            return Err(unavailable(
+                "this QEMU was built without seccomp support".into(),
+            ));
+        } else {
+            warn!(
+                name,
+                "QEMU lacks seccomp support, starting without -sandbox"
+            );
+        }
+    }
+
+    // chroot defaults to on together with a privilege drop: as root it would be escapable.
+    let chroot = match policy.chroot {
+        Some(true) if !root => {
+            return Err(unavailable(
+                "chroot into the work directory requires running as root".into(),
+            ));
+        }
+        Some(true) if !caps.run_with_chroot && !caps.chroot => {
+            return Err(unavailable(
+                "this QEMU supports neither -run-with chroot= nor -chroot".into(),
+            ));
+        }
+        Some(chroot) => chroot,
+        None => policy.user.is_some() && (caps.run_with_chroot || caps.chroot),
+    };
+
+    let mut run_with = Vec::new();
+    if let Some(ref user) = policy.user {
+        if caps.run_with_user {
+            run_with.push(format!("user={user}"));
+        } else {
+            args.extend(["-runas".into(), user.clone()]);
+        }
+    }
+    if chroot {
+        if caps.run_with_chroot {
+            run_with.push(format!("chroot={}", work_dir.display()));
+        } else {
+            args.extend(["-chroot".into(), work_dir.display().to_string()]);
+        }
+    }
+    if !run_with.is_empty() {
+        args.extend(["-run-with".into(), run_with.join(",")]);
+    }
+
+    Ok(args)
+}
+
+/// Restrict `path` to its owner with the given mode.
+pub async fn restrict(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELP_9_1: &str = "\
+-sandbox on[,obsolete=allow|deny][,elevateprivileges=allow|deny|children]
+                Enable seccomp mode 2 system call filter (default 'off').
+-run-with [async-teardown=on|off][,chroot=dir][user=username|uid:gid]
+                Set miscellaneous QEMU process lifecycle options:
+";
+
+    const HELP_8_0: &str = "\
+-sandbox on[,obsolete=allow|deny][,elevateprivileges=allow|deny|children]
+-chroot dir     chroot to dir just before starting the VM
+-runas user     change to user id user just before starting the VM
+";
+
+    fn full_caps() -> QemuCaps {
+        QemuCaps {
+            seccomp: true,
+            ..QemuCaps::from_help(HELP_9_1)
+        }
+    }
+
+    #[test]
+    fn detect_run_with() {
+        let caps = QemuCaps::from_help(HELP_9_1);
+        assert!(caps.run_with_user && caps.run_with_chroot);
+        assert!(!caps.runas && !caps.chroot);
+
+        let old = QemuCaps::from_help(HELP_8_0);
+        assert!(!old.run_with_user && !old.run_with_chroot);
+        assert!(old.runas && old.chroot);
+    }
+
+    #[test]
+    fn seccomp_on_by_default() {
+        let args = qemu_args(
+            "vm",
+            &SandboxConfig::default(),
+            &full_caps(),
+            false,
+            Path::new("/w"),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            args,
+            [
+                "-sandbox",
+                "on,obsolete=deny,elevateprivileges=deny,spawn=deny,resourcecontrol=deny"
+            ]
+        );
+    }
+
+    #[test]
+    fn seccomp_allows_spawn_for_bridge_helper() {
+        let args = qemu_args(
+            "vm",
+            &SandboxConfig::default(),
+            &full_caps(),
+            false,
+            Path::new("/w"),
+            true,
+        )
+        .unwrap();
+        assert!(args[1].contains("spawn=allow"));
+    }
+
+    #[test]
+    fn daemonize_needs_spawn() {
+        let tap = NetworkConfig::Tap {
+            bridge: "br0".into(),
+        };
+        assert!(needs_spawn(&NetworkConfig::User, Launcher::Daemonize));
+        assert!(needs_spawn(&NetworkConfig::None, Launcher::Daemonize));
+        assert!(needs_spawn(&tap, Launcher::Systemd));
+        assert!(!needs_spawn(&NetworkConfig::User, Launcher::Systemd));
+
+        let args = qemu_args(
+            "vm",
+            &SandboxConfig::default(),
+            &full_caps(),
+            false,
+            Path::new("/w"),
+            needs_spawn(&NetworkConfig::User, Launcher::Daemonize),
+        )
+        .unwrap();
+        assert!(args[1].contains("spawn=allow"), "got: {args:?}");
+    }
+
+    #[test]
+    fn missing_seccomp_is_skipped_unless_requested() {
+        let caps = QemuCaps::default();
+        let args = qemu_args(
+            "vm",
+            &SandboxConfig::default(),
+            &caps,
+            false,
+            Path::new("/w"),
+            false,
+        )
+        .unwrap();
+        assert!(args.is_empty());
+
+        let explicit = SandboxConfig {
+            seccomp: Some(true),
+            ..Default::default()
+        };
+        assert!(qemu_args("vm", &explicit, &caps, false, Path::new("/w"), false).is_err());
+    }
+
+    #[test]
+    fn user_drop_implies_chroot() {
+        let policy = SandboxConfig {
+            user: Some("qemu".into()),
+            ..Default::default()
+        };
+        let args = qemu_args("vm", &policy, &full_caps(), true, Path::new("/w"), false).unwrap();
+        assert!(args[1].contains("elevateprivileges=children"));
+        assert_eq!(&args[2..], ["-run-with", "user=qemu,chroot=/w"]);
+
+        let legacy = QemuCaps {
+            seccomp: false,
+            ..QemuCaps::from_help(HELP_8_0)
+        };
+        let args = qemu_args("vm", &policy, &legacy, true, Path::new("/w"), false).unwrap();
+        assert_eq!(args, ["-runas", "qemu", "-chroot", "/w"]);
+    }
+
+    #[test]
+    fn user_drop_requires_root() {
+        let policy = SandboxConfig {
+            user: Some("qemu".into()),
+            ..Default::default()
+        };
+        let err =
+            qemu_args("vm", &policy, &full_caps(), false, Path::new("/w"), false).unwrap_err();
+        assert!(
+            err.to_string().contains("requires running as root"),
+            "got: {err}"
+        );
+    }
+}
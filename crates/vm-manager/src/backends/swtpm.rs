@@ -0,0 +1,271 @@
+//! Per-VM software TPM 2.0 emulator (`swtpm`).
+//!
+//! The emulator keeps its state in `tpm/` inside the VM work directory, so keys and PCR-sealed
+//! secrets survive restarts and are removed together with the VM. It is started with
+//! `--terminate`, which makes it exit as soon as QEMU closes the connection.
+//!
+//! How the emulator is supervised follows the QEMU launcher. With systemd it runs in its own
+//! transient unit, `vmctl-<name>-swtpm.service`, which the QEMU unit is bound to: if the
+//! emulator dies, systemd stops the VM rather than leaving it running without its TPM. A
+//! daemonized emulator is watched through its pidfile, and a VM that lost it is reported as
+//! failed.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::error::{Result, VmError};
+use crate::types::{TpmModel, VmHandle};
+
+use super::launcher::{self, Launcher, pid_alive, read_pidfile, systemctl};
+
+/// Control socket QEMU connects to.
+const SOCKET: &str = "swtpm.sock";
+const PIDFILE: &str = "swtpm.pid";
+const LOG: &str = "swtpm.log";
+/// Directory holding the persistent TPM state.
+const STATE_DIR: &str = "tpm";
+/// How long to wait for the emulator to create its control socket.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn socket_path(vm: &VmHandle) -> PathBuf {
+    vm.work_dir.join(SOCKET)
+}
+
+/// Transient unit the emulator of VM `vm_name` runs in under the systemd launcher.
+pub fn unit_name(vm_name: &str) -> String {
+    let qemu = launcher::unit_name(vm_name);
+    format!("{}-swtpm.service", qemu.trim_end_matches(".service"))
+}
+
+/// Start the emulator for `vm` the way `launcher` starts QEMU, replacing any leftover instance
+/// from a previous run.
+pub async fn start(vm: &VmHandle, launcher: Launcher) -> Result<()> {
+    start_with(vm, launcher, Path::new("swtpm"), SOCKET_TIMEOUT).await
+}
+
+async fn start_with(
+    vm: &VmHandle,
+    launcher: Launcher,
+    binary: &Path,
+    socket_timeout: Duration,
+) -> Result<()> {
+    stop(vm).await;
+
+    let state_dir = vm.work_dir.join(STATE_DIR);
+    tokio::fs::create_dir_all(&state_dir).await?;
+    super::sandbox::restrict(&state_dir, 0o700).await?;
+
+    let socket = socket_path(vm);
+    let _ = tokio::fs::remove_file(&socket).await;
+
+    let failed = |detail: String| VmError::SwtpmFailed {
+        name: vm.name.clone(),
+        detail,
+    };
+
+    let args = swtpm_args(&vm.work_dir, launcher == Launcher::Daemonize);
+    let mut cmd = match launcher {
+        Launcher::Daemonize => {
+            let mut cmd = tokio::process::Command::new(binary);
+            cmd.args(&args);
+            cmd
+        }
+        Launcher::Systemd => {
+            let unit = unit_name(&vm.name);
+            let _ = systemctl(&["reset-failed", &unit]).await;
+            let mut cmd = tokio::process::Command::new("systemd-run");
+            cmd.args(launcher::systemd_run_args(
+                &unit,
+                &format!("vmctl TPM of VM {}", vm.name),
+                None,
+                binary,
+                &args,
+            ));
+            cmd
+        }
+    };
+    let output = cmd
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| failed(format!("cannot run swtpm: {e}")))?;
+    if !output.status.success() {
+        stop(vm).await;
+        return Err(failed(format!(
+            "swtpm exited with status {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    // swtpm creates the socket before daemonizing, but give it a moment on slow hosts.
+    let deadline = tokio::time::Instant::now() + socket_timeout;
+    while !socket.exists() {
+        if tokio::time::Instant::now() >= deadline {
+            stop(vm).await;
+            return Err(failed(format!(
+                "control socket {} did not appear",
+                socket.display()
+            )));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let pid = read_pidfile(&vm.work_dir.join(PIDFILE)).await;
+    info!(name = %vm.name, pid = ?pid, "swtpm: started");
+    Ok(())
+}
+
+/// Whether the emulator of `vm` is running.
+pub async fn is_running(vm: &VmHandle) -> bool {
+    if vm.systemd_unit.is_some() {
+        return systemctl(&["is-active", "--quiet", &unit_name(&vm.name)])
+            .await
+            .is_ok_and(|o| o.status.success());
+    }
+    read_pidfile(&vm.work_dir.join(PIDFILE))
+        .await
+        .is_some_and(pid_alive)
+}
+
+/// Terminate the emulator if it is still running. QEMU exiting normally already ends it.
+pub async fn stop(vm: &VmHandle) {
+    // The unit is only there under the systemd launcher; stopping a missing one is harmless.
+    let unit = unit_name(&vm.name);
+    if let Ok(output) = systemctl(&["is-active", "--quiet", &unit]).await {
+        if output.status.success() {
+            warn!(name = %vm.name, unit = %unit, "swtpm: stopping leftover emulator unit");
+            let _ = systemctl(&["stop", &unit]).await;
+        }
+    }
+
+    let pidfile = vm.work_dir.join(PIDFILE);
+    if let Some(pid) = read_pidfile(&pidfile).await {
+        if pid_alive(pid) {
+            warn!(name = %vm.name, pid, "swtpm: terminating leftover emulator");
+            // SAFETY: plain signal delivery to a PID we started.
+            unsafe {
+                libc::kill(pid as i32, libc::SIGTERM);
+            }
+        }
+    }
+    let _ = tokio::fs::remove_file(&pidfile).await;
+}
+
+/// Command-line arguments for `swtpm` with all files under `work_dir`, detaching itself if
+/// `daemon` is set.
+fn swtpm_args(work_dir: &Path, daemon: bool) -> Vec<String> {
+    let mut args = vec![
+        "socket".into(),
+        "--tpm2".into(),
+        "--tpmstate".into(),
+        format!("dir={}", work_dir.join(STATE_DIR).display()),
+        "--ctrl".into(),
+        format!("type=unixio,path={}", work_dir.join(SOCKET).display()),
+        "--pid".into(),
+        format!("file={}", work_dir.join(PIDFILE).display()),
+        "--log".into(),
+        format!("file={},level=20", work_dir.join(LOG).display()),
+        "--terminate".into(),
+    ];
+    if daemon {
+        args.push("--daemon".into());
+    }
+    args
+}
+
+/// QEMU arguments attaching the emulator as a TPM device of the given model.
+pub fn qemu_args(vm: &VmHandle, model: TpmModel) -> Vec<String> {
+    let device = match model {
+        TpmModel::Crb => "tpm-crb",
+        TpmModel::Tis => "tpm-tis",
+    };
+    vec![
+        "-chardev".into(),
+        format!("socket,id=chrtpm,path={}", socket_path(vm).display()),
+        "-tpmdev".into(),
+        "emulator,id=tpm0,chardev=chrtpm".into(),
+        "-device".into(),
+        format!("{device},tpmdev=tpm0"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_lives_in_work_dir() {
+        let args = swtpm_args(Path::new("/vms/web"), true);
+        assert_eq!(args[0], "socket");
+        assert!(args.contains(&"dir=/vms/web/tpm".to_string()));
+        assert!(args.contains(&"type=unixio,path=/vms/web/swtpm.sock".to_string()));
+        assert!(args.contains(&"--terminate".to_string()));
+        assert!(args.contains(&"--daemon".to_string()));
+        // A systemd unit keeps it in the foreground.
+        assert!(!swtpm_args(Path::new("/vms/web"), false).contains(&"--daemon".to_string()));
+        assert_eq!(unit_name("web"), "vmctl-web-swtpm.service");
+    }
+
+    #[tokio::test]
+    async fn failed_start_leaves_no_emulator_behind() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        // Daemonizes like swtpm but never creates the control socket.
+        let fake = dir.path().join("swtpm");
+        std::fs::write(
+            &fake,
+            "#!/bin/sh\n\
+             for a in \"$@\"; do case \"$a\" in file=*.pid) pidfile=\"${a#file=}\";; esac; done\n\
+             sleep 60 </dev/null >/dev/null 2>&1 &\n\
+             echo $! > \"$pidfile\"\n\
+             echo $! > \"$pidfile.test\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let work_dir = dir.path().join("vm");
+        std::fs::create_dir(&work_dir).unwrap();
+        let vm: VmHandle = serde_json::from_value(serde_json::json!({
+            "id": "x", "name": "tpm-test", "backend": "qemu", "work_dir": work_dir,
+        }))
+        .unwrap();
+
+        let err = start_with(&vm, Launcher::Daemonize, &fake, Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("did not appear"), "got: {err}");
+        assert!(!work_dir.join(PIDFILE).exists());
+        assert!(!is_running(&vm).await);
+
+        // The emulator itself was terminated (an unreaped zombie counts as gone).
+        let pid = read_pidfile(&work_dir.join("swtpm.pid.test"))
+            .await
+            .unwrap();
+        let gone = || {
+            std::fs::read_to_string(format!("/proc/{pid}/stat"))
+                .map(|stat| stat.rsplit(") ").next().unwrap_or("").starts_with('Z'))
+                .unwrap_or(true)
+        };
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !gone() && std::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(gone(), "swtpm {pid} is still running");
+    }
+
+    #[test]
+    fn device_matches_model() {
+        let vm: VmHandle = serde_json::from_str(
+            r#"{"id":"x","name":"web","backend":"qemu","work_dir":"/vms/web"}"#,
+        )
+        .unwrap();
+        let args = qemu_args(&vm, TpmModel::Tis);
+        assert_eq!(args[1], "socket,id=chrtpm,path=/vms/web/swtpm.sock");
+        assert_eq!(args.last().unwrap(), "tpm-tis,tpmdev=tpm0");
+    }
+}
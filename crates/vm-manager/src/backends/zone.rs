@@ -0,0 +1,396 @@
+//! Lifecycle of the `nebula-vm` branded zones that host propolis-server.
+//!
+//! Every step checks the zone state first, so repeating an operation is harmless, and any
+//! command that then fails is reported as [`VmError::CommandFailed`].
+
+use std::path::{Path, PathBuf};
+
+use tracing::info;
+
+use super::command::CommandRunner;
+use crate::error::{Result, VmError};
+use crate::lock::FileLock;
+
+/// Zone brand managed by vmctl.
+pub const BRAND: &str = "nebula-vm";
+
+/// zonecfg attribute holding the propolis-server address, read by the brand boot script.
+pub const ADDR_ATTR: &str = "propolis-addr";
+
+/// First port handed out to a VM's propolis-server.
+const PROPOLIS_BASE_PORT: u16 = 12400;
+
+/// Lock file in the data directory that serializes picking and committing addresses.
+const ADDR_LOCK: &str = ".propolis-addr.lock";
+
+/// State of `zone` as reported by `zoneadm list -p` (e.g. `installed`, `running`), or `None`
+/// if there is no such zone.
+pub async fn state(runner: &impl CommandRunner, zone: &str) -> Result<Option<String>> {
+    let output = runner
+        .output("zoneadm", &["-z", zone, "list", "-p"])
+        .await?;
+    if !output.success() {
+        return Ok(None);
+    }
+    // Format: zoneid:zonename:state:zonepath:uuid:brand:ip-type
+    Ok(output
+        .stdout
+        .split(':')
+        .nth(2)
+        .map(|s| s.trim().to_string()))
+}
+
+/// Root file system of the zone at `zonepath`, as seen from the global zone.
+pub fn root(zonepath: &Path) -> PathBuf {
+    zonepath.join("root")
+}
+
+/// Where `path` is seen inside the zone at `zonepath`. Paths under the zone's [`root`] are
+/// moved to the zone's `/`; others, such as delegated devices, are the same in the zone.
+pub fn in_zone(zonepath: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix(root(zonepath)) {
+        Ok(relative) => Path::new("/").join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Configure and install `zone` with its root at `zonepath`, networking on `vnic` and the
+/// device `disk` delegated to it, skipping what is already done, and make sure it has a
+/// propolis-server address. Returns the address.
+///
+/// A new address is picked and committed under a lock in `data_dir`, so zones provisioned at
+/// the same time never get the same one.
+pub async fn provision(
+    runner: &impl CommandRunner,
+    zone: &str,
+    zonepath: &Path,
+    vnic: &str,
+    disk: &Path,
+    data_dir: &Path,
+) -> Result<String> {
+    let mut current = state(runner, zone).await?;
+
+    if current.is_none() {
+        let cmds = format!(
+            "create -b; set brand={BRAND}; set zonepath={}; set ip-type=exclusive; \
+             add net; set physical={vnic}; end; add device; set match={}; end; commit",
+            zonepath.display(),
+            disk.display()
+        );
+        runner.run("zonecfg", &["-z", zone, &cmds]).await?;
+        current = Some("configured".into());
+    }
+
+    // Keep an address configured earlier (e.g. the zone's own IP on an etherstub).
+    let addr = match attr(runner, zone, ADDR_ATTR).await? {
+        Some(addr) => addr,
+        None => {
+            let _lock = FileLock::acquire(&data_dir.join(ADDR_LOCK)).await?;
+            let addr = allocate_addr(runner, zone).await?;
+            let cmds = format!(
+                "add attr; set name={ADDR_ATTR}; set type=string; set value={addr}; end; commit"
+            );
+            runner.run("zonecfg", &["-z", zone, &cmds]).await?;
+            addr
+        }
+    };
+
+    if current.as_deref() == Some("configured") {
+        runner.run("zoneadm", &["-z", zone, "install"]).await?;
+    }
+
+    info!(zone, addr = %addr, "zone: provisioned");
+    Ok(addr)
+}
+
+/// Boot `zone` unless it is running already.
+pub async fn boot(runner: &impl CommandRunner, zone: &str) -> Result<()> {
+    if state(runner, zone).await?.as_deref() != Some("running") {
+        runner.run("zoneadm", &["-z", zone, "boot"]).await?;
+    }
+    Ok(())
+}
+
+/// Halt `zone` if it is running.
+pub async fn halt(runner: &impl CommandRunner, zone: &str) -> Result<()> {
+    if matches!(
+        state(runner, zone).await?.as_deref(),
+        Some("running" | "ready" | "shutting_down")
+    ) {
+        runner.run("zoneadm", &["-z", zone, "halt"]).await?;
+    }
+    Ok(())
+}
+
+/// Halt, uninstall and delete `zone`, as far as it exists.
+pub async fn remove(runner: &impl CommandRunner, zone: &str) -> Result<()> {
+    halt(runner, zone).await?;
+    match state(runner, zone).await?.as_deref() {
+        None => return Ok(()),
+        Some("configured") => {}
+        Some(_) => {
+            runner
+                .run("zoneadm", &["-z", zone, "uninstall", "-F"])
+                .await?;
+        }
+    }
+    runner.run("zonecfg", &["-z", zone, "delete", "-F"]).await?;
+    Ok(())
+}
+
+/// Value of the zonecfg attribute `name`, if set.
+pub async fn attr(runner: &impl CommandRunner, zone: &str, name: &str) -> Result<Option<String>> {
+    let filter = format!("name={name}");
+    let output = runner
+        .output("zonecfg", &["-z", zone, "info", "attr", &filter])
+        .await?;
+    Ok(if output.success() {
+        parse_attr_value(&output.stdout)
+    } else {
+        None
+    })
+}
+
+/// Pick a loopback port that no other zone of our brand is configured with.
+pub async fn allocate_addr(runner: &impl CommandRunner, zone: &str) -> Result<String> {
+    let listing = runner.run("zoneadm", &["list", "-cp"]).await?;
+    let mut used = Vec::new();
+    // Format: zoneid:zonename:state:zonepath:uuid:brand:ip-type
+    for line in listing.stdout.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 6 || fields[5] != BRAND || fields[1] == zone {
+            continue;
+        }
+        if let Some(addr) = attr(runner, fields[1], ADDR_ATTR).await? {
+            if let Some(port) = addr.rsplit(':').next().and_then(|p| p.parse().ok()) {
+                used.push(port);
+            }
+        }
+    }
+
+    let port = next_free_port(&used).ok_or_else(|| VmError::PropolisAddrExhausted {
+        name: zone.into(),
+        range: format!("127.0.0.1:{PROPOLIS_BASE_PORT}-{}", u16::MAX),
+    })?;
+    Ok(format!("127.0.0.1:{port}"))
+}
+
+/// First IPv4 address configured inside `zone`, other than loopback.
+pub async fn guest_ip(runner: &impl CommandRunner, zone: &str) -> Result<Option<String>> {
+    let output = runner
+        .output("zlogin", &[zone, "ipadm", "show-addr", "-p", "-o", "ADDR"])
+        .await?;
+    if !output.success() {
+        return Ok(None);
+    }
+    Ok(output
+        .stdout
+        .lines()
+        .map(|line| line.split('/').next().unwrap_or("").trim())
+        .find(|addr| !addr.is_empty() && *addr != "127.0.0.1" && addr.contains('.'))
+        .map(str::to_string))
+}
+
+/// Value of the single attribute in `zonecfg info attr` output.
+fn parse_attr_value(info: &str) -> Option<String> {
+    info.lines()
+        .find_map(|l| l.trim().strip_prefix("value:"))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Lowest port from [`PROPOLIS_BASE_PORT`] up that is not in `used`.
+fn next_free_port(used: &[u16]) -> Option<u16> {
+    (PROPOLIS_BASE_PORT..=u16::MAX).find(|p| !used.contains(p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::command::{CommandOutput, RecordingRunner};
+
+    const ATTR_INFO: &str =
+        "attr:\n\tname: propolis-addr\n\ttype: string\n\tvalue: 127.0.0.1:12400\n";
+
+    #[test]
+    fn parses_zonecfg_attr() {
+        assert_eq!(
+            parse_attr_value(ATTR_INFO).as_deref(),
+            Some("127.0.0.1:12400")
+        );
+        assert_eq!(parse_attr_value(""), None);
+    }
+
+    #[test]
+    fn allocates_lowest_free_port() {
+        assert_eq!(next_free_port(&[]), Some(12400));
+        assert_eq!(next_free_port(&[12400, 12402]), Some(12401));
+        assert_eq!(next_free_port(&[12401, 12400]), Some(12402));
+        let all: Vec<u16> = (PROPOLIS_BASE_PORT..=u16::MAX).collect();
+        assert_eq!(next_free_port(&all), None);
+    }
+
+    #[test]
+    fn maps_paths_into_the_zone() {
+        let zonepath = Path::new("/vms/web");
+        assert_eq!(
+            in_zone(zonepath, &root(zonepath).join("vmctl/seed.iso")),
+            Path::new("/vmctl/seed.iso")
+        );
+        assert_eq!(
+            in_zone(zonepath, Path::new("/dev/zvol/rdsk/rpool/vms/web")),
+            Path::new("/dev/zvol/rdsk/rpool/vms/web")
+        );
+    }
+
+    #[tokio::test]
+    async fn provisions_new_zone() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = RecordingRunner::new()
+            .on(
+                "zoneadm -z web list",
+                CommandOutput::failed(1, "no such zone"),
+            )
+            .on(
+                "zoneadm list -cp",
+                CommandOutput::ok(
+                    "0:global:running:/::ipkg:shared\n\
+                     -:db:installed:/vms/db:x:nebula-vm:excl\n",
+                ),
+            )
+            .on("zonecfg -z db info attr", CommandOutput::ok(ATTR_INFO));
+
+        let addr = provision(
+            &runner,
+            "web",
+            Path::new("/vms/web"),
+            "vnic0",
+            Path::new("/dev/zvol/rdsk/rpool/vms/web"),
+            dir.path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(addr, "127.0.0.1:12401");
+
+        let calls = runner.calls();
+        assert!(calls[1].starts_with("zonecfg -z web create -b; set brand=nebula-vm;"));
+        assert!(calls[1].contains("set zonepath=/vms/web;"));
+        assert!(calls[1].contains("set physical=vnic0;"));
+        // propolis-server runs inside the zone and opens the boot disk there.
+        assert!(calls[1].contains("add device; set match=/dev/zvol/rdsk/rpool/vms/web; end;"));
+        assert!(calls.contains(&"zonecfg -z web add attr; set name=propolis-addr; set type=string; set value=127.0.0.1:12401; end; commit".to_string()));
+        assert_eq!(calls.last().unwrap(), "zoneadm -z web install");
+    }
+
+    #[tokio::test]
+    async fn provisioning_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = RecordingRunner::new()
+            .on(
+                "zoneadm -z web list",
+                CommandOutput::ok("-:web:installed:/vms/web:x:nebula-vm:excl\n"),
+            )
+            .on("zonecfg -z web info attr", CommandOutput::ok(ATTR_INFO));
+
+        let addr = provision(
+            &runner,
+            "web",
+            Path::new("/vms/web"),
+            "vnic0",
+            Path::new("/dev/zvol/rdsk/rpool/vms/web"),
+            dir.path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(addr, "127.0.0.1:12400");
+        assert_eq!(
+            runner.calls(),
+            [
+                "zoneadm -z web list -p",
+                "zonecfg -z web info attr name=propolis-addr"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_install_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = RecordingRunner::new()
+            .on(
+                "zoneadm -z web list",
+                CommandOutput::ok("-:web:configured:/vms/web:x:nebula-vm:excl\n"),
+            )
+            .on("zonecfg -z web info attr", CommandOutput::ok(ATTR_INFO))
+            .on(
+                "zoneadm -z web install",
+                CommandOutput::failed(1, "install failed: no space"),
+            );
+
+        let err = provision(
+            &runner,
+            "web",
+            Path::new("/vms/web"),
+            "vnic0",
+            Path::new("/dev/zvol/rdsk/rpool/vms/web"),
+            dir.path(),
+        )
+        .await
+        .unwrap_err();
+        match err {
+            VmError::CommandFailed {
+                command, stderr, ..
+            } => {
+                assert_eq!(command, "zoneadm -z web install");
+                assert!(stderr.contains("no space"));
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn removes_running_zone() {
+        let runner = RecordingRunner::new()
+            .on(
+                "zoneadm -z web list",
+                CommandOutput::ok("1:web:running:/vms/web:x:nebula-vm:excl\n"),
+            )
+            .on(
+                "zoneadm -z web list",
+                CommandOutput::ok("-:web:installed:/vms/web:x:nebula-vm:excl\n"),
+            );
+
+        remove(&runner, "web").await.unwrap();
+        assert_eq!(
+            runner.calls(),
+            [
+                "zoneadm -z web list -p",
+                "zoneadm -z web halt",
+                "zoneadm -z web list -p",
+                "zoneadm -z web uninstall -F",
+                "zonecfg -z web delete -F",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn removing_missing_zone_is_a_no_op() {
+        let runner = RecordingRunner::new().on(
+            "zoneadm -z web list",
+            CommandOutput::failed(1, "no such zone"),
+        );
+        remove(&runner, "web").await.unwrap();
+        assert_eq!(runner.calls().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn finds_guest_ip() {
+        let runner = RecordingRunner::new().on(
+            "zlogin web ipadm",
+            CommandOutput::ok("127.0.0.1/8\n::1/128\n10.0.0.5/24\n"),
+        );
+        assert_eq!(
+            guest_ip(&runner, "web").await.unwrap().as_deref(),
+            Some("10.0.0.5")
+        );
+    }
+}
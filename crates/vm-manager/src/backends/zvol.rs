@@ -0,0 +1,323 @@
+//! Disk images as ZFS volumes, for the Propolis backend.
+//!
+//! A downloaded image is converted to raw once and written into the zvol
+//! `{pool}/images/<sha256>`, named after the image content so that identical images share it.
+//! The zvol is snapshotted as `@base`, and each VM disk is a clone of that snapshot.
+
+use std::path::{Path, PathBuf};
+
+use tracing::info;
+
+use super::command::CommandRunner;
+use crate::error::{Result, VmError};
+use crate::image::{content_hash, format};
+
+/// Snapshot every VM clone is created from.
+pub const BASE_SNAPSHOT: &str = "base";
+
+/// zvol sizes are rounded up to this, a multiple of every usual volblocksize.
+const VOLSIZE_ALIGN: u64 = 1024 * 1024;
+
+/// Dataset holding the image with content hash `hash`.
+pub fn image_dataset(pool: &str, hash: &str) -> String {
+    format!("{pool}/images/{hash}")
+}
+
+/// Raw device node of a zvol.
+pub fn device_path(dataset: &str) -> PathBuf {
+    PathBuf::from(format!("/dev/zvol/rdsk/{dataset}"))
+}
+
+/// Round `bytes` up to a valid zvol size.
+fn align_volsize(bytes: u64) -> u64 {
+    bytes.div_ceil(VOLSIZE_ALIGN).max(1) * VOLSIZE_ALIGN
+}
+
+/// Import `image` into `{pool}/images/<sha256>@base` unless it is there already, returning the
+/// snapshot name.
+pub async fn import(runner: &impl CommandRunner, pool: &str, image: &Path) -> Result<String> {
+    let hash = content_hash(image).await?;
+    let dataset = image_dataset(pool, &hash);
+    let snapshot = format!("{dataset}@{BASE_SNAPSHOT}");
+
+    if exists(runner, &snapshot).await? {
+        info!(image = %image.display(), snapshot = %snapshot, "zvol: reusing imported image");
+        return Ok(snapshot);
+    }
+    // A zvol without snapshot is left over from an interrupted import.
+    if exists(runner, &dataset).await? {
+        runner.run("zfs", &["destroy", "-r", &dataset]).await?;
+    }
+
+    let image_arg = image.to_string_lossy();
+    let size = align_volsize(virtual_size(runner, image).await?);
+    info!(image = %image.display(), dataset = %dataset, size, "zvol: importing image");
+    runner
+        .run("zfs", &["create", "-p", "-V", &size.to_string(), &dataset])
+        .await?;
+
+    // -n: write into the existing device instead of creating a file.
+    let device = device_path(&dataset);
+    let converted = runner
+        .run(
+            "qemu-img",
+            &[
+                "convert",
+                "-n",
+                "-O",
+                "raw",
+                &image_arg,
+                &device.to_string_lossy(),
+            ],
+        )
+        .await;
+    if let Err(e) = converted {
+        let _ = runner.run("zfs", &["destroy", &dataset]).await;
+        return Err(e);
+    }
+
+    runner.run("zfs", &["snapshot", &snapshot]).await?;
+    Ok(snapshot)
+}
+
+/// Clone `snapshot` to `dataset`, growing the clone to `disk_gb` if that is larger than the
+/// image. An existing `dataset` is kept as it is.
+pub async fn clone(
+    runner: &impl CommandRunner,
+    snapshot: &str,
+    dataset: &str,
+    disk_gb: Option<u32>,
+) -> Result<()> {
+    if exists(runner, dataset).await? {
+        info!(dataset, "zvol: VM disk already exists");
+        return Ok(());
+    }
+
+    // Check the size before cloning, so a refused disk leaves no clone to be reused later.
+    let wanted = match disk_gb {
+        Some(gb) => {
+            let wanted = u64::from(gb) * 1024 * 1024 * 1024;
+            let current = volsize(runner, snapshot).await?;
+            if wanted < current {
+                return Err(VmError::ZfsFailed {
+                    dataset: dataset.into(),
+                    detail: format!(
+                        "disk_gb {gb} is smaller than the image ({current} bytes); disks cannot shrink"
+                    ),
+                });
+            }
+            Some(wanted).filter(|&w| w > current)
+        }
+        None => None,
+    };
+
+    runner
+        .run("zfs", &["clone", "-p", snapshot, dataset])
+        .await?;
+    if let Some(wanted) = wanted {
+        runner
+            .run("zfs", &["set", &format!("volsize={wanted}"), dataset])
+            .await?;
+    }
+    Ok(())
+}
+
+/// Destroy `dataset` and its snapshots, if it exists.
+pub async fn destroy(runner: &impl CommandRunner, dataset: &str) -> Result<()> {
+    if exists(runner, dataset).await? {
+        runner.run("zfs", &["destroy", "-r", dataset]).await?;
+    }
+    Ok(())
+}
+
+/// Whether a dataset or snapshot exists.
+pub async fn exists(runner: &impl CommandRunner, name: &str) -> Result<bool> {
+    let output = runner
+        .output("zfs", &["list", "-H", "-o", "name", "-t", "all", name])
+        .await?;
+    Ok(output.success())
+}
+
+async fn volsize(runner: &impl CommandRunner, dataset: &str) -> Result<u64> {
+    let out = runner
+        .run("zfs", &["get", "-Hp", "-o", "value", "volsize", dataset])
+        .await?
+        .stdout;
+    out.trim().parse().map_err(|_| VmError::ZfsFailed {
+        dataset: dataset.into(),
+        detail: format!("unexpected volsize '{}'", out.trim()),
+    })
+}
+
+/// Size of the disk an image describes (not of the file), read from its header or, for
+/// formats vmctl doesn't read itself, with `qemu-img info`.
+async fn virtual_size(runner: &impl CommandRunner, image: &Path) -> Result<u64> {
+    if let Some(info) = format::inspect(image).await? {
+        return Ok(info.virtual_size);
+    }
+    let output = runner
+        .run(
+            "qemu-img",
+            &["info", "--output=json", &image.to_string_lossy()],
+        )
+        .await?;
+    serde_json::from_str::<serde_json::Value>(&output.stdout)
+        .ok()
+        .and_then(|info| info.get("virtual-size").and_then(|s| s.as_u64()))
+        .ok_or_else(|| VmError::ImageFormatDetectionFailed {
+            path: image.into(),
+            detail: "qemu-img info reported no virtual size".into(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::command::{CommandOutput, RecordingRunner};
+
+    #[tokio::test]
+    async fn hashes_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("img");
+        std::fs::write(&path, b"abc").unwrap();
+        assert_eq!(
+            content_hash(&path).await.unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn names_and_sizes() {
+        assert_eq!(image_dataset("rpool", "ab12"), "rpool/images/ab12");
+        assert_eq!(
+            device_path("rpool/vms/web"),
+            Path::new("/dev/zvol/rdsk/rpool/vms/web")
+        );
+        assert_eq!(align_volsize(0), VOLSIZE_ALIGN);
+        assert_eq!(align_volsize(VOLSIZE_ALIGN), VOLSIZE_ALIGN);
+        assert_eq!(align_volsize(VOLSIZE_ALIGN + 1), 2 * VOLSIZE_ALIGN);
+    }
+
+    #[tokio::test]
+    async fn imports_image_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("img");
+        std::fs::write(&path, b"abc").unwrap();
+        let dataset =
+            "rpool/images/ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+        let runner = RecordingRunner::new().on(
+            "zfs list",
+            CommandOutput::failed(1, "dataset does not exist"),
+        );
+        let snapshot = import(&runner, "rpool", &path).await.unwrap();
+        assert_eq!(snapshot, format!("{dataset}@base"));
+        let calls = runner.calls();
+        assert!(!calls.iter().any(|c| c.starts_with("qemu-img info")));
+        assert!(calls.contains(&format!("zfs create -p -V 1048576 {dataset}")));
+        assert!(calls.contains(&format!(
+            "qemu-img convert -n -O raw {} /dev/zvol/rdsk/{dataset}",
+            path.display()
+        )));
+        assert_eq!(
+            calls.last().unwrap(),
+            &format!("zfs snapshot {dataset}@base")
+        );
+
+        let runner = RecordingRunner::new();
+        import(&runner, "rpool", &path).await.unwrap();
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sizes_foreign_formats_with_qemu_img() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.vhdx");
+        std::fs::write(&path, b"vhdxfile").unwrap();
+
+        let runner = RecordingRunner::new()
+            .on(
+                "zfs list",
+                CommandOutput::failed(1, "dataset does not exist"),
+            )
+            .on(
+                "qemu-img info",
+                CommandOutput::ok(r#"{"virtual-size": 3145728, "format": "vhdx"}"#),
+            );
+        import(&runner, "rpool", &path).await.unwrap();
+        assert!(
+            runner
+                .calls()
+                .iter()
+                .any(|c| c.starts_with("zfs create -p -V 3145728 "))
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_conversion_removes_zvol() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("img");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let runner = RecordingRunner::new()
+            .on(
+                "zfs list",
+                CommandOutput::failed(1, "dataset does not exist"),
+            )
+            .on("qemu-img convert", CommandOutput::failed(1, "I/O error"));
+        let err = import(&runner, "rpool", &path).await.unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::CommandFailed {
+                status: Some(1),
+                ..
+            }
+        ));
+        assert!(
+            runner
+                .calls()
+                .last()
+                .unwrap()
+                .starts_with("zfs destroy rpool/images/")
+        );
+    }
+
+    #[tokio::test]
+    async fn clone_grows_but_never_shrinks() {
+        let gib = 1024 * 1024 * 1024u64;
+        let runner = RecordingRunner::new()
+            .on(
+                "zfs list",
+                CommandOutput::failed(1, "dataset does not exist"),
+            )
+            .on("zfs get", CommandOutput::ok(format!("{}\n", 2 * gib)));
+        clone(&runner, "rpool/images/ab@base", "rpool/vms/web", Some(4))
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &format!("zfs set volsize={} rpool/vms/web", 4 * gib)
+        );
+
+        let runner = RecordingRunner::new()
+            .on(
+                "zfs list",
+                CommandOutput::failed(1, "dataset does not exist"),
+            )
+            .on("zfs get", CommandOutput::ok(format!("{}\n", 8 * gib)));
+        let err = clone(&runner, "rpool/images/ab@base", "rpool/vms/web", Some(4))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, VmError::ZfsFailed { .. }));
+        assert!(!runner.calls().iter().any(|c| c.starts_with("zfs clone")));
+
+        // Nothing was cloned, so a retry with a size that fits creates the disk.
+        clone(&runner, "rpool/images/ab@base", "rpool/vms/web", Some(8))
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            "zfs clone -p rpool/images/ab@base rpool/vms/web"
+        );
+    }
+}
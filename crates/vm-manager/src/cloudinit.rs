@@ -146,6 +146,16 @@ pub fn create_nocloud_iso(
     create_nocloud_iso_raw(&user_data, &meta_data, out_iso)
 }
 
+/// How a guest differs from the Debian-style defaults [`build_cloud_config`] assumes for the
+/// user it creates.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CloudInitQuirks {
+    /// Login shell, instead of `/bin/bash`.
+    pub shell: Option<String>,
+    /// Supplementary groups, instead of `sudo`. Empty adds the user to no groups.
+    pub groups: Option<Vec<String>>,
+}
+
 /// Build a minimal cloud-config user-data and meta-data from parameters.
 ///
 /// Returns `(user_data_bytes, meta_data_bytes)`.
@@ -155,13 +165,35 @@ pub fn build_cloud_config(
     instance_id: &str,
     hostname: &str,
 ) -> (Vec<u8>, Vec<u8>) {
+    build_cloud_config_with(
+        user,
+        ssh_pubkey,
+        instance_id,
+        hostname,
+        &CloudInitQuirks::default(),
+    )
+}
+
+/// Like [`build_cloud_config`], adapting the user to the guest's `quirks`.
+pub fn build_cloud_config_with(
+    user: &str,
+    ssh_pubkey: &str,
+    instance_id: &str,
+    hostname: &str,
+    quirks: &CloudInitQuirks,
+) -> (Vec<u8>, Vec<u8>) {
+    let groups = match &quirks.groups {
+        None => "    groups: [sudo]\n".to_string(),
+        Some(groups) if groups.is_empty() => String::new(),
+        Some(groups) => format!("    groups: [{}]\n", groups.join(", ")),
+    };
+    let shell = quirks.shell.as_deref().unwrap_or("/bin/bash");
     let user_data = format!(
         r#"#cloud-config
 users:
   - name: {user}
-    groups: [sudo]
-    sudo: ALL=(ALL) NOPASSWD:ALL
-    shell: /bin/bash
+{groups}    sudo: ALL=(ALL) NOPASSWD:ALL
+    shell: {shell}
     ssh_authorized_keys:
       - {ssh_pubkey}
 ssh_pwauth: false
@@ -0,0 +1,113 @@
+//! Host-wide vmctl configuration.
+//!
+//! Read from `$XDG_CONFIG_HOME/vmctl/config.kdl` (usually `~/.config/vmctl/config.kdl`). Every
+//! setting is optional; per-VM settings in a VMFile take precedence.
+//!
+//! ```kdl
+//! sandbox {
+//!     seccomp #true
+//!     user "qemu"
+//!     chroot #true
+//! }
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use kdl::KdlDocument;
+
+use crate::error::{Result, VmError};
+use crate::types::SandboxConfig;
+use crate::vmfile::parse_sandbox;
+
+/// Parsed contents of `config.kdl`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GlobalConfig {
+    /// Defaults for the QEMU sandbox, overridden by a VMFile's `sandbox` block.
+    pub sandbox: SandboxConfig,
+}
+
+impl GlobalConfig {
+    /// Default location of the config file.
+    pub fn path() -> PathBuf {
+        dirs::config_dir()
+            .unwrap_or_else(|| PathBuf::from("/tmp"))
+            .join("vmctl")
+            .join("config.kdl")
+    }
+
+    /// Load the config from [`GlobalConfig::path`]. A missing file yields the defaults.
+    pub fn load() -> Result<Self> {
+        Self::load_from(&Self::path())
+    }
+
+    /// Load the config from an explicit path. A missing file yields the defaults.
+    pub fn load_from(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Self::parse(&content, path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(VmError::ConfigParseFailed {
+                path: path.to_path_buf(),
+                detail: format!("could not read file: {e}"),
+            }),
+        }
+    }
+
+    /// Parse config file content. `path` is only used for error messages.
+    pub fn parse(content: &str, path: &Path) -> Result<Self> {
+        let doc: KdlDocument =
+            content
+                .parse()
+                .map_err(|e: kdl::KdlError| VmError::ConfigParseFailed {
+                    path: path.to_path_buf(),
+                    detail: e.to_string(),
+                })?;
+
+        let sandbox = match doc.get("sandbox").and_then(|n| n.children()) {
+            Some(sb) => parse_sandbox("<config>", sb)?,
+            None => SandboxConfig::default(),
+        };
+
+        Ok(Self { sandbox })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_file_is_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = GlobalConfig::load_from(&dir.path().join("config.kdl")).unwrap();
+        assert_eq!(cfg, GlobalConfig::default());
+    }
+
+    #[test]
+    fn parse_sandbox_defaults() {
+        let cfg = GlobalConfig::parse(
+            "sandbox {\n    seccomp #false\n    user \"qemu\"\n}\n",
+            Path::new("config.kdl"),
+        )
+        .unwrap();
+        assert_eq!(cfg.sandbox.seccomp, Some(false));
+        assert_eq!(cfg.sandbox.user.as_deref(), Some("qemu"));
+        assert_eq!(cfg.sandbox.chroot, None);
+    }
+
+    #[test]
+    fn vmfile_overrides_global() {
+        let global = SandboxConfig {
+            seccomp: Some(true),
+            user: Some("qemu".into()),
+            chroot: Some(true),
+        };
+        let vm = SandboxConfig {
+            chroot: Some(false),
+            ..Default::default()
+        };
+        let merged = vm.or(&global);
+        assert_eq!(merged.seccomp, Some(true));
+        assert_eq!(merged.user.as_deref(), Some("qemu"));
+        assert_eq!(merged.chroot, Some(false));
+    }
+}
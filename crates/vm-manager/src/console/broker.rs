@@ -0,0 +1,413 @@
+//! Serial console broker.
+//!
+//! QEMU's console chardev accepts a single client at a time. The broker holds that connection
+//! and fans the output out to any number of clients on its own Unix socket. It keeps a
+//! scrollback buffer so that a client attaching later first sees recent output.
+//!
+//! Protocol: after connecting, a client sends a one-line greeting, `rw\n` or `ro\n`. It then
+//! receives the scrollback followed by live output. Bytes sent by `rw` clients are forwarded to
+//! the guest, bytes sent by `ro` clients are discarded.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info};
+
+use crate::error::{Result, VmError};
+use crate::lock::FileLock;
+
+/// Default scrollback size in bytes.
+pub const DEFAULT_SCROLLBACK: usize = 64 * 1024;
+
+/// How long a client may take to send its greeting.
+const GREETING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether an attached client may type into the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachMode {
+    ReadOnly,
+    ReadWrite,
+}
+
+impl AttachMode {
+    fn greeting(self) -> &'static [u8] {
+        match self {
+            Self::ReadOnly => b"ro\n",
+            Self::ReadWrite => b"rw\n",
+        }
+    }
+
+    fn from_greeting(line: &str) -> Option<Self> {
+        match line.trim() {
+            "ro" => Some(Self::ReadOnly),
+            "rw" => Some(Self::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+/// Connect to a running broker.
+pub async fn attach(socket: &Path, mode: AttachMode) -> Result<UnixStream> {
+    let connect_failed = |source| VmError::ConsoleConnectFailed {
+        path: socket.to_path_buf(),
+        source,
+    };
+    let mut stream = UnixStream::connect(socket).await.map_err(connect_failed)?;
+    stream
+        .write_all(mode.greeting())
+        .await
+        .map_err(connect_failed)?;
+    Ok(stream)
+}
+
+/// A console broker between one upstream console socket and many clients.
+#[derive(Debug, Clone)]
+pub struct Broker {
+    upstream: PathBuf,
+    listen: PathBuf,
+    seed_log: Option<PathBuf>,
+    scrollback: usize,
+}
+
+impl Broker {
+    /// Broker between QEMU's console socket `upstream` and a client socket at `listen`.
+    pub fn new(upstream: impl Into<PathBuf>, listen: impl Into<PathBuf>) -> Self {
+        Self {
+            upstream: upstream.into(),
+            listen: listen.into(),
+            seed_log: None,
+            scrollback: DEFAULT_SCROLLBACK,
+        }
+    }
+
+    /// Pre-fill the scrollback with the tail of a console log, so output produced before the
+    /// broker started is shown too.
+    pub fn seed_from(mut self, log: impl Into<PathBuf>) -> Self {
+        self.seed_log = Some(log.into());
+        self
+    }
+
+    /// Set the scrollback size in bytes.
+    pub fn scrollback(mut self, bytes: usize) -> Self {
+        self.scrollback = bytes;
+        self
+    }
+
+    /// Run until the upstream console closes (i.e. the VM stopped).
+    ///
+    /// A broker holds `<listen>.lock` while it runs. If another broker for the same socket
+    /// holds it already, this one returns right away and leaves the socket to it.
+    pub async fn run(self) -> Result<()> {
+        let mut lock = self.listen.as_os_str().to_owned();
+        lock.push(".lock");
+        let Some(_lock) = FileLock::try_acquire(Path::new(&lock))? else {
+            info!(listen = %self.listen.display(), "console broker: already running");
+            return Ok(());
+        };
+
+        let upstream = UnixStream::connect(&self.upstream)
+            .await
+            .map_err(|source| VmError::ConsoleConnectFailed {
+                path: self.upstream.clone(),
+                source,
+            })?;
+
+        let _ = tokio::fs::remove_file(&self.listen).await;
+        let listener = UnixListener::bind(&self.listen)?;
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(&self.listen, std::fs::Permissions::from_mode(0o600))
+                .await?;
+        }
+        info!(upstream = %self.upstream.display(), listen = %self.listen.display(), "console broker: listening");
+
+        let mut seed = Vec::new();
+        if let Some(ref log) = self.seed_log {
+            seed = read_tail(log, self.scrollback).await;
+        }
+        let shared = Arc::new(Shared::new(self.scrollback, &seed));
+
+        let (mut up_read, mut up_write) = upstream.into_split();
+        let (input_tx, mut input_rx) = mpsc::channel::<Vec<u8>>(64);
+
+        let reader = async {
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = up_read.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok::<_, std::io::Error>(());
+                }
+                shared.publish(&buf[..n]);
+            }
+        };
+
+        let writer = async {
+            while let Some(bytes) = input_rx.recv().await {
+                up_write.write_all(&bytes).await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+
+        let acceptor = async {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_client(stream, shared.clone(), input_tx.clone()));
+                    }
+                    Err(e) => return Err::<(), _>(e),
+                }
+            }
+        };
+
+        let result = tokio::select! {
+            r = reader => r,
+            r = writer => r,
+            r = acceptor => r,
+        };
+
+        let _ = tokio::fs::remove_file(&self.listen).await;
+        info!(upstream = %self.upstream.display(), "console broker: upstream closed");
+        Ok(result?)
+    }
+}
+
+/// Scrollback and output fan-out, updated together so attaching clients see no gap.
+struct Shared {
+    inner: Mutex<Scrollback>,
+}
+
+struct Scrollback {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    tx: broadcast::Sender<Arc<[u8]>>,
+}
+
+impl Shared {
+    fn new(capacity: usize, seed: &[u8]) -> Self {
+        let (tx, _) = broadcast::channel(256);
+        let mut sb = Scrollback {
+            buf: VecDeque::with_capacity(capacity),
+            capacity,
+            tx,
+        };
+        sb.push(seed);
+        Self {
+            inner: Mutex::new(sb),
+        }
+    }
+
+    fn publish(&self, bytes: &[u8]) {
+        let mut sb = self.inner.lock().unwrap();
+        sb.push(bytes);
+        // No receivers is fine: nobody is attached.
+        let _ = sb.tx.send(bytes.into());
+    }
+
+    /// Snapshot the scrollback and subscribe to everything after it.
+    fn attach(&self) -> (Vec<u8>, broadcast::Receiver<Arc<[u8]>>) {
+        let sb = self.inner.lock().unwrap();
+        (sb.buf.iter().copied().collect(), sb.tx.subscribe())
+    }
+}
+
+impl Scrollback {
+    fn push(&mut self, bytes: &[u8]) {
+        let bytes = &bytes[bytes.len().saturating_sub(self.capacity)..];
+        let overflow = (self.buf.len() + bytes.len()).saturating_sub(self.capacity);
+        self.buf.drain(..overflow);
+        self.buf.extend(bytes);
+    }
+}
+
+async fn serve_client(stream: UnixStream, shared: Arc<Shared>, input: mpsc::Sender<Vec<u8>>) {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    let mut greeting = String::new();
+    let mode = match tokio::time::timeout(GREETING_TIMEOUT, read.read_line(&mut greeting)).await {
+        Ok(Ok(_)) => AttachMode::from_greeting(&greeting),
+        _ => None,
+    };
+    let Some(mode) = mode else {
+        debug!(greeting = %greeting.trim(), "console broker: rejecting client");
+        return;
+    };
+    debug!(?mode, "console broker: client attached");
+
+    let (history, mut rx) = shared.attach();
+
+    let output = async {
+        write.write_all(&history).await?;
+        loop {
+            match rx.recv().await {
+                Ok(chunk) => write.write_all(&chunk).await?,
+                // A slow client misses some output rather than stalling everyone else.
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+
+    let keyboard = async {
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = read.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            if mode == AttachMode::ReadWrite && input.send(buf[..n].to_vec()).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+
+    tokio::select! {
+        _ = output => {}
+        _ = keyboard => {}
+    }
+    debug!(?mode, "console broker: client detached");
+}
+
+/// Read up to the last `max` bytes of a file.
+async fn read_tail(path: &Path, max: usize) -> Vec<u8> {
+    let tail = async {
+        let mut file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        file.seek(std::io::SeekFrom::Start(len.saturating_sub(max as u64)))
+            .await?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).await?;
+        Ok::<_, std::io::Error>(data)
+    };
+    tail.await.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_exactly(stream: &mut UnixStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+            .await
+            .expect("timed out reading from broker")
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn scrollback_keeps_the_tail() {
+        let shared = Shared::new(8, b"0123");
+        shared.publish(b"456789ab");
+        assert_eq!(shared.attach().0, b"456789ab");
+        shared.publish(b"cd");
+        assert_eq!(shared.attach().0, b"6789abcd");
+    }
+
+    #[tokio::test]
+    async fn fans_out_and_enforces_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream_path = dir.path().join("console.sock");
+        let broker_path = dir.path().join("console-broker.sock");
+        let log = dir.path().join("console.log");
+        std::fs::write(&log, b"boot\n").unwrap();
+
+        // Stand-in for QEMU's single-client console socket.
+        let qemu = UnixListener::bind(&upstream_path).unwrap();
+        let broker = Broker::new(&upstream_path, &broker_path).seed_from(&log);
+        let broker_task = tokio::spawn(broker.run());
+        let (mut guest, _) = qemu.accept().await.unwrap();
+
+        while !broker_path.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut rw = attach(&broker_path, AttachMode::ReadWrite).await.unwrap();
+        let mut ro = attach(&broker_path, AttachMode::ReadOnly).await.unwrap();
+        assert_eq!(read_exactly(&mut rw, 5).await, b"boot\n");
+        assert_eq!(read_exactly(&mut ro, 5).await, b"boot\n");
+
+        guest.write_all(b"login: ").await.unwrap();
+        assert_eq!(read_exactly(&mut rw, 7).await, b"login: ");
+        assert_eq!(read_exactly(&mut ro, 7).await, b"login: ");
+
+        // Only the read-write client reaches the guest.
+        ro.write_all(b"ignored").await.unwrap();
+        rw.write_all(b"root\n").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(5), guest.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"root\n");
+
+        // A late client gets the scrollback.
+        let mut late = attach(&broker_path, AttachMode::ReadOnly).await.unwrap();
+        assert_eq!(read_exactly(&mut late, 12).await, b"boot\nlogin: ");
+
+        // The broker exits once QEMU goes away.
+        drop(guest);
+        tokio::time::timeout(Duration::from_secs(5), broker_task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(!broker_path.exists());
+    }
+
+    #[tokio::test]
+    async fn second_broker_leaves_the_first_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream_path = dir.path().join("console.sock");
+        let broker_path = dir.path().join("console-broker.sock");
+
+        let qemu = UnixListener::bind(&upstream_path).unwrap();
+        let first = tokio::spawn(Broker::new(&upstream_path, &broker_path).run());
+        let (mut guest, _) = qemu.accept().await.unwrap();
+        while !broker_path.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The second broker neither connects to QEMU nor takes over the socket.
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            Broker::new(&upstream_path, &broker_path).run(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), qemu.accept())
+                .await
+                .is_err()
+        );
+
+        let mut client = attach(&broker_path, AttachMode::ReadOnly).await.unwrap();
+        guest.write_all(b"still here").await.unwrap();
+        assert_eq!(read_exactly(&mut client, 10).await, b"still here");
+
+        drop(guest);
+        tokio::time::timeout(Duration::from_secs(5), first)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_the_tail_of_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("console.log");
+        std::fs::write(&log, b"0123456789").unwrap();
+        assert_eq!(read_tail(&log, 4).await, b"6789");
+        assert_eq!(read_tail(&log, 64).await, b"0123456789");
+        assert_eq!(read_tail(&dir.path().join("missing.log"), 4).await, b"");
+    }
+}
@@ -0,0 +1,23 @@
+//! Serial console access shared by all vmctl clients of a VM.
+
+pub mod broker;
+pub mod wait;
+pub mod websocket;
+
+use std::path::PathBuf;
+
+use crate::types::VmHandle;
+
+pub use broker::{AttachMode, Broker};
+pub use wait::{wait_for_console, wait_for_pattern};
+
+/// Byte that detaches an interactive console client (Ctrl+]).
+pub const DETACH_KEY: u8 = 0x1d;
+
+/// Socket of the console broker in the VM work directory.
+pub const BROKER_SOCKET: &str = "console-broker.sock";
+
+/// Path of the console broker socket for `vm`.
+pub fn broker_socket(vm: &VmHandle) -> PathBuf {
+    vm.work_dir.join(BROKER_SOCKET)
+}
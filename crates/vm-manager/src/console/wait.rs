@@ -0,0 +1,131 @@
+//! Waiting for output on a VM's serial console.
+//!
+//! QEMU records all serial output in `console.log` (truncated when the VM starts), so the log
+//! is followed instead of the console socket: that needs no console broker and sees output that
+//! was printed before the wait began.
+
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::Duration;
+
+use regex::Regex;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::error::{Result, VmError};
+use crate::types::VmHandle;
+
+/// How often the log is checked for new output.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Unmatched output kept for matching across reads. Patterns spanning more are not found.
+const WINDOW: usize = 64 * 1024;
+
+/// Wait until `pattern` matches the console output of `vm`, returning the matched text.
+pub async fn wait_for_console(vm: &VmHandle, pattern: &Regex, timeout: Duration) -> Result<String> {
+    wait_for_pattern(&vm.work_dir.join("console.log"), pattern, timeout).await
+}
+
+/// Follow the log at `path` from its beginning until `pattern` matches, returning the matched
+/// text. A missing log is waited for; a truncated log (VM restarted) is read from the start.
+pub async fn wait_for_pattern(path: &Path, pattern: &Regex, timeout: Duration) -> Result<String> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut offset = 0u64;
+    let mut window = String::new();
+
+    loop {
+        if let Ok(mut file) = tokio::fs::File::open(path).await {
+            let len = file.metadata().await?.len();
+            if len < offset {
+                offset = 0;
+                window.clear();
+            }
+            if len > offset {
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut chunk = Vec::with_capacity((len - offset) as usize);
+                file.read_to_end(&mut chunk).await?;
+                offset += chunk.len() as u64;
+                window.push_str(&String::from_utf8_lossy(&chunk));
+
+                if let Some(m) = pattern.find(&window) {
+                    return Ok(m.as_str().to_string());
+                }
+                trim_front(&mut window, WINDOW);
+            }
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            return Err(VmError::ConsoleWaitTimeout {
+                pattern: pattern.as_str().to_string(),
+                waited: timeout,
+            });
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Drop leading text so at most `max` bytes remain, respecting char boundaries.
+fn trim_front(s: &mut String, max: usize) {
+    if s.len() > max {
+        let mut cut = s.len() - max;
+        while !s.is_char_boundary(cut) {
+            cut += 1;
+        }
+        s.drain(..cut);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn matches_output_written_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("console.log");
+        std::fs::write(&log, "Booting...\r\n").unwrap();
+
+        let writer = {
+            let log = log.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                let mut f = std::fs::OpenOptions::new().append(true).open(&log).unwrap();
+                f.write_all(b"Ubuntu 24.04 LTS web ttyS0\r\n\r\nweb lo")
+                    .unwrap();
+                f.flush().unwrap();
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                f.write_all(b"gin: ").unwrap();
+            })
+        };
+
+        let re = Regex::new(r"\w+ login:").unwrap();
+        let found = wait_for_pattern(&log, &re, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(found, "web login:");
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("console.log");
+
+        let re = Regex::new("never").unwrap();
+        let err = wait_for_pattern(&log, &re, Duration::from_millis(300))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, VmError::ConsoleWaitTimeout { .. }),
+            "got: {err}"
+        );
+    }
+
+    #[test]
+    fn trim_respects_char_boundaries() {
+        let mut s = "aé".repeat(4);
+        trim_front(&mut s, 4);
+        assert!(s.len() <= 4);
+        assert!(s.ends_with('é'));
+    }
+}
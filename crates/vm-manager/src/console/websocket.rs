@@ -0,0 +1,170 @@
+//! WebSocket serial console client.
+//!
+//! Propolis serves the guest serial port at `/instance/serial`. Console bytes travel in binary
+//! messages in both directions; text messages carry control notices (e.g. migration) and are
+//! not part of the console stream.
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::debug;
+
+use super::{AttachMode, DETACH_KEY};
+use crate::error::{Result, VmError};
+
+/// Bridge `input` and `output` to the WebSocket console at `url`.
+///
+/// Returns when `input` yields [`DETACH_KEY`] or reaches end of file, or when the server closes
+/// the connection. In [`AttachMode::ReadOnly`] input is only scanned for the detach key.
+pub async fn bridge<R, W>(url: &str, mode: AttachMode, mut input: R, mut output: W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let failed = |e: tokio_tungstenite::tungstenite::Error| VmError::ConsoleWebSocketFailed {
+        url: url.to_string(),
+        detail: e.to_string(),
+    };
+
+    let (ws, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(failed)?;
+    let (mut sink, mut stream) = ws.split();
+
+    let to_ws = async {
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = input.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            let detach = buf[..n].iter().position(|&b| b == DETACH_KEY);
+            let keys = &buf[..detach.unwrap_or(n)];
+            if mode == AttachMode::ReadWrite && !keys.is_empty() {
+                sink.send(Message::binary(keys.to_vec()))
+                    .await
+                    .map_err(failed)?;
+            }
+            if detach.is_some() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+        Ok::<_, VmError>(())
+    };
+
+    let from_ws = async {
+        while let Some(msg) = stream.next().await {
+            match msg.map_err(failed)? {
+                Message::Binary(bytes) => {
+                    output.write_all(&bytes).await?;
+                    output.flush().await?;
+                }
+                Message::Text(text) => debug!(%text, "console: control message"),
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        Ok::<_, VmError>(())
+    };
+
+    tokio::select! {
+        r = to_ws => r,
+        r = from_ws => r,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// Accept one WebSocket client and echo its binary messages back.
+    async fn echo_server() -> (String, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/instance/serial", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            ws.send(Message::text(r#"{"type":"notice"}"#))
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Binary(bytes) = msg {
+                    received.extend_from_slice(&bytes);
+                    ws.send(Message::Binary(bytes)).await.unwrap();
+                }
+            }
+            received
+        });
+        (url, server)
+    }
+
+    async fn bridge_with(mode: AttachMode, typed: &'static [u8]) -> (Vec<u8>, Vec<u8>) {
+        let (url, server) = echo_server().await;
+        let (mut keyboard, input) = tokio::io::duplex(1024);
+        let (output, mut screen) = tokio::io::duplex(1024);
+
+        let client = tokio::spawn(async move { bridge(&url, mode, input, output).await });
+        keyboard.write_all(typed).await.unwrap();
+        let mut shown = Vec::new();
+        if mode == AttachMode::ReadWrite {
+            // Let the echo arrive before detaching.
+            shown.resize(typed.len(), 0);
+            tokio::time::timeout(Duration::from_secs(5), screen.read_exact(&mut shown))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        keyboard.write_all(&[DETACH_KEY]).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), client)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+
+        screen.read_to_end(&mut shown).await.unwrap();
+        (received, shown)
+    }
+
+    #[tokio::test]
+    async fn echoes_and_detaches() {
+        let (received, shown) = bridge_with(AttachMode::ReadWrite, b"uname -a\n").await;
+        assert_eq!(received, b"uname -a\n");
+        assert_eq!(shown, b"uname -a\n");
+    }
+
+    #[tokio::test]
+    async fn read_only_sends_nothing() {
+        let (received, shown) = bridge_with(AttachMode::ReadOnly, b"x").await;
+        assert!(received.is_empty());
+        assert!(shown.is_empty());
+    }
+
+    #[tokio::test]
+    async fn connection_refused_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/instance/serial", listener.local_addr().unwrap());
+        drop(listener);
+
+        let err = bridge(
+            &url,
+            AttachMode::ReadWrite,
+            tokio::io::empty(),
+            tokio::io::sink(),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, VmError::ConsoleWebSocketFailed { .. }),
+            "got: {err}"
+        );
+    }
+}
@@ -13,21 +13,54 @@ pub enum VmError {
     #[diagnostic(
         code(vm_manager::qemu::spawn_failed),
         help(
-            "ensure qemu-system-x86_64 is installed and in PATH, and that KVM is available (/dev/kvm)"
+            "ensure qemu-system-x86_64 is installed and in PATH, and that KVM is available (/dev/kvm){log_tail}"
         )
     )]
-    QemuSpawnFailed { source: std::io::Error },
+    QemuSpawnFailed {
+        source: std::io::Error,
+        /// Tail of `qemu.log`, pre-formatted for the help text (empty if unavailable).
+        log_tail: String,
+    },
 
     #[error("failed to connect to QMP socket at {}: {source}", path.display())]
     #[diagnostic(
         code(vm_manager::qemu::qmp_connect_failed),
         help(
-            "the QEMU process may have crashed before the QMP socket was ready — check the work directory for logs"
+            "the QEMU process may have crashed before the QMP socket was ready — check qemu.log in the work directory{log_tail}"
         )
     )]
     QmpConnectionFailed {
         path: PathBuf,
         source: std::io::Error,
+        /// Tail of `qemu.log`, pre-formatted for the help text (empty if unavailable).
+        log_tail: String,
+    },
+
+    #[error("failed to connect to console socket at {}: {source}", path.display())]
+    #[diagnostic(
+        code(vm_manager::console::connect_failed),
+        help("is the VM running? Check with `vmctl status`")
+    )]
+    ConsoleConnectFailed {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("console WebSocket {url} failed: {detail}")]
+    #[diagnostic(
+        code(vm_manager::console::websocket_failed),
+        help("is the VM running? Check with `vmctl status`")
+    )]
+    ConsoleWebSocketFailed { url: String, detail: String },
+
+    #[error("timed out after {}s waiting for console output matching /{pattern}/", waited.as_secs())]
+    #[diagnostic(
+        code(vm_manager::console::wait_timeout),
+        help("check what the guest printed with `vmctl log <vm> --console`, or raise --timeout")
+    )]
+    ConsoleWaitTimeout {
+        pattern: String,
+        waited: std::time::Duration,
     },
 
     #[error("QMP command failed: {message}")]
@@ -37,7 +70,7 @@ pub enum VmError {
     #[error("failed to create QCOW2 overlay from base image {}: {detail}", base.display())]
     #[diagnostic(
         code(vm_manager::image::overlay_creation_failed),
-        help("ensure qemu-img is installed and the base image exists and is readable")
+        help("ensure the base image exists and is readable; base images in formats other than qcow2, vmdk or raw also need qemu-img")
     )]
     OverlayCreationFailed { base: PathBuf, detail: String },
 
@@ -62,6 +95,22 @@ pub enum VmError {
         source: Box<dyn std::error::Error + Send + Sync>,
     },
 
+    #[error("cannot build propolis instance spec for VM {name}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::propolis::invalid_spec),
+        help("check the VM's vcpus and disk in the VMFile, or recreate it with `vmctl reload`")
+    )]
+    PropolisSpecInvalid { name: String, detail: String },
+
+    #[error("no free propolis-server port left for VM {name} in {range}")]
+    #[diagnostic(
+        code(vm_manager::propolis::addr_exhausted),
+        help(
+            "every port in the range is configured on a nebula-vm zone; remove stale zones with `vmctl destroy` or `zonecfg -z <zone> delete -F`"
+        )
+    )]
+    PropolisAddrExhausted { name: String, range: String },
+
     #[error("failed to create cloud-init seed ISO: {detail}")]
     #[diagnostic(
         code(vm_manager::cloudinit::iso_failed),
@@ -95,10 +144,53 @@ pub enum VmError {
     #[error("image format detection failed for {}: {detail}", path.display())]
     #[diagnostic(
         code(vm_manager::image::format_detection_failed),
-        help("ensure qemu-img is installed and the file is a valid disk image")
+        help("ensure the file is a valid disk image; formats other than qcow2, vmdk or raw need qemu-img")
     )]
     ImageFormatDetectionFailed { path: PathBuf, detail: String },
 
+    #[error("checksum mismatch for {url}: expected {expected}, got {actual}")]
+    #[diagnostic(
+        code(vm_manager::image::checksum_mismatch),
+        help(
+            "the download was corrupted or truncated, or the checksum is out of date; the file was deleted, so pulling again starts over"
+        )
+    )]
+    ImageChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("image cache index {} is unreadable: {detail}", path.display())]
+    #[diagnostic(
+        code(vm_manager::image::index_corrupt),
+        help(
+            "delete the index file to start with an empty cache; cached images will be downloaded again"
+        )
+    )]
+    ImageIndexCorrupt { path: PathBuf, detail: String },
+
+    #[error("no cached image named {name}")]
+    #[diagnostic(
+        code(vm_manager::image::not_found),
+        help("run `vmctl image list` to see cached images by alias and digest")
+    )]
+    ImageNotFound { name: String },
+
+    #[error("image {name} backs the disk of VM {vm} and can't be removed")]
+    #[diagnostic(
+        code(vm_manager::image::in_use),
+        help("destroy the VM first with `vmctl destroy {vm}`")
+    )]
+    ImageInUse { name: String, vm: String },
+
+    #[error("no image {alias} for {arch} in the image catalog")]
+    #[diagnostic(
+        code(vm_manager::image::unknown_alias),
+        help("run `vmctl image search` to list catalog images, or add the image to ~/.config/vmctl/images.kdl")
+    )]
+    UnknownImageAlias { alias: String, arch: String },
+
     #[error("image conversion failed: {detail}")]
     #[diagnostic(
         code(vm_manager::image::conversion_failed),
@@ -106,6 +198,27 @@ pub enum VmError {
     )]
     ImageConversionFailed { detail: String },
 
+    #[error(
+        "command `{command}` failed{}: {stderr}",
+        status.map(|c| format!(" with exit code {c}")).unwrap_or_default()
+    )]
+    #[diagnostic(
+        code(vm_manager::command::failed),
+        help("run the command by hand to investigate")
+    )]
+    CommandFailed {
+        command: String,
+        status: Option<i32>,
+        stderr: String,
+    },
+
+    #[error("ZFS operation on {dataset} failed: {detail}")]
+    #[diagnostic(
+        code(vm_manager::zfs::failed),
+        help("check the pool with `zfs list` and make sure it has enough free space")
+    )]
+    ZfsFailed { dataset: String, detail: String },
+
     #[error("VM {name} not found")]
     #[diagnostic(
         code(vm_manager::vm::not_found),
@@ -146,6 +259,27 @@ pub enum VmError {
         hint: String,
     },
 
+    #[error("failed to parse config file at {}: {detail}", path.display())]
+    #[diagnostic(
+        code(vm_manager::config::parse_failed),
+        help("fix or remove the file; see the configuration chapter of the vmctl book")
+    )]
+    ConfigParseFailed { path: PathBuf, detail: String },
+
+    #[error("swtpm failed for VM {name}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::qemu::swtpm_failed),
+        help("install swtpm (`apt install swtpm swtpm-tools`) and check swtpm.log in the VM's work directory")
+    )]
+    SwtpmFailed { name: String, detail: String },
+
+    #[error("cannot sandbox QEMU for VM {name}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::qemu::sandbox_unavailable),
+        help("privilege dropping and chroot require running vmctl as root and a QEMU build with -run-with or -runas/-chroot; unset the option in the sandbox block to run without it")
+    )]
+    SandboxUnavailable { name: String, detail: String },
+
     #[error("provisioning failed for VM '{vm}' at step {step}: {detail}")]
     #[diagnostic(
         code(vm_manager::provision::failed),
@@ -169,4 +303,41 @@ pub enum VmError {
     Io(#[from] std::io::Error),
 }
 
+impl VmError {
+    /// Attach the tail of a process log to errors that carry one.
+    ///
+    /// `tail` is rendered verbatim after the help text, so it should already be formatted
+    /// (see [`format_log_tail`]). Other variants are returned unchanged.
+    pub fn with_log_tail(mut self, tail: String) -> Self {
+        match &mut self {
+            Self::QemuSpawnFailed { log_tail, .. } | Self::QmpConnectionFailed { log_tail, .. } => {
+                *log_tail = tail;
+            }
+            _ => {}
+        }
+        self
+    }
+}
+
+/// Format the last `lines` lines of a log file for embedding in a diagnostic's help text.
+///
+/// Returns an empty string if the file is missing or empty.
+pub fn format_log_tail(path: &std::path::Path, lines: usize) -> String {
+    let content = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(_) => return String::new(),
+    };
+    let all: Vec<&str> = content.lines().filter(|l| !l.trim().is_empty()).collect();
+    if all.is_empty() {
+        return String::new();
+    }
+    let start = all.len().saturating_sub(lines);
+    let mut out = format!("\n\nlast lines of {}:", path.display());
+    for line in &all[start..] {
+        out.push_str("\n  ");
+        out.push_str(line);
+    }
+    out
+}
+
 pub type Result<T> = std::result::Result<T, VmError>;
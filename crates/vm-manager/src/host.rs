@@ -0,0 +1,523 @@
+//! Host capability probing.
+//!
+//! [`probe`] checks for the pieces of host setup vmctl depends on (KVM access, QEMU and its
+//! tools, ISO generation, bridge helper ACLs, ...) and returns one [`Finding`] per check. Every
+//! finding is a miette [`Diagnostic`] whose help text says how to fix the problem.
+
+// Most checks concern the QEMU backend and only run on Linux.
+#![cfg_attr(not(target_os = "linux"), allow(dead_code, unused_imports))]
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use miette::Diagnostic;
+
+/// Outcome of a single check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    /// Everything needed is present.
+    Ok,
+    /// Optional functionality is unavailable.
+    Warning,
+    /// Core functionality will not work.
+    Error,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Status::Ok => "ok",
+            Status::Warning => "warn",
+            Status::Error => "fail",
+        })
+    }
+}
+
+/// Result of one host check.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{check}: {summary}")]
+pub struct Finding {
+    /// Short identifier of the check, e.g. `kvm` or `qemu-img`.
+    pub check: &'static str,
+    pub status: Status,
+    /// One-line description of what was found.
+    pub summary: String,
+    /// How to fix the problem; `None` for passing checks.
+    pub help: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, summary: impl Into<String>) -> Self {
+        Self {
+            check,
+            status: Status::Ok,
+            summary: summary.into(),
+            help: None,
+        }
+    }
+
+    fn warning(check: &'static str, summary: impl Into<String>, help: impl Into<String>) -> Self {
+        Self {
+            check,
+            status: Status::Warning,
+            summary: summary.into(),
+            help: Some(help.into()),
+        }
+    }
+
+    fn error(check: &'static str, summary: impl Into<String>, help: impl Into<String>) -> Self {
+        Self {
+            check,
+            status: Status::Error,
+            summary: summary.into(),
+            help: Some(help.into()),
+        }
+    }
+}
+
+impl Diagnostic for Finding {
+    fn code<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        Some(Box::new(format!("vm_manager::host::{}", self.check)))
+    }
+
+    fn severity(&self) -> Option<miette::Severity> {
+        Some(match self.status {
+            Status::Ok => miette::Severity::Advice,
+            Status::Warning => miette::Severity::Warning,
+            Status::Error => miette::Severity::Error,
+        })
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        self.help
+            .as_ref()
+            .map(|h| Box::new(h) as Box<dyn fmt::Display + 'a>)
+    }
+}
+
+/// All findings of a probe run.
+#[derive(Debug, Clone, Default)]
+pub struct HostReport {
+    pub findings: Vec<Finding>,
+}
+
+impl HostReport {
+    /// Findings that are not [`Status::Ok`].
+    pub fn problems(&self) -> impl Iterator<Item = &Finding> {
+        self.findings.iter().filter(|f| f.status != Status::Ok)
+    }
+
+    /// The worst status across all findings.
+    pub fn status(&self) -> Status {
+        self.findings
+            .iter()
+            .map(|f| f.status)
+            .max()
+            .unwrap_or(Status::Ok)
+    }
+}
+
+/// What to probe.
+#[derive(Debug, Clone)]
+pub struct ProbeOptions {
+    pub qemu_binary: PathBuf,
+    /// Directory holding VM work directories and images; checked for free space.
+    pub data_dir: PathBuf,
+    /// Bridge whose access via the QEMU bridge helper should be verified.
+    pub bridge: Option<String>,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        Self {
+            qemu_binary: "qemu-system-x86_64".into(),
+            data_dir: dirs::data_dir()
+                .unwrap_or_else(|| PathBuf::from("/tmp"))
+                .join("vmctl"),
+            bridge: None,
+        }
+    }
+}
+
+/// Below this much free space in the data directory, VM creation is likely to fail.
+const MIN_FREE_BYTES: u64 = 2 << 30;
+/// Below this much free space, a couple of cloud images will fill the disk.
+const LOW_FREE_BYTES: u64 = 10 << 30;
+
+/// Probe the host with default options.
+pub async fn probe() -> HostReport {
+    probe_with(&ProbeOptions::default()).await
+}
+
+/// Probe the host.
+pub async fn probe_with(opts: &ProbeOptions) -> HostReport {
+    let mut findings = Vec::new();
+
+    #[cfg(target_os = "linux")]
+    {
+        findings.push(check_kvm());
+        findings.extend(check_qemu(&opts.qemu_binary).await);
+        findings.push(check_qemu_img().await);
+    }
+    findings.push(check_iso_tool());
+    #[cfg(target_os = "linux")]
+    {
+        findings.push(check_bridge_helper(opts.bridge.as_deref()));
+        findings.push(check_virtiofsd());
+        findings.push(check_swtpm());
+        findings.push(check_ip().await);
+        findings.push(check_free_space(&opts.data_dir));
+    }
+
+    HostReport { findings }
+}
+
+#[cfg(target_os = "linux")]
+fn check_kvm() -> Finding {
+    const CHECK: &str = "kvm";
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+    {
+        Ok(_) => Finding::ok(CHECK, "/dev/kvm is usable"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Finding::error(
+            CHECK,
+            "/dev/kvm does not exist",
+            "enable virtualization (VT-x/AMD-V) in the firmware and load the kvm_intel or kvm_amd module; inside a VM, enable nested virtualization",
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Finding::error(
+            CHECK,
+            "/dev/kvm is not accessible by the current user",
+            "add your user to the kvm group (`sudo usermod -aG kvm $USER`) and log in again",
+        ),
+        Err(e) => Finding::error(
+            CHECK,
+            format!("cannot open /dev/kvm: {e}"),
+            "check the permissions and ownership of /dev/kvm",
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn check_qemu(binary: &Path) -> Vec<Finding> {
+    const CHECK: &str = "qemu";
+    let version = match command_stdout(binary, &["--version"]).await {
+        Some(out) => parse_qemu_version(&out).unwrap_or_else(|| "unknown version".into()),
+        None => {
+            return vec![Finding::error(
+                CHECK,
+                format!("{} not found", binary.display()),
+                "install QEMU: `apt install qemu-system-x86` (Debian/Ubuntu) or `dnf install qemu-kvm` (Fedora)",
+            )];
+        }
+    };
+    let mut findings = vec![Finding::ok(CHECK, format!("QEMU {version}"))];
+
+    const MACHINES: &str = "qemu-machines";
+    let machines = command_stdout(binary, &["-machine", "help"])
+        .await
+        .map(|out| parse_machine_types(&out))
+        .unwrap_or_default();
+    findings.push(if machines.iter().any(|m| m == "q35") {
+        Finding::ok(
+            MACHINES,
+            format!("q35 supported ({} machine types)", machines.len()),
+        )
+    } else {
+        Finding::error(
+            MACHINES,
+            "machine type q35 is not supported",
+            format!(
+                "vmctl boots VMs on the q35 machine type; install a full system emulator build of {}",
+                binary.display()
+            ),
+        )
+    });
+
+    findings
+}
+
+#[cfg(target_os = "linux")]
+async fn check_qemu_img() -> Finding {
+    const CHECK: &str = "qemu-img";
+    match command_stdout(Path::new("qemu-img"), &["--version"]).await {
+        Some(out) => Finding::ok(
+            CHECK,
+            parse_qemu_version(&out)
+                .map(|v| format!("qemu-img {v}"))
+                .unwrap_or_else(|| "qemu-img found".into()),
+        ),
+        None => Finding::warning(
+            CHECK,
+            "qemu-img not found",
+            "install qemu-utils (Debian/Ubuntu) or qemu-img (Fedora); it is needed to convert images and to use images in formats other than qcow2, vmdk and raw",
+        ),
+    }
+}
+
+fn check_iso_tool() -> Finding {
+    const CHECK: &str = "iso";
+    if cfg!(feature = "pure-iso") {
+        return Finding::ok(CHECK, "built with the pure-iso feature");
+    }
+    match ["genisoimage", "mkisofs"]
+        .into_iter()
+        .find_map(|tool| find_in_path(tool).map(|p| (tool, p)))
+    {
+        Some((tool, path)) => Finding::ok(CHECK, format!("{tool} at {}", path.display())),
+        None => Finding::error(
+            CHECK,
+            "neither genisoimage nor mkisofs found",
+            "install genisoimage to build cloud-init seed ISOs, or rebuild vmctl with `--features vm-manager/pure-iso`",
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_bridge_helper(bridge: Option<&str>) -> Finding {
+    const CHECK: &str = "bridge-helper";
+    const TAP_ONLY: &str = "only needed for TAP networking";
+
+    let helper = [
+        "/usr/lib/qemu/qemu-bridge-helper",
+        "/usr/libexec/qemu-bridge-helper",
+        "/usr/local/libexec/qemu-bridge-helper",
+    ]
+    .into_iter()
+    .map(Path::new)
+    .find(|p| p.exists());
+    let Some(helper) = helper else {
+        return Finding::warning(
+            CHECK,
+            "qemu-bridge-helper not found",
+            format!("{TAP_ONLY}; it ships with the QEMU system packages"),
+        );
+    };
+
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let setuid = std::fs::metadata(helper)
+            .map(|m| m.permissions().mode() & 0o4000 != 0)
+            .unwrap_or(false);
+        if !setuid && !crate::backends::sandbox::is_root() {
+            return Finding::warning(
+                CHECK,
+                format!("{} is not setuid root", helper.display()),
+                format!("{TAP_ONLY}; run `sudo chmod u+s {}`", helper.display()),
+            );
+        }
+    }
+
+    let bridge = bridge.unwrap_or("br0");
+    let conf = ["/etc/qemu/bridge.conf", "/usr/local/etc/qemu/bridge.conf"]
+        .into_iter()
+        .map(Path::new)
+        .find_map(|p| std::fs::read_to_string(p).ok().map(|c| (p, c)));
+    match conf {
+        Some((path, content)) if bridge_acl_allows(&content, bridge) => {
+            Finding::ok(CHECK, format!("{} allows bridge {bridge}", path.display()))
+        }
+        Some((path, _)) => Finding::warning(
+            CHECK,
+            format!("{} does not allow bridge {bridge}", path.display()),
+            format!("{TAP_ONLY}; add `allow {bridge}` to {}", path.display()),
+        ),
+        None => Finding::warning(
+            CHECK,
+            "/etc/qemu/bridge.conf not found",
+            format!("{TAP_ONLY}; run `echo 'allow {bridge}' | sudo tee /etc/qemu/bridge.conf`"),
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_virtiofsd() -> Finding {
+    const CHECK: &str = "virtiofsd";
+    let found = find_in_path("virtiofsd").or_else(|| {
+        ["/usr/libexec/virtiofsd", "/usr/lib/qemu/virtiofsd"]
+            .into_iter()
+            .map(PathBuf::from)
+            .find(|p| p.exists())
+    });
+    match found {
+        Some(path) => Finding::ok(CHECK, format!("virtiofsd at {}", path.display())),
+        None => Finding::warning(
+            CHECK,
+            "virtiofsd not found",
+            "only needed to share host directories with guests; install the virtiofsd package",
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_swtpm() -> Finding {
+    const CHECK: &str = "swtpm";
+    match find_in_path("swtpm") {
+        Some(path) => Finding::ok(CHECK, format!("swtpm at {}", path.display())),
+        None => Finding::warning(
+            CHECK,
+            "swtpm not found",
+            "only needed for VMs with `tpm #true`; install swtpm (`apt install swtpm`)",
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn check_ip() -> Finding {
+    const CHECK: &str = "ip";
+    match command_stdout(Path::new("ip"), &["-V"]).await {
+        Some(out) => Finding::ok(CHECK, out.trim().to_string()),
+        None => Finding::warning(
+            CHECK,
+            "ip not found",
+            "guest IP discovery for TAP networking uses `ip neigh`; install iproute2",
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_free_space(data_dir: &Path) -> Finding {
+    use std::os::unix::ffi::OsStrExt;
+
+    const CHECK: &str = "disk-space";
+    // The data dir may not exist yet; measure the filesystem it will be created on.
+    let existing = data_dir
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("/"));
+    let Ok(c_path) = std::ffi::CString::new(existing.as_os_str().as_bytes()) else {
+        return Finding::warning(CHECK, "invalid data directory path", "check $XDG_DATA_HOME");
+    };
+
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is a valid NUL-terminated string and st a properly sized out-parameter.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut st) } != 0 {
+        return Finding::warning(
+            CHECK,
+            format!(
+                "cannot stat {}: {}",
+                existing.display(),
+                std::io::Error::last_os_error()
+            ),
+            "check that the data directory is accessible",
+        );
+    }
+    #[allow(clippy::unnecessary_cast)]
+    let free = st.f_bavail as u64 * st.f_frsize as u64;
+    let summary = format!("{} free in {}", format_bytes(free), data_dir.display());
+    let help = "free up space, e.g. with `vmctl image list` and removing unused images, or point XDG_DATA_HOME at a larger filesystem";
+
+    if free < MIN_FREE_BYTES {
+        Finding::error(CHECK, summary, help)
+    } else if free < LOW_FREE_BYTES {
+        Finding::warning(CHECK, summary, help)
+    } else {
+        Finding::ok(CHECK, summary)
+    }
+}
+
+/// Run a command and return its stdout if it exited successfully.
+async fn command_stdout(program: &Path, args: &[&str]) -> Option<String> {
+    let out = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .await
+        .ok()?;
+    out.status
+        .success()
+        .then(|| String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Find an executable in `$PATH`.
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|p| p.is_file())
+}
+
+/// Extract `8.2.2` from `QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)`.
+fn parse_qemu_version(output: &str) -> Option<String> {
+    let line = output.lines().next()?;
+    let rest = &line[line.find("version ")? + "version ".len()..];
+    rest.split_whitespace().next().map(String::from)
+}
+
+/// Machine type names from `qemu-system-* -machine help`.
+fn parse_machine_types(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next())
+        .map(String::from)
+        .collect()
+}
+
+/// Whether a qemu-bridge-helper ACL permits `bridge`. Later lines override earlier ones.
+fn bridge_acl_allows(conf: &str, bridge: &str) -> bool {
+    let mut allowed = false;
+    for line in conf.lines() {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("allow"), Some(b)) if b == bridge || b == "all" => allowed = true,
+            (Some("deny"), Some(b)) if b == bridge || b == "all" => allowed = false,
+            _ => {}
+        }
+    }
+    allowed
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const GIB: f64 = (1u64 << 30) as f64;
+    format!("{:.1} GiB", bytes as f64 / GIB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qemu_version_is_parsed() {
+        let out = "QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1.4)\nCopyright (c) 2003-2023 Fabrice Bellard and the QEMU Project developers\n";
+        assert_eq!(parse_qemu_version(out).as_deref(), Some("8.2.2"));
+        assert_eq!(
+            parse_qemu_version("qemu-img version 9.1.0\n").as_deref(),
+            Some("9.1.0")
+        );
+        assert_eq!(parse_qemu_version("garbage"), None);
+    }
+
+    #[test]
+    fn machine_types_are_listed() {
+        let out = "Supported machines are:\nq35                  Standard PC (Q35 + ICH9, 2009) (alias of pc-q35-8.2)\npc-q35-8.2           Standard PC (Q35 + ICH9, 2009) (default)\nnone                 empty machine\n";
+        assert_eq!(parse_machine_types(out), ["q35", "pc-q35-8.2", "none"]);
+    }
+
+    #[test]
+    fn bridge_acl() {
+        assert!(bridge_acl_allows("allow br0\n", "br0"));
+        assert!(bridge_acl_allows("# comment\nallow all\n", "virbr0"));
+        assert!(!bridge_acl_allows("allow br1\n", "br0"));
+        assert!(!bridge_acl_allows("allow all\ndeny br0\n", "br0"));
+    }
+
+    #[test]
+    fn report_status_is_worst_finding() {
+        let report = HostReport {
+            findings: vec![
+                Finding::ok("a", "fine"),
+                Finding::warning("b", "meh", "fix b"),
+            ],
+        };
+        assert_eq!(report.status(), Status::Warning);
+        assert_eq!(report.problems().count(), 1);
+        assert_eq!(
+            report.findings[1].code().unwrap().to_string(),
+            "vm_manager::host::b"
+        );
+    }
+}
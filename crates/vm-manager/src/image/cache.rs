@@ -0,0 +1,388 @@
+//! Content-addressed layout of the image cache.
+//!
+//! Every image is stored once, at `blobs/<algorithm>/<hex digest>`, named after the digest of
+//! its (decompressed) content. `index.json` records where each image came from, and aliases
+//! give images human-readable names:
+//!
+//! ```text
+//! images/
+//! ├── index.json
+//! ├── index.lock          held while index.json is updated
+//! ├── blobs/sha256/<hex>
+//! └── downloads/          downloads in progress
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::Checksum;
+use crate::error::{Result, VmError};
+use crate::lock::FileLock;
+
+/// Name of the index file in the cache directory.
+pub const INDEX_FILE: &str = "index.json";
+
+/// Lock file serializing updates of the index.
+pub const INDEX_LOCK: &str = "index.lock";
+
+/// Where an image was pulled from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageOrigin {
+    Url(String),
+    Oci(String),
+    /// Exported from the disk of the VM with this name.
+    Vm(String),
+}
+
+impl std::fmt::Display for ImageOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Url(url) => write!(f, "{url}"),
+            Self::Oci(reference) => write!(f, "oci://{reference}"),
+            Self::Vm(name) => write!(f, "vm://{name}"),
+        }
+    }
+}
+
+/// One pulled image in the index. Sources that serve the same content share its blob.
+///
+/// A source has one entry per version pulled from it: when a refresh finds a new image, the
+/// entry of the old one stays, without aliases, for as long as its blob is kept.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub source: ImageOrigin,
+    /// Digest of the cached file, which names its blob.
+    pub digest: Checksum,
+    /// `ETag` the server sent with the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// `Last-Modified` the server sent with the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    pub size_bytes: u64,
+    /// Seconds since the Unix epoch.
+    pub pulled_at: u64,
+    /// Seconds since the Unix epoch the server last confirmed the image is current; 0 if it
+    /// never has since the pull.
+    #[serde(default)]
+    pub checked_at: u64,
+    /// Seconds since the Unix epoch the image was last pulled or used by a VM.
+    pub last_used: u64,
+}
+
+/// Contents of `index.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageIndex {
+    #[serde(default)]
+    pub images: Vec<IndexEntry>,
+    /// Alias -> digest.
+    #[serde(default)]
+    pub aliases: BTreeMap<String, Checksum>,
+}
+
+impl ImageIndex {
+    /// Load the index of the cache in `cache`. A missing index is an empty one.
+    pub async fn load(cache: &Path) -> Result<Self> {
+        let path = cache.join(INDEX_FILE);
+        match tokio::fs::read_to_string(&path).await {
+            Ok(data) => serde_json::from_str(&data).map_err(|e| VmError::ImageIndexCorrupt {
+                path,
+                detail: e.to_string(),
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the index atomically (write to a temporary file, then rename). The temporary file
+    /// is unique to this writer, so a concurrent save can't rename it half-written.
+    async fn save(&self, cache: &Path) -> Result<()> {
+        let path = cache.join(INDEX_FILE);
+        let tmp_path = cache.join(format!("{INDEX_FILE}.{}.tmp", uuid::Uuid::new_v4()));
+        let data = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+        tokio::fs::write(&tmp_path, data).await?;
+        if let Err(e) = tokio::fs::rename(&tmp_path, &path).await {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Load the index, apply `f` and save it again. [`INDEX_LOCK`] is held throughout, so
+    /// concurrent updates by other tasks or vmctl processes don't drop each other's changes.
+    pub async fn update<T>(cache: &Path, f: impl FnOnce(&mut Self) -> T) -> Result<T> {
+        tokio::fs::create_dir_all(cache).await?;
+        let _lock = FileLock::acquire(&cache.join(INDEX_LOCK)).await?;
+        let mut index = Self::load(cache).await?;
+        let result = f(&mut index);
+        index.save(cache).await?;
+        Ok(result)
+    }
+
+    /// The latest image pulled from `source`.
+    pub fn by_source(&self, source: &ImageOrigin) -> Option<&IndexEntry> {
+        self.images
+            .iter()
+            .filter(|e| &e.source == source)
+            .max_by_key(|e| e.pulled_at)
+    }
+
+    fn by_source_mut(&mut self, source: &ImageOrigin) -> Option<&mut IndexEntry> {
+        self.images
+            .iter_mut()
+            .filter(|e| &e.source == source)
+            .max_by_key(|e| e.pulled_at)
+    }
+
+    /// Add `entry`, replacing the entry for the same source and content.
+    pub fn record(&mut self, entry: IndexEntry) {
+        self.images
+            .retain(|e| e.source != entry.source || e.digest != entry.digest);
+        self.images.push(entry);
+    }
+
+    /// Mark the latest image of `source` as used now.
+    pub fn touch(&mut self, source: &ImageOrigin) {
+        if let Some(entry) = self.by_source_mut(source) {
+            entry.last_used = now();
+        }
+    }
+
+    /// Record that the server confirmed the latest image of `source` is current.
+    pub fn mark_checked(&mut self, source: &ImageOrigin) {
+        if let Some(entry) = self.by_source_mut(source) {
+            entry.checked_at = now();
+        }
+    }
+
+    /// Point `alias` at `digest`, replacing what it pointed at before.
+    pub fn alias(&mut self, alias: &str, digest: &Checksum) {
+        self.aliases.insert(alias.to_string(), digest.clone());
+    }
+
+    /// Aliases pointing at `digest`.
+    pub fn aliases_of(&self, digest: &Checksum) -> Vec<String> {
+        self.aliases
+            .iter()
+            .filter(|(_, d)| *d == digest)
+            .map(|(alias, _)| alias.clone())
+            .collect()
+    }
+
+    /// Digest an alias or digest string refers to.
+    pub fn resolve(&self, name: &str) -> Option<Checksum> {
+        if let Some(digest) = self.aliases.get(name) {
+            return Some(digest.clone());
+        }
+        let digest: Checksum = name.parse().ok()?;
+        self.images
+            .iter()
+            .any(|e| e.digest == digest)
+            .then_some(digest)
+    }
+}
+
+/// When a cached image is checked for a newer version upstream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RefreshPolicy {
+    /// Use the cached image as long as it exists.
+    #[default]
+    Never,
+    /// Check on every pull.
+    Always,
+    /// Check if the image wasn't checked in the last day.
+    Daily,
+    /// Check if the image wasn't checked in the last week.
+    Weekly,
+}
+
+impl RefreshPolicy {
+    /// Whether `entry` is due for a check at `now`.
+    pub fn is_due(self, entry: &IndexEntry, now: u64) -> bool {
+        let max_age = match self {
+            Self::Never => return false,
+            Self::Always => return true,
+            Self::Daily => 24 * 3600,
+            Self::Weekly => 7 * 24 * 3600,
+        };
+        let checked = entry.checked_at.max(entry.pulled_at);
+        now.saturating_sub(checked) >= max_age
+    }
+}
+
+impl std::str::FromStr for RefreshPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "always" => Ok(Self::Always),
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            other => Err(format!(
+                "unknown refresh policy '{other}', use never, always, daily or weekly"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for RefreshPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Never => write!(f, "never"),
+            Self::Always => write!(f, "always"),
+            Self::Daily => write!(f, "daily"),
+            Self::Weekly => write!(f, "weekly"),
+        }
+    }
+}
+
+/// Path of the blob holding the image with `digest`.
+pub fn blob_path(cache: &Path, digest: &Checksum) -> PathBuf {
+    cache
+        .join("blobs")
+        .join(digest.algorithm.to_string())
+        .join(&digest.digest)
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn entry(source: &str) -> IndexEntry {
+        IndexEntry {
+            source: ImageOrigin::Url(source.into()),
+            digest: DIGEST.parse().unwrap(),
+            etag: Some("\"abc\"".into()),
+            last_modified: None,
+            size_bytes: 3,
+            pulled_at: 1,
+            checked_at: 0,
+            last_used: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn index_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            ImageIndex::load(dir.path()).await.unwrap(),
+            ImageIndex::default()
+        );
+
+        ImageIndex::update(dir.path(), |index| {
+            index.record(entry("https://example.com/a.img"));
+            index.alias("web", &DIGEST.parse().unwrap());
+        })
+        .await
+        .unwrap();
+
+        let index = ImageIndex::load(dir.path()).await.unwrap();
+        assert_eq!(index.images, vec![entry("https://example.com/a.img")]);
+        assert_eq!(index.resolve("web"), Some(DIGEST.parse().unwrap()));
+        assert_eq!(index.resolve(DIGEST), Some(DIGEST.parse().unwrap()));
+        assert_eq!(index.resolve("db"), None);
+
+        let json = std::fs::read_to_string(dir.path().join(INDEX_FILE)).unwrap();
+        assert!(
+            json.contains(r#""url": "https://example.com/a.img""#),
+            "{json}"
+        );
+        assert!(json.contains(DIGEST), "{json}");
+    }
+
+    #[tokio::test]
+    async fn concurrent_updates_keep_every_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let updates: Vec<_> = (0..16)
+            .map(|i| {
+                let cache = dir.path().to_path_buf();
+                tokio::spawn(async move {
+                    ImageIndex::update(&cache, |index| {
+                        index.record(entry(&format!("https://example.com/{i}.img")));
+                        index.alias(&format!("img{i}"), &DIGEST.parse().unwrap());
+                    })
+                    .await
+                })
+            })
+            .collect();
+        for update in updates {
+            update.await.unwrap().unwrap();
+        }
+
+        let index = ImageIndex::load(dir.path()).await.unwrap();
+        assert_eq!(index.images.len(), 16);
+        assert_eq!(index.aliases.len(), 16);
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, [INDEX_FILE, INDEX_LOCK]);
+    }
+
+    #[test]
+    fn refresh_policy_is_due_by_last_check() {
+        let day = 24 * 3600;
+        let mut entry = entry("https://example.com/a.img");
+        entry.pulled_at = 10 * day;
+        let now = 11 * day + 1;
+
+        assert!(!RefreshPolicy::Never.is_due(&entry, now));
+        assert!(RefreshPolicy::Always.is_due(&entry, now));
+        assert!(RefreshPolicy::Daily.is_due(&entry, now));
+        assert!(!RefreshPolicy::Weekly.is_due(&entry, now));
+        entry.checked_at = 11 * day;
+        assert!(!RefreshPolicy::Daily.is_due(&entry, now));
+
+        assert_eq!("daily".parse(), Ok(RefreshPolicy::Daily));
+        assert!("hourly".parse::<RefreshPolicy>().is_err());
+    }
+
+    #[test]
+    fn record_keeps_older_versions_of_a_source() {
+        let mut index = ImageIndex::default();
+        let source = ImageOrigin::Url("https://example.com/a.img".into());
+        index.record(entry("https://example.com/a.img"));
+        let mut newer = entry("https://example.com/a.img");
+        newer.digest = format!("sha256:{}", "0".repeat(64)).parse().unwrap();
+        newer.pulled_at = 2;
+        index.record(newer.clone());
+
+        assert_eq!(index.images.len(), 2);
+        assert_eq!(index.by_source(&source), Some(&newer));
+        index.mark_checked(&source);
+        assert!(index.by_source(&source).unwrap().checked_at > 0);
+        assert_eq!(index.images[0].checked_at, 0);
+    }
+
+    #[test]
+    fn record_replaces_entry_of_same_source_and_content() {
+        let mut index = ImageIndex::default();
+        index.record(entry("https://example.com/a.img"));
+        index.record(entry("https://mirror.example.com/a.img"));
+        let mut updated = entry("https://example.com/a.img");
+        updated.etag = None;
+        index.record(updated.clone());
+
+        assert_eq!(index.images.len(), 2);
+        assert_eq!(
+            index.by_source(&ImageOrigin::Url("https://example.com/a.img".into())),
+            Some(&updated)
+        );
+    }
+}
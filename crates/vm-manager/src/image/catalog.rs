@@ -0,0 +1,308 @@
+//! Catalog of well-known cloud images under short aliases such as `ubuntu:24.04`.
+//!
+//! vmctl ships a built-in catalog (`catalog.kdl` next to this file). Entries in
+//! `$XDG_CONFIG_HOME/vmctl/images.kdl` (usually `~/.config/vmctl/images.kdl`) are added to it,
+//! replacing built-in entries with the same alias and architecture:
+//!
+//! ```kdl
+//! image "ubuntu:24.04" arch="x86_64" {
+//!     description "Ubuntu 24.04 LTS (Noble Numbat)"
+//!     url "https://mirror.example.com/ubuntu-24.04-server-cloudimg-amd64.img"
+//!     checksum-url "https://mirror.example.com/SHA256SUMS"
+//!     ssh-user "ubuntu"
+//!     cloud-init {
+//!         shell "/bin/sh"
+//!         groups "wheel"
+//!     }
+//! }
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use kdl::{KdlDocument, KdlNode};
+
+use crate::cloudinit::CloudInitQuirks;
+use crate::error::{Result, VmError};
+
+/// The built-in catalog.
+const BUILTIN: &str = include_str!("catalog.kdl");
+
+/// One image in the catalog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogEntry {
+    /// Short name, `<distribution>:<version>`.
+    pub alias: String,
+    /// Architecture the image is built for, as in [`std::env::consts::ARCH`]. `None` matches
+    /// any host.
+    pub arch: Option<String>,
+    pub description: Option<String>,
+    /// Where to download the image from.
+    pub url: String,
+    /// `SHA256SUMS`-style file listing the image; without it, one is looked up next to the
+    /// image.
+    pub checksum_url: Option<String>,
+    /// User to log in as when the VMFile doesn't name one.
+    pub ssh_user: Option<String>,
+    pub cloud_init: CloudInitQuirks,
+}
+
+/// The image catalog.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Catalog {
+    pub entries: Vec<CatalogEntry>,
+}
+
+impl Catalog {
+    /// The catalog shipped with vmctl.
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN, Path::new("<built-in catalog>"))
+            .expect("built-in image catalog is valid")
+    }
+
+    /// Default location of the user catalog.
+    pub fn path() -> PathBuf {
+        dirs::config_dir()
+            .unwrap_or_else(|| PathBuf::from("/tmp"))
+            .join("vmctl")
+            .join("images.kdl")
+    }
+
+    /// The built-in catalog with the user catalog at [`Catalog::path`] applied.
+    pub fn load() -> Result<Self> {
+        Self::load_from(&Self::path())
+    }
+
+    /// The built-in catalog with the user catalog at `path` applied. A missing file leaves
+    /// the built-in catalog as is.
+    pub fn load_from(path: &Path) -> Result<Self> {
+        let mut catalog = Self::builtin();
+        match std::fs::read_to_string(path) {
+            Ok(content) => catalog.merge(Self::parse(&content, path)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(VmError::ConfigParseFailed {
+                    path: path.to_path_buf(),
+                    detail: format!("could not read file: {e}"),
+                });
+            }
+        }
+        Ok(catalog)
+    }
+
+    /// Parse catalog file content. `path` is only used for error messages.
+    pub fn parse(content: &str, path: &Path) -> Result<Self> {
+        let invalid = |detail: String| VmError::ConfigParseFailed {
+            path: path.to_path_buf(),
+            detail,
+        };
+        let doc: KdlDocument = content
+            .parse()
+            .map_err(|e: kdl::KdlError| invalid(e.to_string()))?;
+
+        let mut entries = Vec::new();
+        for node in doc.nodes() {
+            if node.name().value() != "image" {
+                return Err(invalid(format!(
+                    "unknown node '{}', expected image",
+                    node.name().value()
+                )));
+            }
+            entries.push(parse_entry(node).map_err(invalid)?);
+        }
+        Ok(Self { entries })
+    }
+
+    /// Add the entries of `other`, replacing those with the same alias and architecture.
+    pub fn merge(&mut self, other: Catalog) {
+        for entry in other.entries {
+            self.entries
+                .retain(|e| e.alias != entry.alias || e.arch != entry.arch);
+            self.entries.push(entry);
+        }
+    }
+
+    /// The entry for `alias` on `arch`, preferring one built for `arch` over one for any
+    /// architecture.
+    pub fn lookup(&self, alias: &str, arch: &str) -> Option<&CatalogEntry> {
+        let mut matching = self.entries.iter().filter(|e| e.alias == alias);
+        matching
+            .clone()
+            .find(|e| e.arch.as_deref() == Some(arch))
+            .or_else(|| matching.find(|e| e.arch.is_none()))
+    }
+
+    /// The entry for `alias` on this host.
+    pub fn resolve(&self, alias: &str) -> Result<&CatalogEntry> {
+        let arch = std::env::consts::ARCH;
+        self.lookup(alias, arch)
+            .ok_or_else(|| VmError::UnknownImageAlias {
+                alias: alias.to_string(),
+                arch: arch.to_string(),
+            })
+    }
+
+    /// Whether `image` has the form of a catalog alias, `<name>:<version>`, rather than a
+    /// path or URL.
+    pub fn is_alias(image: &str) -> bool {
+        let Some((name, version)) = image.split_once(':') else {
+            return false;
+        };
+        !name.is_empty()
+            && !version.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            && version
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    }
+}
+
+fn parse_entry(node: &KdlNode) -> std::result::Result<CatalogEntry, String> {
+    let alias = node
+        .get(0)
+        .and_then(|v| v.as_string())
+        .ok_or("image node must have an alias argument, e.g. image \"ubuntu:24.04\"")?
+        .to_string();
+    if !Catalog::is_alias(&alias) {
+        return Err(format!(
+            "invalid alias '{alias}', use <name>:<version> like ubuntu:24.04"
+        ));
+    }
+    let arch = node
+        .get("arch")
+        .and_then(|v| v.as_string())
+        .map(String::from);
+    let body = node
+        .children()
+        .ok_or_else(|| format!("image {alias} must have a body with at least a url"))?;
+    let string = |key: &str| {
+        body.get_arg(key)
+            .and_then(|v| v.as_string())
+            .map(String::from)
+    };
+
+    let url = string("url").ok_or_else(|| format!("image {alias} has no url"))?;
+    let cloud_init = match body.get("cloud-init").and_then(|n| n.children()) {
+        Some(ci) => CloudInitQuirks {
+            shell: ci
+                .get_arg("shell")
+                .and_then(|v| v.as_string())
+                .map(String::from),
+            groups: ci.get("groups").map(|groups| {
+                groups
+                    .entries()
+                    .iter()
+                    .filter(|e| e.name().is_none())
+                    .filter_map(|e| e.value().as_string())
+                    .map(String::from)
+                    .collect()
+            }),
+        },
+        None => CloudInitQuirks::default(),
+    };
+
+    Ok(CatalogEntry {
+        description: string("description"),
+        url,
+        checksum_url: string("checksum-url"),
+        ssh_user: string("ssh-user"),
+        cloud_init,
+        alias,
+        arch,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_catalog_covers_common_distributions() {
+        let catalog = Catalog::builtin();
+        for alias in [
+            "ubuntu:24.04",
+            "debian:12",
+            "fedora:40",
+            "omnios:stable",
+            "freebsd:14",
+        ] {
+            let entry = catalog.lookup(alias, "x86_64").unwrap();
+            assert!(entry.url.starts_with("https://"), "{alias}");
+            assert!(entry.ssh_user.is_some(), "{alias}");
+        }
+
+        let freebsd = catalog.lookup("freebsd:14", "aarch64").unwrap();
+        assert!(freebsd.url.contains("arm64"));
+        assert_eq!(freebsd.cloud_init.shell.as_deref(), Some("/bin/sh"));
+        let omnios = catalog.lookup("omnios:stable", "x86_64").unwrap();
+        assert_eq!(omnios.cloud_init.groups, Some(vec![]));
+        assert!(catalog.lookup("omnios:stable", "aarch64").is_none());
+    }
+
+    #[test]
+    fn user_catalog_overrides_builtin_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("images.kdl");
+        std::fs::write(
+            &path,
+            r#"
+image "ubuntu:24.04" arch="x86_64" {
+    url "https://mirror.example.com/noble.img"
+}
+image "alpine:3.20" {
+    url "https://example.com/alpine.qcow2"
+    ssh-user "alpine"
+}
+"#,
+        )
+        .unwrap();
+
+        let catalog = Catalog::load_from(&path).unwrap();
+        let ubuntu = catalog.lookup("ubuntu:24.04", "x86_64").unwrap();
+        assert_eq!(ubuntu.url, "https://mirror.example.com/noble.img");
+        assert_eq!(ubuntu.ssh_user, None);
+        // Other architectures keep the built-in entry.
+        assert!(
+            catalog
+                .lookup("ubuntu:24.04", "aarch64")
+                .unwrap()
+                .url
+                .starts_with("https://cloud-images.ubuntu.com/")
+        );
+        // An entry without arch matches any host.
+        assert_eq!(
+            catalog.lookup("alpine:3.20", "riscv64").unwrap().ssh_user,
+            Some("alpine".into())
+        );
+        assert_eq!(
+            Catalog::load_from(&dir.path().join("missing.kdl")).unwrap(),
+            Catalog::builtin()
+        );
+    }
+
+    #[test]
+    fn invalid_catalogs_are_rejected() {
+        for (content, expected) in [
+            ("image \"ubuntu:24.04\" { description \"x\" }", "has no url"),
+            ("image \"ubuntu\" { url \"https://x\" }", "invalid alias"),
+            (
+                "distro \"ubuntu:24.04\" { url \"https://x\" }",
+                "unknown node",
+            ),
+        ] {
+            let err = Catalog::parse(content, Path::new("images.kdl")).unwrap_err();
+            assert!(err.to_string().contains(expected), "got: {err}");
+        }
+    }
+
+    #[test]
+    fn recognises_aliases() {
+        assert!(Catalog::is_alias("ubuntu:24.04"));
+        assert!(Catalog::is_alias("omnios:stable"));
+        assert!(!Catalog::is_alias("/var/images/ubuntu:24.04"));
+        assert!(!Catalog::is_alias("disk.qcow2"));
+        assert!(!Catalog::is_alias("https://example.com/a.img"));
+        assert!(!Catalog::is_alias("ubuntu:"));
+    }
+}
@@ -0,0 +1,222 @@
+//! Exporting VM disks as standalone images.
+//!
+//! A VM disk is usually a QCOW2 overlay of a cached image. Exporting it flattens the overlay
+//! and its backing chain into one image with `qemu-img convert`, stores that in the cache like
+//! a pulled image, and optionally writes a (compressed) copy elsewhere, e.g. to publish it.
+
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use async_compression::tokio::write::{BzEncoder, GzipEncoder, XzEncoder, ZstdEncoder};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tracing::info;
+
+use super::cache::{self, ImageIndex, ImageOrigin};
+use super::{CachedImage, Compression, ImageFormat, ImageManager, Validators};
+use crate::error::{Result, VmError};
+
+/// How [`ImageManager::export`] writes the image.
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    /// Alias to record the image under in the cache.
+    pub name: String,
+    /// Format of the exported image.
+    pub format: ImageFormat,
+    /// Also write the image to this file.
+    pub output: Option<PathBuf>,
+    /// Compression of the file written to `output`. The cached image is always stored
+    /// uncompressed.
+    pub compression: Compression,
+    /// The disk is open in a paused VM: read it without taking QEMU's image lock.
+    pub shared: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            format: ImageFormat::Qcow2,
+            output: None,
+            compression: Compression::None,
+            shared: false,
+        }
+    }
+}
+
+impl ImageManager {
+    /// Flatten the disk of the VM `vm` at `disk`, with its backing chain, into one image and
+    /// store it in the cache under `options.name`.
+    ///
+    /// The disk must not change while it is read: stop the VM first, or pause it and set
+    /// [`shared`](ExportOptions::shared). Exporting the same VM again with changes stores a new
+    /// image and moves the alias to it.
+    pub async fn export(
+        &self,
+        vm: &str,
+        disk: &Path,
+        options: &ExportOptions,
+    ) -> Result<CachedImage> {
+        let source = ImageOrigin::Vm(vm.to_string());
+        let flattened = self.download_path(&source);
+        if let Some(parent) = flattened.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        info!(vm, disk = %disk.display(), format = %options.format, "flattening disk");
+        if let Err(e) = flatten(disk, &flattened, options.format, options.shared).await {
+            let _ = tokio::fs::remove_file(&flattened).await;
+            return Err(e);
+        }
+        let path = self
+            .store(source, &options.name, &flattened, Validators::default())
+            .await?;
+
+        if let Some(ref output) = options.output {
+            write_output(&path, output, options.compression).await?;
+            info!(output = %output.display(), compression = %options.compression, "image written");
+        }
+
+        let index = ImageIndex::load(&self.cache).await?;
+        let entry = index
+            .by_source(&ImageOrigin::Vm(vm.to_string()))
+            .cloned()
+            .ok_or_else(|| VmError::ImageNotFound {
+                name: options.name.clone(),
+            })?;
+        info!(vm, alias = %options.name, digest = %entry.digest, "disk exported");
+        Ok(CachedImage {
+            aliases: index.aliases_of(&entry.digest),
+            path: cache::blob_path(&self.cache, &entry.digest),
+            entry,
+        })
+    }
+}
+
+/// Convert `disk` and everything it is backed by into a single image at `dst`.
+async fn flatten(disk: &Path, dst: &Path, format: ImageFormat, shared: bool) -> Result<()> {
+    let mut cmd = tokio::process::Command::new("qemu-img");
+    cmd.arg("convert");
+    if shared {
+        cmd.arg("-U");
+    }
+    let output = cmd
+        .args(["-O", format.as_str()])
+        .arg(disk)
+        .arg(dst)
+        .output()
+        .await
+        .map_err(|e| VmError::ImageConversionFailed {
+            detail: format!("qemu-img convert failed to start: {e}"),
+        })?;
+
+    if !output.status.success() {
+        return Err(VmError::ImageConversionFailed {
+            detail: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Copy `src` to `dst`, compressed with `compression`. The copy is written next to `dst` and
+/// renamed into place, so `dst` is never left half-written.
+async fn write_output(src: &Path, dst: &Path, compression: Compression) -> Result<()> {
+    let mut tmp = dst.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let file = BufWriter::new(tokio::fs::File::create(&tmp).await?);
+    let mut out: Pin<Box<dyn AsyncWrite + Send>> = match compression {
+        Compression::None => Box::pin(file),
+        Compression::Zstd => Box::pin(ZstdEncoder::new(file)),
+        Compression::Xz => Box::pin(XzEncoder::new(file)),
+        Compression::Gzip => Box::pin(GzipEncoder::new(file)),
+        Compression::Bzip2 => Box::pin(BzEncoder::new(file)),
+    };
+    let mut input = tokio::fs::File::open(src).await?;
+    let copied = async {
+        tokio::io::copy(&mut input, &mut out).await?;
+        out.shutdown().await
+    }
+    .await;
+    if let Err(e) = copied {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e.into());
+    }
+    tokio::fs::rename(&tmp, dst).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_compression::tokio::bufread::ZstdDecoder;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn writes_compressed_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("disk.img");
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&src, &data).await.unwrap();
+
+        let plain = dir.path().join("plain.img");
+        write_output(&src, &plain, Compression::None).await.unwrap();
+        assert_eq!(tokio::fs::read(&plain).await.unwrap(), data);
+
+        let packed = dir.path().join("disk.img.zst");
+        write_output(&src, &packed, Compression::Zstd)
+            .await
+            .unwrap();
+        let compressed = tokio::fs::read(&packed).await.unwrap();
+        assert_eq!(Compression::detect(&compressed), Compression::Zstd);
+        assert!(compressed.len() < data.len());
+        let mut unpacked = Vec::new();
+        ZstdDecoder::new(&compressed[..])
+            .read_to_end(&mut unpacked)
+            .await
+            .unwrap();
+        assert_eq!(unpacked, data);
+        assert!(!dir.path().join("disk.img.zst.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn exports_flattened_overlays() {
+        if std::process::Command::new("qemu-img")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.img");
+        tokio::fs::write(&base, vec![7u8; 1024 * 1024])
+            .await
+            .unwrap();
+        let overlay = dir.path().join("overlay.qcow2");
+        super::super::create_overlay(&base, &overlay, None)
+            .await
+            .unwrap();
+
+        let mgr = ImageManager::with_cache_dir(dir.path().join("cache"));
+        let options = ExportOptions {
+            name: "golden".into(),
+            format: ImageFormat::Raw,
+            output: Some(dir.path().join("golden.img.zst")),
+            compression: Compression::Zstd,
+            ..Default::default()
+        };
+        let image = mgr.export("web", &overlay, &options).await.unwrap();
+
+        // The exported image stands on its own and holds the base image's content.
+        assert_eq!(
+            tokio::fs::read(&image.path).await.unwrap(),
+            vec![7u8; 1024 * 1024]
+        );
+        assert_eq!(image.aliases, ["golden"]);
+        assert_eq!(image.entry.source, ImageOrigin::Vm("web".into()));
+        assert!(dir.path().join("golden.img.zst").exists());
+        let listed = mgr.list().await.unwrap();
+        assert_eq!(listed.len(), 1);
+    }
+}
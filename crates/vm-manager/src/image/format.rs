@@ -0,0 +1,548 @@
+//! Disk image headers, read and written natively instead of with `qemu-img`.
+//!
+//! [`inspect`] understands QCOW2 (versions 2 and 3), VMDK (sparse extents and text
+//! descriptors) and raw images; [`create_qcow2_overlay`] writes an empty QCOW2 v3 image backed
+//! by another one. Files in formats only QEMU knows, such as VDI or VHDX, are recognised and
+//! left to `qemu-img`, as are conversions.
+
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::error::{Result, VmError};
+
+/// First bytes of every QCOW2 image.
+pub const QCOW2_MAGIC: [u8; 4] = *b"QFI\xfb";
+
+/// First bytes of a VMDK sparse extent.
+const VMDK_MAGIC: [u8; 4] = *b"KDMV";
+
+/// First bytes of a VMDK text descriptor.
+const VMDK_DESCRIPTOR: &[u8] = b"# Disk DescriptorFile";
+
+/// Signatures (offset, bytes) of formats qemu-img reads but this module doesn't: QED, VHDX,
+/// dynamic VHD, VDI and LUKS.
+const FOREIGN_SIGNATURES: &[(usize, &[u8])] = &[
+    (0, b"QED\0"),
+    (0, b"vhdxfile"),
+    (0, b"conectix"),
+    (64, &[0x7f, 0x10, 0xda, 0xbe]),
+    (0, b"LUKS\xba\xbe"),
+];
+
+/// Bytes read from the start of an image to recognise its format.
+const PROBE_LEN: usize = 512;
+
+/// QCOW2 header extension naming the format of the backing file.
+const QCOW2_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+/// Longest backing file name QCOW2 allows.
+const QCOW2_MAX_BACKING_NAME: usize = 1023;
+
+/// Length of the QCOW2 v3 header written by [`create_qcow2_overlay`].
+const QCOW2_V3_HEADER_LEN: usize = 104;
+
+/// Cluster size of overlays, 64 KiB as qemu-img uses by default.
+const OVERLAY_CLUSTER_BITS: u32 = 16;
+
+/// Largest VMDK descriptor read, far more than real descriptors take.
+const MAX_VMDK_DESCRIPTOR: u64 = 64 * 1024;
+
+/// Disk image format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+    Vmdk,
+}
+
+impl ImageFormat {
+    /// Name of the format as qemu-img uses it.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Qcow2 => "qcow2",
+            Self::Vmdk => "vmdk",
+        }
+    }
+}
+
+impl std::str::FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "qcow2" => Ok(Self::Qcow2),
+            "vmdk" => Ok(Self::Vmdk),
+            other => Err(format!(
+                "unknown image format '{other}', expected qcow2, raw or vmdk"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What the header of an image says about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    /// Size of the disk the image describes, in bytes (not the size of the file).
+    pub virtual_size: u64,
+    /// Image this one is an overlay of, resolved against the directory of the image.
+    pub backing_file: Option<PathBuf>,
+    /// Format of the backing file, if the image records it.
+    pub backing_format: Option<String>,
+}
+
+/// Read the header of the image at `path`.
+///
+/// Returns `None` for images in formats only `qemu-img` understands. Files without any known
+/// signature are raw images, as they are for QEMU.
+pub async fn inspect(path: &Path) -> Result<Option<ImageInfo>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let len = file.seek(std::io::SeekFrom::End(0)).await?;
+    let head = read_at(&mut file, path, 0, len.min(PROBE_LEN as u64)).await?;
+    let malformed = |detail: String| VmError::ImageFormatDetectionFailed {
+        path: path.into(),
+        detail,
+    };
+
+    if head.starts_with(&QCOW2_MAGIC) {
+        return qcow2(&mut file, path, &head, len).await.map(Some);
+    }
+    if head.starts_with(&VMDK_MAGIC) {
+        if head.len() < 44 {
+            return Err(malformed("truncated VMDK header".into()));
+        }
+        let capacity = le_u64(&head, 12);
+        let descriptor_offset = le_u64(&head, 28).saturating_mul(512);
+        let descriptor_len = le_u64(&head, 36).saturating_mul(512);
+        let parent = if descriptor_offset == 0 || descriptor_len == 0 {
+            None
+        } else {
+            let descriptor = read_at(
+                &mut file,
+                path,
+                descriptor_offset,
+                descriptor_len.min(MAX_VMDK_DESCRIPTOR),
+            )
+            .await?;
+            vmdk_descriptor(&descriptor).1
+        };
+        return Ok(Some(ImageInfo {
+            format: ImageFormat::Vmdk,
+            virtual_size: capacity.saturating_mul(512),
+            backing_file: parent.map(|p| resolve_backing(path, p)),
+            backing_format: None,
+        }));
+    }
+    if head.starts_with(VMDK_DESCRIPTOR) {
+        let descriptor = read_at(&mut file, path, 0, len.min(MAX_VMDK_DESCRIPTOR)).await?;
+        let (sectors, parent) = vmdk_descriptor(&descriptor);
+        return Ok(Some(ImageInfo {
+            format: ImageFormat::Vmdk,
+            virtual_size: sectors.saturating_mul(512),
+            backing_file: parent.map(|p| resolve_backing(path, p)),
+            backing_format: None,
+        }));
+    }
+    if FOREIGN_SIGNATURES
+        .iter()
+        .any(|(offset, magic)| head.get(*offset..offset + magic.len()) == Some(*magic))
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(ImageInfo {
+        format: ImageFormat::Raw,
+        virtual_size: len,
+        backing_file: None,
+        backing_format: None,
+    }))
+}
+
+async fn qcow2(
+    file: &mut tokio::fs::File,
+    path: &Path,
+    head: &[u8],
+    len: u64,
+) -> Result<ImageInfo> {
+    let malformed = |detail: String| VmError::ImageFormatDetectionFailed {
+        path: path.into(),
+        detail,
+    };
+    if head.len() < 72 {
+        return Err(malformed("truncated QCOW2 header".into()));
+    }
+    let version = be_u32(head, 4);
+    if !(2..=3).contains(&version) {
+        return Err(malformed(format!("unsupported QCOW2 version {version}")));
+    }
+    let backing_offset = be_u64(head, 8);
+    let backing_len = be_u32(head, 16) as usize;
+    let cluster_bits = be_u32(head, 20);
+    if !(9..=21).contains(&cluster_bits) {
+        return Err(malformed(format!(
+            "invalid QCOW2 cluster size 2^{cluster_bits}"
+        )));
+    }
+    let header_len = if version >= 3 {
+        if head.len() < QCOW2_V3_HEADER_LEN {
+            return Err(malformed("truncated QCOW2 header".into()));
+        }
+        u64::from(be_u32(head, 100))
+    } else {
+        72
+    };
+
+    // Header extensions follow the header, up to the backing file name or the end of the
+    // first cluster.
+    let extensions_end = match backing_offset {
+        0 => 1u64 << cluster_bits,
+        offset => offset,
+    }
+    .min(len);
+    let mut backing_format = None;
+    let mut offset = header_len;
+    while offset + 8 <= extensions_end {
+        let ext = read_at(file, path, offset, 8).await?;
+        let (kind, len) = (be_u32(&ext, 0), u64::from(be_u32(&ext, 4)));
+        if kind == 0 {
+            break;
+        }
+        // Checked before reading, so a corrupt length can't make us allocate gigabytes.
+        if offset + 8 + len > extensions_end {
+            return Err(malformed(format!(
+                "QCOW2 header extension {kind:#x} of {len} bytes runs past the header"
+            )));
+        }
+        if kind == QCOW2_EXT_BACKING_FORMAT {
+            let name = read_at(file, path, offset + 8, len).await?;
+            backing_format = Some(String::from_utf8_lossy(&name).into_owned());
+        }
+        offset += 8 + len.next_multiple_of(8);
+    }
+
+    let backing_file = if backing_offset == 0 || backing_len == 0 {
+        None
+    } else if backing_len > QCOW2_MAX_BACKING_NAME {
+        return Err(malformed(format!(
+            "backing file name of {backing_len} bytes exceeds the QCOW2 limit"
+        )));
+    } else {
+        let name = read_at(file, path, backing_offset, backing_len as u64).await?;
+        Some(resolve_backing(
+            path,
+            String::from_utf8_lossy(&name).into_owned(),
+        ))
+    };
+
+    Ok(ImageInfo {
+        format: ImageFormat::Qcow2,
+        virtual_size: be_u64(head, 24),
+        backing_file,
+        backing_format,
+    })
+}
+
+/// Total size in sectors of the extents a VMDK descriptor lists, and its parent.
+fn vmdk_descriptor(descriptor: &[u8]) -> (u64, Option<String>) {
+    let text = String::from_utf8_lossy(descriptor);
+    let mut sectors = 0u64;
+    let mut parent = None;
+    for line in text.lines().map(str::trim) {
+        let mut words = line.split_whitespace();
+        if let Some("RW" | "RDONLY" | "NOACCESS") = words.next() {
+            if let Some(n) = words.next().and_then(|n| n.parse::<u64>().ok()) {
+                sectors = sectors.saturating_add(n);
+            }
+        } else if let Some(hint) = line.strip_prefix("parentFileNameHint=") {
+            parent = Some(hint.trim_matches('"').to_string());
+        }
+    }
+    (sectors, parent)
+}
+
+/// Write an empty QCOW2 v3 image at `path` with a disk of `size` bytes, reading through to
+/// `backing` (an image in `backing_format`) wherever it has no data of its own.
+///
+/// The image is laid out as qemu-img creates it: header and backing file name in the first
+/// cluster, then one refcount table cluster, one refcount block and the L1 table.
+pub async fn create_qcow2_overlay(
+    path: &Path,
+    backing: &Path,
+    backing_format: ImageFormat,
+    size: u64,
+) -> Result<()> {
+    let image =
+        qcow2_overlay(&backing.to_string_lossy(), backing_format, size).map_err(|detail| {
+            VmError::OverlayCreationFailed {
+                base: backing.into(),
+                detail,
+            }
+        })?;
+    tokio::fs::write(path, image).await?;
+    Ok(())
+}
+
+/// Contents of a new QCOW2 v3 overlay; see [`create_qcow2_overlay`].
+fn qcow2_overlay(
+    backing: &str,
+    backing_format: ImageFormat,
+    size: u64,
+) -> std::result::Result<Vec<u8>, String> {
+    if backing.len() > QCOW2_MAX_BACKING_NAME {
+        return Err(format!(
+            "backing file name of {} bytes exceeds the QCOW2 limit",
+            backing.len()
+        ));
+    }
+    let cluster = 1u64 << OVERLAY_CLUSTER_BITS;
+    let size = size.next_multiple_of(512);
+    // Each L1 entry points at an L2 table of cluster / 8 entries, each mapping one cluster.
+    let l1_entries = size.div_ceil(cluster * (cluster / 8));
+    let l1_clusters = (l1_entries * 8).div_ceil(cluster).max(1);
+    let (refcount_table, refcount_block, l1_table) = (cluster, 2 * cluster, 3 * cluster);
+    let clusters = 3 + l1_clusters;
+    let l1_entries =
+        u32::try_from(l1_entries).map_err(|_| format!("disk size {size} is too large"))?;
+
+    let mut image = vec![0u8; (clusters * cluster) as usize];
+    let format = backing_format.as_str().as_bytes();
+    let extensions = QCOW2_V3_HEADER_LEN;
+    let backing_offset = extensions + 8 + format.len().next_multiple_of(8) + 8;
+
+    let header = &mut image[..QCOW2_V3_HEADER_LEN];
+    header[0..4].copy_from_slice(&QCOW2_MAGIC);
+    header[4..8].copy_from_slice(&3u32.to_be_bytes());
+    header[8..16].copy_from_slice(&(backing_offset as u64).to_be_bytes());
+    header[16..20].copy_from_slice(&(backing.len() as u32).to_be_bytes());
+    header[20..24].copy_from_slice(&OVERLAY_CLUSTER_BITS.to_be_bytes());
+    header[24..32].copy_from_slice(&size.to_be_bytes());
+    // 32..36: no encryption
+    header[36..40].copy_from_slice(&l1_entries.to_be_bytes());
+    header[40..48].copy_from_slice(&l1_table.to_be_bytes());
+    header[48..56].copy_from_slice(&refcount_table.to_be_bytes());
+    header[56..60].copy_from_slice(&1u32.to_be_bytes());
+    // 60..72: no snapshots; 72..96: no incompatible, compatible or autoclear features
+    header[96..100].copy_from_slice(&4u32.to_be_bytes()); // 16-bit refcounts
+    header[100..104].copy_from_slice(&(QCOW2_V3_HEADER_LEN as u32).to_be_bytes());
+
+    // Backing format extension, then the end-of-extensions marker (all zeros).
+    image[extensions..extensions + 4].copy_from_slice(&QCOW2_EXT_BACKING_FORMAT.to_be_bytes());
+    image[extensions + 4..extensions + 8].copy_from_slice(&(format.len() as u32).to_be_bytes());
+    image[extensions + 8..extensions + 8 + format.len()].copy_from_slice(format);
+    image[backing_offset..backing_offset + backing.len()].copy_from_slice(backing.as_bytes());
+
+    // The refcount table's only entry points at the block counting the metadata clusters.
+    let table = refcount_table as usize;
+    image[table..table + 8].copy_from_slice(&refcount_block.to_be_bytes());
+    let block = refcount_block as usize;
+    for i in 0..clusters as usize {
+        image[block + 2 * i..block + 2 * i + 2].copy_from_slice(&1u16.to_be_bytes());
+    }
+    Ok(image)
+}
+
+/// Resolve a backing file name against the directory of the image naming it, as QEMU does.
+fn resolve_backing(image: &Path, name: String) -> PathBuf {
+    let backing = PathBuf::from(name);
+    match image.parent() {
+        Some(dir) if backing.is_relative() => dir.join(backing),
+        _ => backing,
+    }
+}
+
+/// Read `len` bytes at `offset` of the image at `path`.
+async fn read_at(
+    file: &mut tokio::fs::File,
+    path: &Path,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut buf = vec![0u8; len as usize];
+    match file.read_exact(&mut buf).await {
+        Ok(_) => Ok(buf),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Err(VmError::ImageFormatDetectionFailed {
+                path: path.into(),
+                detail: format!("file ends before byte {}", offset + len),
+            })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn be_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().expect("4 bytes"))
+}
+
+fn be_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(buf[at..at + 8].try_into().expect("8 bytes"))
+}
+
+fn le_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().expect("8 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    /// Header of a VMDK sparse extent of `sectors`, with an embedded `descriptor`.
+    fn vmdk_sparse(sectors: u64, descriptor: &str) -> Vec<u8> {
+        let mut image = vec![0u8; 1024];
+        image[..4].copy_from_slice(&VMDK_MAGIC);
+        image[4..8].copy_from_slice(&1u32.to_le_bytes());
+        image[12..20].copy_from_slice(&sectors.to_le_bytes());
+        image[28..36].copy_from_slice(&1u64.to_le_bytes());
+        image[36..44].copy_from_slice(&1u64.to_le_bytes());
+        image[512..512 + descriptor.len()].copy_from_slice(descriptor.as_bytes());
+        image
+    }
+
+    #[tokio::test]
+    async fn writes_overlays_that_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.raw");
+        std::fs::write(&base, vec![0u8; 3 * MIB as usize]).unwrap();
+        let overlay = dir.path().join("overlay.qcow2");
+
+        create_qcow2_overlay(&overlay, &base, ImageFormat::Raw, 3 * MIB + 1)
+            .await
+            .unwrap();
+
+        let info = inspect(&overlay).await.unwrap().unwrap();
+        assert_eq!(
+            info,
+            ImageInfo {
+                format: ImageFormat::Qcow2,
+                virtual_size: 3 * MIB + 512,
+                backing_file: Some(base.clone()),
+                backing_format: Some("raw".into()),
+            }
+        );
+
+        // Header, refcount table, refcount block and one L1 cluster, each counted once.
+        let image = std::fs::read(&overlay).unwrap();
+        let cluster = 1usize << OVERLAY_CLUSTER_BITS;
+        assert_eq!(image.len(), 4 * cluster);
+        assert_eq!(be_u64(&image, cluster), 2 * cluster as u64);
+        let refcounts: Vec<_> = (0..5)
+            .map(|i| {
+                u16::from_be_bytes([image[2 * cluster + 2 * i], image[2 * cluster + 2 * i + 1]])
+            })
+            .collect();
+        assert_eq!(refcounts, [1, 1, 1, 1, 0]);
+
+        if let Ok(out) = std::process::Command::new("qemu-img")
+            .args(["check", "-f", "qcow2"])
+            .arg(&overlay)
+            .output()
+        {
+            assert!(
+                out.status.success(),
+                "{}",
+                String::from_utf8_lossy(&out.stdout)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_header_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = qcow2_overlay("/base.img", ImageFormat::Raw, MIB).unwrap();
+        // The backing format extension directly follows the 104-byte v3 header.
+        assert_eq!(be_u32(&image, 104), QCOW2_EXT_BACKING_FORMAT);
+
+        for len in [u32::MAX, 1 << OVERLAY_CLUSTER_BITS] {
+            let mut corrupt = image.clone();
+            corrupt[108..112].copy_from_slice(&len.to_be_bytes());
+            let path = dir.path().join("corrupt.qcow2");
+            std::fs::write(&path, &corrupt).unwrap();
+            let err = inspect(&path).await.unwrap_err();
+            assert!(
+                err.to_string().contains("runs past the header"),
+                "got: {err}"
+            );
+        }
+
+        // A file cut off inside the extension area.
+        let path = dir.path().join("truncated.qcow2");
+        std::fs::write(&path, &image[..110]).unwrap();
+        assert!(inspect(&path).await.is_err());
+    }
+
+    #[test]
+    fn sizes_the_l1_table_for_the_disk() {
+        let gib = 1024 * MIB;
+        let image = qcow2_overlay("/base.img", ImageFormat::Qcow2, 20 * gib).unwrap();
+        // One L1 entry per 512 MiB.
+        assert_eq!(be_u32(&image, 36), 40);
+        assert_eq!(be_u64(&image, 24), 20 * gib);
+
+        let image = qcow2_overlay("/base.img", ImageFormat::Qcow2, 4096 * gib).unwrap();
+        assert_eq!(be_u32(&image, 36), 8192);
+        assert_eq!(image.len(), 4 << OVERLAY_CLUSTER_BITS);
+
+        assert!(qcow2_overlay(&"a".repeat(1024), ImageFormat::Raw, gib).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_vmdk_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let sparse = dir.path().join("delta.vmdk");
+        std::fs::write(
+            &sparse,
+            vmdk_sparse(
+                2048,
+                "# Disk DescriptorFile\nparentFileNameHint=\"base.vmdk\"\nRW 2048 SPARSE \"delta.vmdk\"\n",
+            ),
+        )
+        .unwrap();
+        let info = inspect(&sparse).await.unwrap().unwrap();
+        assert_eq!(info.format, ImageFormat::Vmdk);
+        assert_eq!(info.virtual_size, MIB);
+        assert_eq!(info.backing_file, Some(dir.path().join("base.vmdk")));
+
+        let descriptor = dir.path().join("split.vmdk");
+        std::fs::write(
+            &descriptor,
+            "# Disk DescriptorFile\nversion=1\nRW 4096 SPARSE \"split-s001.vmdk\"\nRW 2048 SPARSE \"split-s002.vmdk\"\n",
+        )
+        .unwrap();
+        let info = inspect(&descriptor).await.unwrap().unwrap();
+        assert_eq!(info.format, ImageFormat::Vmdk);
+        assert_eq!(info.virtual_size, 3 * MIB);
+        assert_eq!(info.backing_file, None);
+    }
+
+    #[tokio::test]
+    async fn leaves_other_formats_to_qemu_img() {
+        let dir = tempfile::tempdir().unwrap();
+        let vhdx = dir.path().join("disk.vhdx");
+        std::fs::write(&vhdx, b"vhdxfile\0\0\0\0").unwrap();
+        assert_eq!(inspect(&vhdx).await.unwrap(), None);
+
+        let raw = dir.path().join("disk.img");
+        std::fs::write(&raw, [0u8; 1536]).unwrap();
+        let info = inspect(&raw).await.unwrap().unwrap();
+        assert_eq!((info.format, info.virtual_size), (ImageFormat::Raw, 1536));
+
+        let truncated = dir.path().join("truncated.qcow2");
+        std::fs::write(&truncated, QCOW2_MAGIC).unwrap();
+        assert!(matches!(
+            inspect(&truncated).await.unwrap_err(),
+            VmError::ImageFormatDetectionFailed { .. }
+        ));
+    }
+}
@@ -0,0 +1,2011 @@
+use std::cmp::min;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::Duration;
+
+use async_compression::tokio::write::{BzDecoder, GzipDecoder, XzDecoder, ZstdDecoder};
+use futures_util::StreamExt;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256, Sha512};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tracing::{debug, info, warn};
+
+use crate::error::{Result, VmError};
+use crate::lock::FileLock;
+
+pub mod cache;
+pub mod catalog;
+mod export;
+pub mod format;
+mod prune;
+
+pub use cache::{ImageIndex, ImageOrigin, IndexEntry, RefreshPolicy};
+pub use catalog::{Catalog, CatalogEntry};
+pub use export::ExportOptions;
+pub use format::{ImageFormat, ImageInfo};
+pub use prune::{PruneOptions, RemovedImage};
+
+/// How often a download is attempted before giving up.
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+
+/// Delay before retrying a failed download; doubled for every further attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound for the delay between download attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Returns the default image cache directory: `{XDG_DATA_HOME}/vmctl/images/`.
+pub fn cache_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join("vmctl")
+        .join("images")
+}
+
+/// Hash algorithm of an image [`Checksum`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl ChecksumAlgorithm {
+    /// Name of the checksum file distributions publish next to their images.
+    fn sums_file(self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA256SUMS",
+            Self::Sha512 => "SHA512SUMS",
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+            Self::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+}
+
+impl std::fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sha256 => write!(f, "sha256"),
+            Self::Sha512 => write!(f, "sha512"),
+        }
+    }
+}
+
+/// Expected digest of a downloaded image, written as `sha256:<hex>` or `sha512:<hex>`.
+///
+/// It covers the file as served, i.e. before decompression.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    /// Lowercase hex digest.
+    pub digest: String,
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (algorithm, digest) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <algorithm>:<hex digest>, got '{s}'"))?;
+        let (algorithm, len) = match algorithm.to_ascii_lowercase().as_str() {
+            "sha256" => (ChecksumAlgorithm::Sha256, 64),
+            "sha512" => (ChecksumAlgorithm::Sha512, 128),
+            other => return Err(format!("unsupported checksum algorithm '{other}'")),
+        };
+        if digest.len() != len || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("{algorithm} digest must be {len} hex characters"));
+        }
+        Ok(Self {
+            algorithm,
+            digest: digest.to_ascii_lowercase(),
+        })
+    }
+}
+
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.digest)
+    }
+}
+
+impl Serialize for Checksum {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Checksum {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Hex SHA-256 of a file's content.
+pub async fn content_hash(path: &Path) -> Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Digest computed over a download as it streams in.
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(h) => h.update(data),
+            Self::Sha512(h) => h.update(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Self::Sha256(h) => format!("{:x}", h.finalize()),
+            Self::Sha512(h) => format!("{:x}", h.finalize()),
+        }
+    }
+}
+
+/// Compare the digest of a finished download with `expected`, deleting `file` on mismatch.
+fn verify(
+    url: &str,
+    file: &Path,
+    hasher: Option<Hasher>,
+    expected: Option<&Checksum>,
+) -> Result<()> {
+    let (Some(hasher), Some(expected)) = (hasher, expected) else {
+        return Ok(());
+    };
+    let actual = hasher.finish();
+    if actual != expected.digest {
+        let _ = std::fs::remove_file(file);
+        return Err(VmError::ImageChecksumMismatch {
+            url: url.into(),
+            expected: expected.to_string(),
+            actual: format!("{}:{actual}", expected.algorithm),
+        });
+    }
+    info!(url = %url, checksum = %expected, "checksum verified");
+    Ok(())
+}
+
+/// Digest listed for `file_name` in a `SHA256SUMS`-style file.
+///
+/// Accepts the GNU format (`<hex>  <name>`, `*` marking binary mode) and the BSD format
+/// (`SHA256 (<name>) = <hex>`).
+fn find_in_sums(sums: &str, algorithm: ChecksumAlgorithm, file_name: &str) -> Option<Checksum> {
+    sums.lines().find_map(|line| {
+        let line = line.trim();
+        let (digest, name) = if let Some((_, rest)) = line.split_once(" (") {
+            let (name, digest) = rest.split_once(") = ")?;
+            (digest, name)
+        } else {
+            let (digest, name) = line.split_once(char::is_whitespace)?;
+            (digest, name.trim_start().trim_start_matches('*'))
+        };
+        if name != file_name {
+            return None;
+        }
+        format!("{algorithm}:{}", digest.trim()).parse().ok()
+    })
+}
+
+/// Streaming image downloader with progress logging and decompression of zstd, xz, gzip and
+/// bzip2 images.
+pub struct ImageManager {
+    client: reqwest::Client,
+    cache: PathBuf,
+    attempts: u32,
+    retry_delay: Duration,
+    refresh: RefreshPolicy,
+    checksum_url: Option<String>,
+}
+
+impl Default for ImageManager {
+    fn default() -> Self {
+        Self::with_cache_dir(cache_dir())
+    }
+}
+
+impl ImageManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_cache_dir(cache: PathBuf) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            cache,
+            attempts: DOWNLOAD_ATTEMPTS,
+            retry_delay: RETRY_DELAY,
+            refresh: RefreshPolicy::Never,
+            checksum_url: None,
+        }
+    }
+
+    /// Try downloads up to `attempts` times, waiting `delay` before the first retry and twice
+    /// as long before each further one.
+    pub fn with_retries(mut self, attempts: u32, delay: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Check cached images for a newer version upstream as `refresh` says, when pulling them.
+    pub fn with_refresh(mut self, refresh: RefreshPolicy) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// Look up checksums of downloads without an explicit one in the `SHA256SUMS`- or
+    /// `SHA512SUMS`-style file at `url`, instead of next to the image.
+    pub fn with_checksum_url(mut self, url: impl Into<String>) -> Self {
+        self.checksum_url = Some(url.into());
+        self
+    }
+
+    /// Download an image from `url` to `destination`.
+    ///
+    /// If the file already exists at `destination`, the download is skipped.
+    /// zstd, xz, gzip and bzip2 images, recognised by their magic bytes, are decompressed as
+    /// they stream in.
+    ///
+    /// The download goes to `<destination>.part` (`<destination>.unpack.part` while
+    /// decompressing) and is renamed into place once complete, so `destination` never holds a
+    /// partial image. An interrupted uncompressed download resumes from the `.part` file with
+    /// an HTTP range request. Downloads to the same destination take turns, holding
+    /// `<destination>.lock` while they run.
+    pub async fn download(&self, url: &str, destination: &Path) -> Result<()> {
+        self.download_verified(url, destination, None).await
+    }
+
+    /// Download like [`download`](Self::download), checking the file against `checksum`.
+    ///
+    /// Without a checksum, one is looked up in a `SHA256SUMS` or `SHA512SUMS` file next to
+    /// the image, as distributions publish them. A download that doesn't match is deleted.
+    pub async fn download_verified(
+        &self,
+        url: &str,
+        destination: &Path,
+        checksum: Option<&Checksum>,
+    ) -> Result<()> {
+        if destination.exists() {
+            info!(url = %url, dest = %destination.display(), "image already present; skipping download");
+            return Ok(());
+        }
+        let lock = lock_download(destination).await?;
+        let fetched = if destination.exists() {
+            info!(url = %url, dest = %destination.display(), "image downloaded meanwhile; skipping download");
+            Ok(None)
+        } else {
+            self.fetch_to(url, destination, checksum, None).await
+        };
+        let _ = lock.remove();
+        fetched.map(|_| ())
+    }
+
+    /// Download `url` to `destination` as in [`download_verified`](Self::download_verified),
+    /// returning the validators the server sent.
+    ///
+    /// With `conditional`, the server is asked to send the image only if it changed since it
+    /// sent those validators; `None` means it didn't.
+    async fn fetch_to(
+        &self,
+        url: &str,
+        destination: &Path,
+        checksum: Option<&Checksum>,
+        conditional: Option<&Validators>,
+    ) -> Result<Option<Validators>> {
+        if let Some(parent) = destination.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let discovered = match checksum {
+            Some(_) => None,
+            None => self.discover_checksum(url).await,
+        };
+        let expected = checksum.or(discovered.as_ref());
+
+        let Some((output, validators)) =
+            self.fetch(url, destination, expected, conditional).await?
+        else {
+            return Ok(None);
+        };
+        tokio::fs::rename(&output, destination).await?;
+        info!(dest = %destination.display(), "image downloaded");
+        Ok(Some(validators))
+    }
+
+    /// Look up the checksum of `url` in the [checksum URL](Self::with_checksum_url), or a
+    /// `SHA256SUMS` or `SHA512SUMS` file in the same directory. Missing or unreadable sums
+    /// files are not an error.
+    async fn discover_checksum(&self, url: &str) -> Option<Checksum> {
+        let (base, file_name) = url.rsplit_once('/')?;
+        let candidates = match &self.checksum_url {
+            // The algorithm of a named file isn't known up front; the digest length tells.
+            Some(sums_url) => vec![(sums_url.clone(), None)],
+            None => [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Sha512]
+                .map(|algorithm| (format!("{base}/{}", algorithm.sums_file()), Some(algorithm)))
+                .into(),
+        };
+        for (sums_url, algorithm) in candidates {
+            let sums = match self.client.get(&sums_url).send().await {
+                Ok(res) if res.status().is_success() => match res.text().await {
+                    Ok(text) => text,
+                    Err(_) => continue,
+                },
+                _ => {
+                    debug!(url = %sums_url, "no checksum file");
+                    continue;
+                }
+            };
+            let found = match algorithm {
+                Some(algorithm) => find_in_sums(&sums, algorithm, file_name),
+                None => find_in_sums(&sums, ChecksumAlgorithm::Sha256, file_name)
+                    .or_else(|| find_in_sums(&sums, ChecksumAlgorithm::Sha512, file_name)),
+            };
+            if let Some(checksum) = found {
+                info!(url = %sums_url, checksum = %checksum, "found published checksum");
+                return Some(checksum);
+            }
+        }
+        info!(url = %url, "no published checksum found; download will not be verified");
+        None
+    }
+
+    /// Pull a QCOW2 image from an OCI registry into the cache, returning the cached path.
+    ///
+    /// The image is recorded in the index under `name`, or the reference with `/` and `:`
+    /// replaced by `_`. A reference that was pulled before is not pulled again.
+    pub async fn pull_oci(&self, reference: &str, name: Option<&str>) -> Result<PathBuf> {
+        let alias = name
+            .map(|n| n.to_string())
+            .unwrap_or_else(|| reference.replace(['/', ':'], "_"));
+        let source = ImageOrigin::Oci(reference.to_string());
+        let download = self.download_path(&source);
+        let lock = lock_download(&download).await?;
+        let pulled = self
+            .pull_oci_locked(reference, &alias, source, &download)
+            .await;
+        let _ = lock.remove();
+        pulled
+    }
+
+    /// Pull `reference` as in [`pull_oci`](Self::pull_oci), holding the lock of `download`.
+    async fn pull_oci_locked(
+        &self,
+        reference: &str,
+        alias: &str,
+        source: ImageOrigin,
+        download: &Path,
+    ) -> Result<PathBuf> {
+        if self.cached(&source).await?.is_some() {
+            let path = self.use_cached(&source, alias, false).await?;
+            info!(reference, path = %path.display(), "OCI image already cached; skipping pull");
+            return Ok(path);
+        }
+
+        let data = crate::oci::pull_qcow2(reference).await?;
+        let part = part_path(download);
+        tokio::fs::write(&part, &data).await?;
+        tokio::fs::rename(&part, download).await?;
+        let path = self
+            .store(source, alias, download, Validators::default())
+            .await?;
+        info!(reference, path = %path.display(), "OCI artifact cached");
+        Ok(path)
+    }
+
+    /// Pull an image from a URL into the cache, returning the cached path.
+    pub async fn pull(&self, url: &str, name: Option<&str>) -> Result<PathBuf> {
+        self.pull_verified(url, name, None).await
+    }
+
+    /// Pull like [`pull`](Self::pull), verifying the download as in
+    /// [`download_verified`](Self::download_verified).
+    ///
+    /// The image is stored under its content digest and recorded in the index under `name`,
+    /// or the file name from the URL. A URL that was pulled before is not downloaded again,
+    /// whatever name it is pulled under; pulling a different URL under an existing name
+    /// points the name at the new image.
+    ///
+    /// When the [refresh policy](Self::with_refresh) says a cached image is due, the server is
+    /// asked with `If-None-Match`/`If-Modified-Since` whether it changed. Only a changed image
+    /// is downloaded; it gets a new blob, and `name` moves to it, while the old blob stays for
+    /// the overlays based on it.
+    ///
+    /// Pulls of the same source take turns: a second pull waits for the first one and then
+    /// uses the image it cached.
+    pub async fn pull_verified(
+        &self,
+        url: &str,
+        name: Option<&str>,
+        checksum: Option<&Checksum>,
+    ) -> Result<PathBuf> {
+        let alias = name.map(|n| n.to_string()).unwrap_or_else(|| {
+            let file_name = url.rsplit('/').next().unwrap_or("image");
+            COMPRESSED_SUFFIXES
+                .iter()
+                .find_map(|suffix| file_name.strip_suffix(suffix))
+                .unwrap_or(file_name)
+                .to_string()
+        });
+        let source = ImageOrigin::Url(url.to_string());
+        let download = self.download_path(&source);
+        let lock = lock_download(&download).await?;
+        let pulled = self
+            .pull_locked(url, &alias, checksum, source, &download)
+            .await;
+        let _ = lock.remove();
+        pulled
+    }
+
+    /// Pull `url` as in [`pull_verified`](Self::pull_verified), holding the lock of `download`.
+    async fn pull_locked(
+        &self,
+        url: &str,
+        alias: &str,
+        checksum: Option<&Checksum>,
+        source: ImageOrigin,
+        download: &Path,
+    ) -> Result<PathBuf> {
+        let conditional = match self.cached(&source).await? {
+            Some(entry) if self.refresh.is_due(&entry, cache::now()) => {
+                info!(url = %url, refresh = %self.refresh, "checking for a newer image");
+                Some(Validators {
+                    etag: entry.etag,
+                    last_modified: entry.last_modified,
+                })
+            }
+            Some(_) => {
+                let path = self.use_cached(&source, alias, false).await?;
+                info!(url = %url, path = %path.display(), "image already cached; skipping download");
+                return Ok(path);
+            }
+            None => None,
+        };
+
+        let Some(validators) = self
+            .fetch_to(url, download, checksum, conditional.as_ref())
+            .await?
+        else {
+            let path = self.use_cached(&source, alias, true).await?;
+            info!(url = %url, path = %path.display(), "cached image is up to date");
+            return Ok(path);
+        };
+        let path = self.store(source, alias, download, validators).await?;
+        info!(url = %url, alias = %alias, path = %path.display(), "image cached");
+        Ok(path)
+    }
+
+    /// Index entry of the latest cached image of `source`, if its blob is still there.
+    async fn cached(&self, source: &ImageOrigin) -> Result<Option<IndexEntry>> {
+        let index = ImageIndex::load(&self.cache).await?;
+        Ok(index
+            .by_source(source)
+            .filter(|entry| cache::blob_path(&self.cache, &entry.digest).exists())
+            .cloned())
+    }
+
+    /// Point `alias` at the cached image of `source` and mark it used, and `checked` against
+    /// the server if it was. Returns its blob.
+    async fn use_cached(
+        &self,
+        source: &ImageOrigin,
+        alias: &str,
+        checked: bool,
+    ) -> Result<PathBuf> {
+        let digest = ImageIndex::update(&self.cache, |index| {
+            index.touch(source);
+            if checked {
+                index.mark_checked(source);
+            }
+            let digest = index.by_source(source).map(|entry| entry.digest.clone());
+            if let Some(ref digest) = digest {
+                index.alias(alias, digest);
+            }
+            digest
+        })
+        .await?
+        .ok_or_else(|| VmError::ImageNotFound {
+            name: source.to_string(),
+        })?;
+        Ok(cache::blob_path(&self.cache, &digest))
+    }
+
+    /// Where the image of `source` is downloaded to before it is stored: named after the
+    /// source, so that an interrupted download is resumed by the next pull of the same source.
+    fn download_path(&self, source: &ImageOrigin) -> PathBuf {
+        let hash = format!("{:x}", Sha256::digest(source.to_string()));
+        self.cache.join("downloads").join(&hash[..16])
+    }
+
+    /// Move the finished download of `source` into its blob and record it in the index.
+    async fn store(
+        &self,
+        source: ImageOrigin,
+        alias: &str,
+        download: &Path,
+        validators: Validators,
+    ) -> Result<PathBuf> {
+        let digest = Checksum {
+            algorithm: ChecksumAlgorithm::Sha256,
+            digest: content_hash(download).await?,
+        };
+        let size_bytes = tokio::fs::metadata(download).await?.len();
+        let path = cache::blob_path(&self.cache, &digest);
+        if path.exists() {
+            // Another source already delivered the same image.
+            tokio::fs::remove_file(download).await?;
+        } else {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::rename(download, &path).await?;
+        }
+
+        let now = cache::now();
+        ImageIndex::update(&self.cache, |index| {
+            index.alias(alias, &digest);
+            index.record(IndexEntry {
+                source,
+                digest,
+                etag: validators.etag,
+                last_modified: validators.last_modified,
+                size_bytes,
+                pulled_at: now,
+                checked_at: now,
+                last_used: now,
+            });
+        })
+        .await?;
+        Ok(path)
+    }
+
+    /// List all cached images, most recently used first.
+    pub async fn list(&self) -> Result<Vec<CachedImage>> {
+        let index = ImageIndex::load(&self.cache).await?;
+        let mut images: Vec<_> = index
+            .images
+            .iter()
+            .map(|entry| CachedImage {
+                aliases: index.aliases_of(&entry.digest),
+                path: cache::blob_path(&self.cache, &entry.digest),
+                entry: entry.clone(),
+            })
+            .filter(|image| image.path.exists())
+            .collect();
+        images.sort_by_key(|image| std::cmp::Reverse(image.entry.last_used));
+        Ok(images)
+    }
+
+    /// Download `url` for `destination`, resuming what an earlier attempt left there.
+    /// Transient failures are retried with exponential backoff, each retry picking up where the
+    /// previous one stopped. Returns the complete, verified file, ready to be renamed into place,
+    /// or `None` if the server answered a `conditional` request with 304 Not Modified.
+    async fn fetch(
+        &self,
+        url: &str,
+        destination: &Path,
+        expected: Option<&Checksum>,
+        conditional: Option<&Validators>,
+    ) -> Result<Option<(PathBuf, Validators)>> {
+        let mut download = PartialDownload::open(destination, expected).await?;
+        if download.received > 0 {
+            info!(url = %url, part = %download.part.display(), offset = download.received, "resuming download");
+        }
+
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        loop {
+            match self.fetch_once(url, &mut download, conditional).await {
+                Ok(true) => break,
+                Ok(false) => {
+                    info!(url = %url, "image not modified");
+                    return Ok(None);
+                }
+                Err(Attempt::Fatal(e)) => {
+                    download.abandon().await;
+                    return Err(e);
+                }
+                Err(Attempt::Retry(detail)) if attempt < self.attempts => {
+                    warn!(
+                        url = %url,
+                        attempt,
+                        offset = download.received,
+                        retry_in_ms = delay.as_millis() as u64,
+                        "download interrupted ({detail}); retrying"
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = min(delay * 2, MAX_RETRY_DELAY);
+                    attempt += 1;
+                }
+                Err(Attempt::Retry(detail)) => {
+                    download.abandon().await;
+                    return Err(VmError::ImageDownloadFailed {
+                        url: url.into(),
+                        detail: format!("{detail} (gave up after {attempt} attempts)"),
+                    });
+                }
+            }
+        }
+
+        let output = match download.finish().await {
+            Ok(output) => output,
+            Err(e) => {
+                download.abandon().await;
+                return Err(VmError::ImageDownloadFailed {
+                    url: url.into(),
+                    detail: e.to_string(),
+                });
+            }
+        };
+        verify(url, &output, download.hasher, expected)?;
+        info!(url = %url, "download complete");
+        Ok(Some((output, download.validators)))
+    }
+
+    /// One request for the rest of `download`. Returns `false` if the server answered a
+    /// `conditional` request with 304 Not Modified.
+    async fn fetch_once(
+        &self,
+        url: &str,
+        download: &mut PartialDownload,
+        conditional: Option<&Validators>,
+    ) -> std::result::Result<bool, Attempt> {
+        let mut req = self.client.get(url);
+        if download.received > 0 {
+            match download.validators.if_range() {
+                // A server whose file changed since the partial download answers with all of
+                // the new file instead of the rest of the old one.
+                Some(if_range) => {
+                    req = req
+                        .header(
+                            reqwest::header::RANGE,
+                            format!("bytes={}-", download.received),
+                        )
+                        .header(reqwest::header::IF_RANGE, if_range);
+                }
+                None => {
+                    info!(url = %url, "server sent no validators; restarting download");
+                    download.restart().await.map_err(Attempt::Fatal)?;
+                }
+            }
+        }
+        if let Some(validators) = conditional {
+            if let Some(ref etag) = validators.etag {
+                req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(ref last_modified) = validators.last_modified {
+                req = req.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let res = req
+            .send()
+            .await
+            .map_err(|e| Attempt::Retry(e.to_string()))?;
+
+        let status = res.status();
+        match status {
+            reqwest::StatusCode::NOT_MODIFIED if conditional.is_some() => return Ok(false),
+            reqwest::StatusCode::PARTIAL_CONTENT if download.received > 0 => {}
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE => {
+                // The partial file doesn't fit the image (any more); start over.
+                download.restart().await.map_err(Attempt::Fatal)?;
+                return Err(Attempt::Retry(format!(
+                    "HTTP {status} for resumed download"
+                )));
+            }
+            s if s.is_success() => {
+                if download.received > 0 {
+                    info!(url = %url, "image changed or server ignored the range request; restarting download");
+                }
+                download.restart().await.map_err(Attempt::Fatal)?;
+                download
+                    .remember(Validators::of(&res))
+                    .await
+                    .map_err(|e| Attempt::Fatal(e.into()))?;
+            }
+            s if s.is_server_error()
+                || s == reqwest::StatusCode::REQUEST_TIMEOUT
+                || s == reqwest::StatusCode::TOO_MANY_REQUESTS =>
+            {
+                return Err(Attempt::Retry(format!("HTTP {s}")));
+            }
+            s => {
+                return Err(Attempt::Fatal(VmError::ImageDownloadFailed {
+                    url: url.into(),
+                    detail: format!("HTTP {s}"),
+                }));
+            }
+        }
+
+        let total_size = res.content_length().map(|len| len + download.received);
+        info!(url = %url, offset = download.received, size_bytes = ?total_size, "downloading image");
+
+        let mut stream = res.bytes_stream();
+        let mut last_logged_pct: u64 = 0;
+        while let Some(item) = stream.next().await {
+            let chunk = item.map_err(|e| Attempt::Retry(e.to_string()))?;
+            download.write(&chunk).await.map_err(|e| {
+                Attempt::Fatal(VmError::ImageDownloadFailed {
+                    url: url.into(),
+                    detail: e.to_string(),
+                })
+            })?;
+            if let Some(total) = total_size.filter(|&t| t > 0) {
+                let downloaded = min(download.received, total);
+                let pct = downloaded.saturating_mul(100) / total;
+                if pct >= last_logged_pct + 5 || pct == 100 {
+                    info!(
+                        percent = pct,
+                        downloaded_mb = (downloaded as f64) / 1_000_000.0,
+                        "downloading..."
+                    );
+                    last_logged_pct = pct;
+                }
+            }
+        }
+
+        // A connection closed early can end the stream without an error.
+        if let Some(total) = total_size {
+            if download.received < total {
+                return Err(Attempt::Retry(format!(
+                    "connection closed after {} of {total} bytes",
+                    download.received
+                )));
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// What a server sent to identify the version of an image, and sends back to ask whether it
+/// changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Validators {
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Value for an `If-Range` header: a strong `ETag`, or else `Last-Modified`. Weak tags
+    /// can't be used to resume a download.
+    fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+
+    fn of(res: &reqwest::Response) -> Self {
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+        }
+    }
+}
+
+/// Outcome of a failed download attempt.
+enum Attempt {
+    /// Worth retrying: connection problems, server errors.
+    Retry(String),
+    Fatal(VmError),
+}
+
+/// Compression of a downloaded or exported image, recognised by its magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+    Xz,
+    Gzip,
+    Bzip2,
+}
+
+impl Compression {
+    /// Bytes [`detect`](Self::detect) needs to tell the formats apart.
+    pub const MAGIC_LEN: usize = 6;
+
+    /// Detect the compression from the first bytes of a file.
+    pub fn detect(head: &[u8]) -> Self {
+        if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Self::Xz
+        } else if head.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if head.starts_with(b"BZh") {
+            Self::Bzip2
+        } else {
+            Self::None
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            "xz" => Ok(Self::Xz),
+            "gzip" => Ok(Self::Gzip),
+            "bzip2" => Ok(Self::Bzip2),
+            other => Err(format!(
+                "unknown compression '{other}', expected zstd, xz, gzip, bzip2 or none"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Zstd => write!(f, "zstd"),
+            Self::Xz => write!(f, "xz"),
+            Self::Gzip => write!(f, "gzip"),
+            Self::Bzip2 => write!(f, "bzip2"),
+        }
+    }
+}
+
+/// URL suffixes of compressed images, dropped from the cached file name.
+const COMPRESSED_SUFFIXES: &[&str] = &[".zst", ".zstd", ".xz", ".gz", ".bz2"];
+
+/// Where downloaded bytes go: the `.part` file, or a decoder writing the `.unpack.part` file.
+type Sink = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// A download in progress, with the digest of what has been received so far.
+///
+/// The validators of the response the `.part` file came from are kept in `<destination>.part.json`,
+/// so that a later download only resumes it if the image didn't change upstream in between.
+///
+/// Uncompressed images are written to `<destination>.part`, which a later download can resume.
+/// Compressed images are decompressed on the fly into `<destination>.unpack.part`; the decoder
+/// state lives only in memory, so retries within one download resume, but a new download
+/// starts over.
+struct PartialDownload {
+    part: PathBuf,
+    /// Validators of the `.part` file.
+    meta: PathBuf,
+    unpack: PathBuf,
+    /// Bytes received from the server, i.e. the offset to resume from.
+    received: u64,
+    /// First bytes, held back until the compression is known.
+    head: Vec<u8>,
+    compression: Option<Compression>,
+    sink: Option<Sink>,
+    algorithm: Option<ChecksumAlgorithm>,
+    hasher: Option<Hasher>,
+    /// Validators of the last response.
+    validators: Validators,
+}
+
+impl PartialDownload {
+    /// Start a download for `destination`, keeping what an interrupted download left in its
+    /// `.part` file.
+    async fn open(destination: &Path, expected: Option<&Checksum>) -> Result<Self> {
+        let part = part_path(destination);
+        let mut meta = part.as_os_str().to_owned();
+        meta.push(".json");
+        let meta = PathBuf::from(meta);
+        let mut unpack = destination.as_os_str().to_owned();
+        unpack.push(".unpack.part");
+        let unpack = PathBuf::from(unpack);
+        let _ = tokio::fs::remove_file(&unpack).await;
+
+        let algorithm = expected.map(|c| c.algorithm);
+        let mut download = Self {
+            part,
+            meta,
+            unpack,
+            received: 0,
+            head: Vec::new(),
+            compression: None,
+            sink: None,
+            algorithm,
+            hasher: algorithm.map(ChecksumAlgorithm::hasher),
+            validators: Validators::default(),
+        };
+
+        match tokio::fs::File::open(&download.part).await {
+            Ok(mut file) => {
+                let mut buf = vec![0u8; 1024 * 1024];
+                loop {
+                    let n = file.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    if let Some(ref mut hasher) = download.hasher {
+                        hasher.update(&buf[..n]);
+                    }
+                    download.received += n as u64;
+                }
+                let validators = tokio::fs::read(&download.meta)
+                    .await
+                    .ok()
+                    .and_then(|data| serde_json::from_slice::<Validators>(&data).ok())
+                    .filter(|v| v.if_range().is_some());
+                match validators {
+                    _ if download.received == 0 => {}
+                    Some(validators) => {
+                        download.validators = validators;
+                        download.start(Compression::None).await?;
+                    }
+                    None => {
+                        // Without validators there's no telling whether the rest of the image
+                        // on the server still belongs to these bytes.
+                        info!(part = %download.part.display(), "discarding partial download of unknown version");
+                        download.restart().await?;
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(download)
+    }
+
+    /// Open the sink for `compression`.
+    async fn start(&mut self, compression: Compression) -> std::io::Result<()> {
+        if compression != Compression::None {
+            info!(%compression, "decompressing while downloading");
+        }
+        let sink: Sink = match compression {
+            Compression::None => Box::pin(BufWriter::new(
+                tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.part)
+                    .await?,
+            )),
+            _ => {
+                let out = BufWriter::new(tokio::fs::File::create(&self.unpack).await?);
+                match compression {
+                    Compression::Zstd => Box::pin(ZstdDecoder::new(out)),
+                    Compression::Xz => Box::pin(XzDecoder::new(out)),
+                    Compression::Gzip => Box::pin(GzipDecoder::new(out)),
+                    _ => Box::pin(BzDecoder::new(out)),
+                }
+            }
+        };
+        self.compression = Some(compression);
+        self.sink = Some(sink);
+        Ok(())
+    }
+
+    /// Record the validators of the response the download is now written from.
+    async fn remember(&mut self, validators: Validators) -> std::io::Result<()> {
+        let data = serde_json::to_vec(&validators).map_err(std::io::Error::other)?;
+        tokio::fs::write(&self.meta, data).await?;
+        self.validators = validators;
+        Ok(())
+    }
+
+    /// Give up on the download: keep what arrived for the next download to resume from, or
+    /// delete it if that can't resume it, as with decompressed images.
+    async fn abandon(&mut self) {
+        match self.compression {
+            Some(compression) if compression != Compression::None => {
+                let _ = self.restart().await;
+            }
+            _ => {
+                let _ = self.flush().await;
+            }
+        }
+    }
+
+    /// Drop what has been downloaded so far.
+    async fn restart(&mut self) -> Result<()> {
+        self.sink = None;
+        self.validators = Validators::default();
+        for path in [&self.part, &self.meta, &self.unpack] {
+            match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        self.received = 0;
+        self.head.clear();
+        self.compression = None;
+        self.hasher = self.algorithm.map(ChecksumAlgorithm::hasher);
+        Ok(())
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.update(chunk);
+        }
+        self.received += chunk.len() as u64;
+
+        if let Some(ref mut sink) = self.sink {
+            return sink
+                .write_all(chunk)
+                .await
+                .map_err(|e| self.decode_error(e));
+        }
+        self.head.extend_from_slice(chunk);
+        if self.head.len() >= Compression::MAGIC_LEN {
+            self.flush_head().await?;
+        }
+        Ok(())
+    }
+
+    /// Write out buffered bytes.
+    async fn flush(&mut self) -> std::io::Result<()> {
+        match self.sink {
+            Some(ref mut sink) => sink.flush().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Detect the compression from the held-back bytes and pass them on.
+    async fn flush_head(&mut self) -> std::io::Result<()> {
+        self.start(Compression::detect(&self.head)).await?;
+        let head = std::mem::take(&mut self.head);
+        let sink = self.sink.as_mut().expect("sink was just started");
+        sink.write_all(&head)
+            .await
+            .map_err(|e| self.decode_error(e))
+    }
+
+    /// Finish writing and return the file holding the image.
+    async fn finish(&mut self) -> std::io::Result<PathBuf> {
+        if self.sink.is_none() {
+            self.flush_head().await?;
+        }
+        let sink = self.sink.as_mut().expect("sink was started");
+        sink.shutdown().await.map_err(|e| self.decode_error(e))?;
+        self.sink = None;
+        let _ = tokio::fs::remove_file(&self.meta).await;
+        Ok(match self.compression {
+            Some(Compression::None) | None => self.part.clone(),
+            Some(_) => {
+                let _ = tokio::fs::remove_file(&self.part).await;
+                self.unpack.clone()
+            }
+        })
+    }
+
+    /// Describe a write error, which for compressed images is usually corrupt input.
+    fn decode_error(&self, e: std::io::Error) -> std::io::Error {
+        match self.compression {
+            Some(compression) if compression != Compression::None => {
+                std::io::Error::new(e.kind(), format!("{compression} decompression failed: {e}"))
+            }
+            _ => e,
+        }
+    }
+}
+
+/// Lock `<path>.lock`, held by the download to `path` while it runs.
+async fn lock_download(path: &Path) -> Result<FileLock> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(".lock");
+    FileLock::acquire(Path::new(&name)).await
+}
+
+/// Path of the partial download for `path`: `<path>.part`.
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// A cached image: its index entry, the aliases pointing at it and its blob.
+#[derive(Debug, Clone)]
+pub struct CachedImage {
+    pub entry: IndexEntry,
+    pub aliases: Vec<String>,
+    pub path: PathBuf,
+}
+
+/// Detect the format of a disk image from its header, using `qemu-img info` for formats
+/// [`format::inspect`] doesn't read.
+pub async fn detect_format(path: &Path) -> Result<String> {
+    if let Some(info) = format::inspect(path).await? {
+        return Ok(info.format.to_string());
+    }
+
+    let output = tokio::process::Command::new("qemu-img")
+        .args(["info", "--output=json"])
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| VmError::ImageFormatDetectionFailed {
+            path: path.into(),
+            detail: format!("qemu-img not found: {e}"),
+        })?;
+
+    if !output.status.success() {
+        return Err(VmError::ImageFormatDetectionFailed {
+            path: path.into(),
+            detail: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).map_err(|e| {
+        VmError::ImageFormatDetectionFailed {
+            path: path.into(),
+            detail: format!("failed to parse qemu-img JSON: {e}"),
+        }
+    })?;
+
+    Ok(info
+        .get("format")
+        .and_then(|f| f.as_str())
+        .unwrap_or("raw")
+        .to_string())
+}
+
+/// Backing file recorded in the header of the QCOW2 or VMDK image at `path`, if it has one.
+///
+/// Relative names are resolved against the directory of `path`, as QEMU does. Missing files
+/// and files that aren't overlays, such as raw disks or zvol devices, have none.
+pub async fn backing_file(path: &Path) -> Result<Option<PathBuf>> {
+    match tokio::fs::metadata(path).await {
+        Ok(meta) if meta.is_file() => {}
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(format::inspect(path)
+        .await?
+        .and_then(|info| info.backing_file))
+}
+
+/// Convert an image from one format to another using `qemu-img convert`.
+pub async fn convert(src: &Path, dst: &Path, output_format: &str) -> Result<()> {
+    let output = tokio::process::Command::new("qemu-img")
+        .args(["convert", "-O", output_format])
+        .arg(src)
+        .arg(dst)
+        .output()
+        .await
+        .map_err(|e| VmError::ImageConversionFailed {
+            detail: format!("qemu-img convert failed to start: {e}"),
+        })?;
+
+    if !output.status.success() {
+        return Err(VmError::ImageConversionFailed {
+            detail: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Create a QCOW2 overlay backed by a base image.
+///
+/// Automatically detects the base image format. If `size_gb` is provided, the overlay is resized.
+/// The overlay is written natively for QCOW2, VMDK and raw bases, and with `qemu-img create`
+/// for other formats.
+pub async fn create_overlay(base: &Path, overlay: &Path, size_gb: Option<u32>) -> Result<()> {
+    if let Some(info) = format::inspect(base).await? {
+        let size = size_gb
+            .map(|gb| u64::from(gb) * 1024 * 1024 * 1024)
+            .unwrap_or(info.virtual_size);
+        return format::create_qcow2_overlay(overlay, base, info.format, size).await;
+    }
+    let base_fmt = detect_format(base).await?;
+
+    let mut args = vec![
+        "create".to_string(),
+        "-f".into(),
+        "qcow2".into(),
+        "-F".into(),
+        base_fmt,
+        "-b".into(),
+        base.to_string_lossy().into_owned(),
+        overlay.to_string_lossy().into_owned(),
+    ];
+
+    if let Some(gb) = size_gb {
+        args.push(format!("{gb}G"));
+    }
+
+    let output = tokio::process::Command::new("qemu-img")
+        .args(&args)
+        .output()
+        .await
+        .map_err(|e| VmError::OverlayCreationFailed {
+            base: base.into(),
+            detail: format!("qemu-img not found: {e}"),
+        })?;
+
+    if !output.status.success() {
+        return Err(VmError::OverlayCreationFailed {
+            base: base.into(),
+            detail: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    /// Paths and bodies the test server serves.
+    type Files = Vec<(&'static str, Vec<u8>)>;
+
+    /// Test HTTP server for `files` (path, body) that supports range requests.
+    struct Server {
+        base: String,
+        /// Request lines and `Range` headers received, and `if-none-match` for conditional
+        /// requests.
+        requests: Arc<Mutex<Vec<String>>>,
+        files: Arc<Mutex<Files>>,
+    }
+
+    impl Server {
+        /// Replace the file at `path` with `body`.
+        fn publish(&self, path: &'static str, body: &[u8]) {
+            let mut files = self.files.lock().unwrap();
+            files.retain(|(p, _)| *p != path);
+            files.push((path, body.to_vec()));
+        }
+
+        /// Requests for `path`.
+        fn requests_for(&self, path: &str) -> Vec<String> {
+            let prefix = format!("GET {path}");
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|r| *r == &prefix || r.starts_with(&format!("{prefix} ")))
+                .cloned()
+                .collect()
+        }
+    }
+
+    /// Serve `files` over HTTP on localhost, returning the base URL.
+    async fn serve(files: Files) -> String {
+        serve_flaky(files, 0).await.base
+    }
+
+    /// Like [`serve`], but the first `drops` responses with a body are cut off halfway.
+    async fn serve_flaky(files: Files, drops: usize) -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let drops = Arc::new(AtomicUsize::new(drops));
+        let log = requests.clone();
+        let files = Arc::new(Mutex::new(files));
+        let served = files.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let files = served.lock().unwrap().clone();
+                let log = log.clone();
+                let drops = drops.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let head = String::from_utf8_lossy(&head).into_owned();
+                    let path = head.split_whitespace().nth(1).unwrap_or("/").to_string();
+                    let range = head
+                        .lines()
+                        .filter_map(|l| l.split_once(':'))
+                        .find(|(k, _)| k.eq_ignore_ascii_case("range"))
+                        .map(|(_, v)| v.trim().to_string());
+                    let header = |name: &str| {
+                        head.lines()
+                            .filter_map(|l| l.split_once(':'))
+                            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                            .map(|(_, v)| v.trim().to_string())
+                    };
+                    let if_none_match = header("if-none-match");
+                    let if_range = header("if-range");
+                    log.lock().unwrap().push(
+                        format!(
+                            "GET {path} {}{}",
+                            range.as_deref().unwrap_or(""),
+                            if if_none_match.is_some() {
+                                " if-none-match"
+                            } else {
+                                ""
+                            }
+                        )
+                        .trim_end()
+                        .replace("  ", " "),
+                    );
+
+                    let Some((_, body)) = files.iter().find(|(p, _)| *p == path) else {
+                        let _ = stream
+                            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                            .await;
+                        return;
+                    };
+                    if if_none_match == Some(format!("\"{}\"", etag(body))) {
+                        let _ = stream
+                            .write_all(b"HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n")
+                            .await;
+                        return;
+                    }
+                    // A range for another version of the file gets the whole file.
+                    let start = range
+                        .as_deref()
+                        .and_then(|r| r.strip_prefix("bytes="))
+                        .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok())
+                        .filter(|_| {
+                            if_range
+                                .as_ref()
+                                .is_none_or(|v| *v == format!("\"{}\"", etag(body)))
+                        });
+                    let (status, extra, content) = match start {
+                        Some(start) if start >= body.len() => {
+                            let reply = format!(
+                                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                                body.len()
+                            );
+                            let _ = stream.write_all(reply.as_bytes()).await;
+                            return;
+                        }
+                        Some(start) => (
+                            "206 Partial Content",
+                            format!(
+                                "Content-Range: bytes {start}-{}/{}\r\n",
+                                body.len() - 1,
+                                body.len()
+                            ),
+                            &body[start..],
+                        ),
+                        None => ("200 OK", String::new(), &body[..]),
+                    };
+                    let reply = format!(
+                        "HTTP/1.1 {status}\r\n{extra}ETag: \"{}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        etag(body),
+                        content.len()
+                    );
+                    let _ = stream.write_all(reply.as_bytes()).await;
+                    let cut = drops
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                    let sent = if cut {
+                        &content[..content.len() / 2]
+                    } else {
+                        content
+                    };
+                    let _ = stream.write_all(sent).await;
+                });
+            }
+        });
+        Server {
+            base,
+            requests,
+            files,
+        }
+    }
+
+    /// Header of a QCOW2 v2 image backed by `backing`, without any data.
+    pub(super) fn qcow2_header(backing: &str) -> Vec<u8> {
+        let mut header = vec![0u8; 72];
+        header[..4].copy_from_slice(&format::QCOW2_MAGIC);
+        header[4..8].copy_from_slice(&2u32.to_be_bytes());
+        header[8..16].copy_from_slice(&72u64.to_be_bytes());
+        header[16..20].copy_from_slice(&(backing.len() as u32).to_be_bytes());
+        header[20..24].copy_from_slice(&16u32.to_be_bytes());
+        header.extend_from_slice(backing.as_bytes());
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_backing_file_from_qcow2_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let absolute = dir.path().join("absolute.qcow2");
+        std::fs::write(&absolute, qcow2_header("/images/base.img")).unwrap();
+        let relative = dir.path().join("relative.qcow2");
+        std::fs::write(&relative, qcow2_header("base.img")).unwrap();
+        let raw = dir.path().join("disk.raw");
+        std::fs::write(&raw, [0u8; 512]).unwrap();
+
+        assert_eq!(
+            backing_file(&absolute).await.unwrap(),
+            Some(PathBuf::from("/images/base.img"))
+        );
+        assert_eq!(
+            backing_file(&relative).await.unwrap(),
+            Some(dir.path().join("base.img"))
+        );
+        assert_eq!(backing_file(&raw).await.unwrap(), None);
+        assert_eq!(
+            backing_file(&dir.path().join("missing")).await.unwrap(),
+            None
+        );
+    }
+
+    /// `ETag` the test server sends for `body`.
+    fn etag(body: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(body))[..16].to_string()
+    }
+
+    /// Files under `downloads/` in the cache in `dir`.
+    fn downloads(dir: &Path) -> Vec<PathBuf> {
+        match std::fs::read_dir(dir.join("downloads")) {
+            Ok(entries) => entries.map(|e| e.unwrap().path()).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parses_checksums() {
+        let checksum: Checksum = format!("SHA256:{}", ABC_SHA256.to_uppercase())
+            .parse()
+            .unwrap();
+        assert_eq!(checksum.algorithm, ChecksumAlgorithm::Sha256);
+        assert_eq!(checksum.to_string(), format!("sha256:{ABC_SHA256}"));
+
+        assert!("sha512:abc".parse::<Checksum>().is_err());
+        assert!("md5:abc".parse::<Checksum>().is_err());
+        assert!(ABC_SHA256.parse::<Checksum>().is_err());
+    }
+
+    #[test]
+    fn finds_digest_in_sums_files() {
+        let gnu = format!("{}  other.img\n{ABC_SHA256} *disk.img\n", "0".repeat(64));
+        let found = find_in_sums(&gnu, ChecksumAlgorithm::Sha256, "disk.img").unwrap();
+        assert_eq!(found.digest, ABC_SHA256);
+
+        let bsd = format!("SHA256 (disk.img) = {ABC_SHA256}\n");
+        let found = find_in_sums(&bsd, ChecksumAlgorithm::Sha256, "disk.img").unwrap();
+        assert_eq!(found.digest, ABC_SHA256);
+
+        assert!(find_in_sums(&gnu, ChecksumAlgorithm::Sha256, "missing.img").is_none());
+    }
+
+    #[tokio::test]
+    async fn verifies_against_published_sums() {
+        let base = serve(vec![
+            ("/disk.img", b"abc".to_vec()),
+            (
+                "/SHA256SUMS",
+                format!("{ABC_SHA256}  disk.img\n").into_bytes(),
+            ),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        let path = mgr.pull(&format!("{base}/disk.img"), None).await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"abc");
+    }
+
+    #[tokio::test]
+    async fn mismatch_deletes_download() {
+        let base = serve(vec![
+            ("/disk.img", b"ab".to_vec()),
+            (
+                "/SHA256SUMS",
+                format!("{ABC_SHA256}  disk.img\n").into_bytes(),
+            ),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        let err = mgr
+            .pull(&format!("{base}/disk.img"), None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, VmError::ImageChecksumMismatch { ref expected, .. } if expected.ends_with(ABC_SHA256)),
+            "got: {err}"
+        );
+        assert!(downloads(dir.path()).is_empty());
+        assert!(!dir.path().join("blobs").exists());
+        assert!(mgr.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn uses_checksum_url() {
+        let base = serve(vec![
+            ("/images/disk.img", b"ab".to_vec()),
+            // Not consulted when a checksum URL is given.
+            (
+                "/images/SHA256SUMS",
+                format!("{:x}  disk.img\n", Sha256::digest(b"ab")).into_bytes(),
+            ),
+            (
+                "/sums/CHECKSUM",
+                format!("SHA256 (disk.img) = {ABC_SHA256}\n").into_bytes(),
+            ),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into())
+            .with_checksum_url(format!("{base}/sums/CHECKSUM"));
+
+        let err = mgr
+            .pull(&format!("{base}/images/disk.img"), None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, VmError::ImageChecksumMismatch { ref expected, .. } if expected.ends_with(ABC_SHA256)),
+            "got: {err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn explicit_checksum_wins() {
+        let base = serve(vec![
+            ("/disk.img", b"abc".to_vec()),
+            (
+                "/SHA256SUMS",
+                format!("{}  disk.img\n", "0".repeat(64)).into_bytes(),
+            ),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+        let checksum: Checksum = format!("sha256:{ABC_SHA256}").parse().unwrap();
+
+        mgr.pull_verified(&format!("{base}/disk.img"), None, Some(&checksum))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumes_dropped_connections() {
+        let body: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let server = serve_flaky(vec![("/disk.img", body.clone())], 2).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into())
+            .with_retries(5, Duration::from_millis(10));
+
+        let path = mgr
+            .pull(&format!("{}/disk.img", server.base), None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert!(downloads(dir.path()).is_empty());
+
+        let requests = server.requests.lock().unwrap().clone();
+        let downloads: Vec<_> = requests
+            .iter()
+            .filter(|r| r.starts_with("GET /disk.img"))
+            .collect();
+        assert_eq!(downloads.len(), 3, "{requests:?}");
+        assert_eq!(downloads[0], "GET /disk.img");
+        assert_eq!(downloads[1], "GET /disk.img bytes=50000-");
+        assert_eq!(downloads[2], "GET /disk.img bytes=75000-");
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_part_file_and_verifies_it() {
+        let server = serve_flaky(
+            vec![
+                ("/disk.img", b"abc".to_vec()),
+                (
+                    "/SHA256SUMS",
+                    format!("{ABC_SHA256}  disk.img\n").into_bytes(),
+                ),
+            ],
+            0,
+        )
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("disk.img");
+        std::fs::write(dir.path().join("disk.img.part"), b"ab").unwrap();
+        write_validators(&dir.path().join("disk.img.part.json"), b"abc");
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        mgr.download(&format!("{}/disk.img", server.base), &dest)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(dest).unwrap(), b"abc");
+        assert!(!dir.path().join("disk.img.part.json").exists());
+        assert!(
+            server
+                .requests
+                .lock()
+                .unwrap()
+                .contains(&"GET /disk.img bytes=2-".to_string())
+        );
+    }
+
+    /// Record the validators the test server sends for `body` as those of a partial download.
+    fn write_validators(meta: &Path, body: &[u8]) {
+        let validators = Validators {
+            etag: Some(format!("\"{}\"", etag(body))),
+            last_modified: None,
+        };
+        std::fs::write(meta, serde_json::to_vec(&validators).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restarts_part_files_of_changed_images() {
+        let server = serve_flaky(vec![("/disk.img", b"xyz".to_vec())], 0).await;
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("{}/disk.img", server.base);
+
+        // Left by a download of a version the server no longer has.
+        let dest = dir.path().join("changed.img");
+        std::fs::write(dir.path().join("changed.img.part"), b"ab").unwrap();
+        write_validators(&dir.path().join("changed.img.part.json"), b"abc");
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+        mgr.download(&url, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"xyz");
+
+        // Left by a download whose version is unknown.
+        let dest = dir.path().join("unknown.img");
+        std::fs::write(dir.path().join("unknown.img.part"), b"ab").unwrap();
+        mgr.download(&url, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"xyz");
+
+        assert_eq!(
+            server.requests_for("/disk.img"),
+            ["GET /disk.img bytes=2-", "GET /disk.img"]
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let server = serve_flaky(vec![("/disk.img", vec![7u8; 1000])], 10).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into())
+            .with_retries(2, Duration::from_millis(10));
+
+        let err = mgr
+            .pull(&format!("{}/disk.img", server.base), None)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("gave up after 2 attempts"),
+            "got: {err}"
+        );
+        // The partial download stays for the next attempt, but never enters the cache.
+        let mut parts = downloads(dir.path());
+        parts.sort();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].extension().unwrap(), "part");
+        assert_eq!(std::fs::read(&parts[0]).unwrap().len(), 750);
+        // Along with the validators to resume it with.
+        assert_eq!(parts[1].extension().unwrap(), "json");
+        assert!(mgr.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_image_is_not_retried() {
+        let server = serve_flaky(vec![], 0).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        let err = mgr
+            .pull(&format!("{}/disk.img", server.base), None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("404"), "got: {err}");
+        let requests = server.requests.lock().unwrap().clone();
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|r| r.starts_with("GET /disk.img"))
+                .count(),
+            1
+        );
+    }
+
+    /// Compress `data` with `compression`.
+    async fn compress(compression: Compression, data: &[u8]) -> Vec<u8> {
+        use async_compression::tokio::write::{BzEncoder, GzipEncoder, XzEncoder, ZstdEncoder};
+
+        let mut out = Vec::new();
+        let mut encoder: Pin<Box<dyn AsyncWrite + Send + '_>> = match compression {
+            Compression::Zstd => Box::pin(ZstdEncoder::new(&mut out)),
+            Compression::Xz => Box::pin(XzEncoder::new(&mut out)),
+            Compression::Gzip => Box::pin(GzipEncoder::new(&mut out)),
+            Compression::Bzip2 => Box::pin(BzEncoder::new(&mut out)),
+            Compression::None => Box::pin(&mut out),
+        };
+        encoder.write_all(data).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        drop(encoder);
+        out
+    }
+
+    #[tokio::test]
+    async fn decompresses_downloads() {
+        for (compression, suffix) in [
+            (Compression::Zstd, ".zst"),
+            (Compression::Xz, ".xz"),
+            (Compression::Gzip, ".gz"),
+            (Compression::Bzip2, ".bz2"),
+        ] {
+            let compressed = compress(compression, b"abc").await;
+            assert_eq!(Compression::detect(&compressed), compression);
+            let url_path: &'static str = format!("/disk.img{suffix}").leak();
+            let base = serve(vec![(url_path, compressed)]).await;
+            let dir = tempfile::tempdir().unwrap();
+            let mgr = ImageManager::with_cache_dir(dir.path().into());
+            let dest = dir.path().join("disk.img");
+
+            mgr.download(&format!("{base}{url_path}"), &dest)
+                .await
+                .unwrap();
+            assert_eq!(std::fs::read(&dest).unwrap(), b"abc", "{compression}");
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn detects_compression_by_content_not_name() {
+        let base = serve(vec![
+            ("/packed.img", compress(Compression::Xz, b"abc").await),
+            ("/plain.img.gz", b"abc".to_vec()),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        let packed = mgr.pull(&format!("{base}/packed.img"), None).await.unwrap();
+        assert_eq!(std::fs::read(packed).unwrap(), b"abc");
+        let plain = mgr
+            .pull(&format!("{base}/plain.img.gz"), None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(plain).unwrap(), b"abc");
+    }
+
+    #[tokio::test]
+    async fn resumes_compressed_downloads_in_process() {
+        let body: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let compressed = compress(Compression::Gzip, &body).await;
+        let server = serve_flaky(vec![("/disk.img.gz", compressed)], 2).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into())
+            .with_retries(5, Duration::from_millis(10));
+
+        let dest = dir.path().join("disk.img");
+        mgr.download(&format!("{}/disk.img.gz", server.base), &dest)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        let requests = server.requests.lock().unwrap().clone();
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|r| r.starts_with("GET /disk.img.gz bytes="))
+                .count(),
+            2,
+            "{requests:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupt_compressed_download_fails() {
+        let mut compressed = compress(Compression::Zstd, &[1u8; 4096]).await;
+        compressed.truncate(compressed.len() / 2);
+        let base = serve(vec![("/disk.img.zst", compressed)]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into())
+            .with_retries(1, Duration::from_millis(10));
+
+        let err = mgr
+            .pull(&format!("{base}/disk.img.zst"), None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("zstd"), "got: {err}");
+        assert!(downloads(dir.path()).is_empty());
+        assert!(!dir.path().join("blobs").exists());
+        assert!(mgr.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pulls_are_shared_by_content() {
+        let server = serve_flaky(vec![("/disk.img", b"abc".to_vec())], 0).await;
+        let url = format!("{}/disk.img", server.base);
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        let web = mgr.pull(&url, Some("web")).await.unwrap();
+        let db = mgr.pull(&url, Some("db")).await.unwrap();
+        assert_eq!(web, db);
+        assert_eq!(web, dir.path().join("blobs/sha256").join(ABC_SHA256));
+        assert_eq!(
+            server
+                .requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|r| r.starts_with("GET /disk.img"))
+                .count(),
+            1
+        );
+
+        let images = mgr.list().await.unwrap();
+        assert_eq!(images.len(), 1);
+        let image = &images[0];
+        assert_eq!(image.aliases, ["db", "web"]);
+        assert_eq!(image.entry.source, ImageOrigin::Url(url));
+        assert_eq!(
+            image.entry.digest.to_string(),
+            format!("sha256:{ABC_SHA256}")
+        );
+        assert_eq!(
+            image.entry.etag.as_deref(),
+            Some(format!("\"{}\"", etag(b"abc")).as_str())
+        );
+        assert_eq!(image.entry.size_bytes, 3);
+        assert!(image.entry.last_used >= image.entry.pulled_at);
+    }
+
+    #[tokio::test]
+    async fn concurrent_pulls_of_a_source_take_turns() {
+        let body: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let server = serve_flaky(vec![("/disk.img", body.clone())], 0).await;
+        let url = format!("{}/disk.img", server.base);
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = Arc::new(ImageManager::with_cache_dir(dir.path().into()));
+
+        let pulls: Vec<_> = ["web", "db", "ci"]
+            .into_iter()
+            .map(|name| {
+                let mgr = mgr.clone();
+                let url = url.clone();
+                tokio::spawn(async move { mgr.pull(&url, Some(name)).await })
+            })
+            .collect();
+        let mut paths = Vec::new();
+        for pull in pulls {
+            paths.push(pull.await.unwrap().unwrap());
+        }
+
+        // One pull downloaded the image, the others waited for it and used the cached image.
+        assert!(paths.iter().all(|p| *p == paths[0]));
+        assert_eq!(std::fs::read(&paths[0]).unwrap(), body);
+        assert_eq!(server.requests_for("/disk.img").len(), 1);
+        assert!(downloads(dir.path()).is_empty());
+        assert_eq!(mgr.list().await.unwrap()[0].aliases, ["ci", "db", "web"]);
+    }
+
+    #[tokio::test]
+    async fn new_url_repoints_alias() {
+        let base = serve(vec![
+            (
+                "/noble.img.zst",
+                compress(Compression::Zstd, b"noble").await,
+            ),
+            ("/plucky.img", b"plucky".to_vec()),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        let old = mgr
+            .pull(&format!("{base}/noble.img.zst"), None)
+            .await
+            .unwrap();
+        mgr.pull(&format!("{base}/noble.img.zst"), Some("web"))
+            .await
+            .unwrap();
+        let new = mgr
+            .pull(&format!("{base}/plucky.img"), Some("web"))
+            .await
+            .unwrap();
+        assert_ne!(old, new);
+        assert_eq!(std::fs::read(&new).unwrap(), b"plucky");
+
+        let index = ImageIndex::load(dir.path()).await.unwrap();
+        let aliases: Vec<_> = index
+            .aliases
+            .iter()
+            .map(|(alias, digest)| (alias.as_str(), cache::blob_path(dir.path(), digest)))
+            .collect();
+        assert_eq!(aliases, [("noble.img", old), ("web", new)]);
+        assert_eq!(mgr.list().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn refresh_replaces_only_changed_images() {
+        let server = serve_flaky(vec![("/disk.img", b"v1".to_vec())], 0).await;
+        let url = format!("{}/disk.img", server.base);
+        let dir = tempfile::tempdir().unwrap();
+        let mgr =
+            ImageManager::with_cache_dir(dir.path().into()).with_refresh(RefreshPolicy::Always);
+
+        let v1 = mgr.pull(&url, Some("web")).await.unwrap();
+        let again = mgr.pull(&url, Some("web")).await.unwrap();
+        assert_eq!(again, v1);
+        assert_eq!(
+            server.requests_for("/disk.img"),
+            ["GET /disk.img", "GET /disk.img if-none-match"]
+        );
+        let index = ImageIndex::load(dir.path()).await.unwrap();
+        assert_eq!(index.images.len(), 1);
+
+        server.publish("/disk.img", b"v2");
+        let v2 = mgr.pull(&url, Some("web")).await.unwrap();
+        assert_ne!(v2, v1);
+        assert_eq!(std::fs::read(&v2).unwrap(), b"v2");
+        // Overlays based on the old image keep their base.
+        assert_eq!(std::fs::read(&v1).unwrap(), b"v1");
+
+        let images = mgr.list().await.unwrap();
+        assert_eq!(images.len(), 2);
+        let latest = images.iter().find(|i| i.path == v2).unwrap();
+        assert_eq!(latest.aliases, ["web"]);
+        assert_eq!(
+            latest.entry.etag.as_deref(),
+            Some(format!("\"{}\"", etag(b"v2")).as_str())
+        );
+        let superseded = images.iter().find(|i| i.path == v1).unwrap();
+        assert!(superseded.aliases.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refresh_policy_decides_when_to_check() {
+        let server = serve_flaky(vec![("/disk.img", b"v1".to_vec())], 0).await;
+        let url = format!("{}/disk.img", server.base);
+        let dir = tempfile::tempdir().unwrap();
+
+        let never = ImageManager::with_cache_dir(dir.path().into());
+        never.pull(&url, None).await.unwrap();
+        server.publish("/disk.img", b"v2");
+        let path = never.pull(&url, None).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"v1");
+
+        // Pulled just now, so not due for a daily check yet.
+        let daily =
+            ImageManager::with_cache_dir(dir.path().into()).with_refresh(RefreshPolicy::Daily);
+        daily.pull(&url, None).await.unwrap();
+        assert_eq!(server.requests_for("/disk.img"), ["GET /disk.img"]);
+
+        ImageIndex::update(dir.path(), |index| {
+            index.images[0].pulled_at -= 2 * 24 * 3600;
+            index.images[0].checked_at -= 2 * 24 * 3600;
+        })
+        .await
+        .unwrap();
+        let path = daily.pull(&url, None).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"v2");
+    }
+}
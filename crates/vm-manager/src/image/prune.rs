@@ -0,0 +1,461 @@
+//! Removing images from the cache.
+//!
+//! An image that backs the overlay of a VM is never removed: the overlay is useless without
+//! it. Which images those are is read from the overlays themselves, as the backing file
+//! recorded in their QCOW2 header.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use super::cache::{self, INDEX_FILE, INDEX_LOCK, ImageIndex, ImageOrigin};
+use super::{Checksum, ImageManager, backing_file};
+use crate::error::{Result, VmError};
+use crate::types::VmHandle;
+
+/// Partial downloads untouched for this long are abandoned, and pruned.
+const STALE_DOWNLOAD_AGE: Duration = Duration::from_secs(24 * 3600);
+
+/// Which images [`ImageManager::prune`] removes. By default, every image no VM uses.
+#[derive(Debug, Clone, Default)]
+pub struct PruneOptions {
+    /// Keep images an alias points at, removing only those no alias refers to any more.
+    pub keep_referenced: bool,
+    /// Only remove images that haven't been used for this long.
+    pub older_than: Option<Duration>,
+    /// Instead of removing all images, remove the least recently used ones until the cache
+    /// is at most this many bytes.
+    pub max_size: Option<u64>,
+    /// Report what would be removed without removing anything.
+    pub dry_run: bool,
+}
+
+/// An image removed from the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemovedImage {
+    pub path: PathBuf,
+    /// Content digest, for images in the index.
+    pub digest: Option<Checksum>,
+    pub aliases: Vec<String>,
+    pub sources: Vec<ImageOrigin>,
+    pub size_bytes: u64,
+}
+
+/// An image in the cache, as far as pruning is concerned.
+#[derive(Debug, Clone)]
+struct Stored {
+    image: RemovedImage,
+    last_used: u64,
+}
+
+impl ImageManager {
+    /// Remove the alias `name`, or every alias of the image with digest `name`.
+    ///
+    /// The image itself is removed once no alias points at it any more, and returned; `None`
+    /// means only the alias was removed. Fails if the image backs the overlay of one of `vms`.
+    pub async fn remove(&self, name: &str, vms: &[VmHandle]) -> Result<Option<RemovedImage>> {
+        let index = ImageIndex::load(&self.cache).await?;
+        let digest = index
+            .resolve(name)
+            .ok_or_else(|| VmError::ImageNotFound { name: name.into() })?;
+        let is_alias = index.aliases.contains_key(name);
+        let others = index
+            .aliases_of(&digest)
+            .into_iter()
+            .filter(|alias| alias != name)
+            .count();
+
+        if is_alias && others > 0 {
+            ImageIndex::update(&self.cache, |index| {
+                index.aliases.remove(name);
+            })
+            .await?;
+            info!(alias = name, "removed image alias");
+            return Ok(None);
+        }
+
+        let path = cache::blob_path(&self.cache, &digest);
+        if let Some(vm) = in_use(vms).await?.get(&canonical(&path)) {
+            return Err(VmError::ImageInUse {
+                name: name.into(),
+                vm: vm.clone(),
+            });
+        }
+
+        let stored = self.stored(&index).await?;
+        let image = match stored.into_iter().find(|s| s.image.path == path) {
+            Some(stored) => stored.image,
+            None => RemovedImage {
+                path,
+                digest: Some(digest),
+                aliases: Vec::new(),
+                sources: Vec::new(),
+                size_bytes: 0,
+            },
+        };
+        self.delete(std::slice::from_ref(&image)).await?;
+        Ok(Some(image))
+    }
+
+    /// Remove images no VM uses, as selected by `options`, and abandoned partial downloads.
+    ///
+    /// Images that back the overlay of one of `vms` are always kept. Returns the removed
+    /// images, least recently used first.
+    pub async fn prune(
+        &self,
+        options: &PruneOptions,
+        vms: &[VmHandle],
+    ) -> Result<Vec<RemovedImage>> {
+        let index = ImageIndex::load(&self.cache).await?;
+        let in_use = in_use(vms).await?;
+        let now = cache::now();
+
+        let mut stored = self.stored(&index).await?;
+        stored.sort_by_key(|s| s.last_used);
+        let total: u64 = stored.iter().map(|s| s.image.size_bytes).sum();
+
+        let mut candidates: Vec<_> = stored
+            .into_iter()
+            .filter(|s| !in_use.contains_key(&canonical(&s.image.path)))
+            .filter(|s| !options.keep_referenced || s.image.aliases.is_empty())
+            .collect();
+
+        let mut removed = Vec::new();
+        if let Some(older_than) = options.older_than {
+            let cutoff = now.saturating_sub(older_than.as_secs());
+            let (old, recent) = candidates.into_iter().partition(|s| s.last_used < cutoff);
+            removed = old;
+            candidates = recent;
+        }
+        match options.max_size {
+            Some(max_size) => {
+                let mut size = total - removed.iter().map(|s| s.image.size_bytes).sum::<u64>();
+                for candidate in candidates {
+                    if size <= max_size {
+                        break;
+                    }
+                    size -= candidate.image.size_bytes;
+                    removed.push(candidate);
+                }
+            }
+            // Without a size limit, --older-than alone selects what goes.
+            None if options.older_than.is_none() => removed.extend(candidates),
+            None => {}
+        }
+
+        let removed: Vec<_> = removed.into_iter().map(|s| s.image).collect();
+        if !options.dry_run {
+            self.delete(&removed).await?;
+            self.prune_downloads().await?;
+        }
+        Ok(removed)
+    }
+
+    /// Every image in the cache: the blobs in the index and image files left in the cache
+    /// directory by versions before the index.
+    async fn stored(&self, index: &ImageIndex) -> Result<Vec<Stored>> {
+        let mut by_digest: HashMap<&Checksum, Stored> = HashMap::new();
+        for entry in &index.images {
+            let stored = by_digest.entry(&entry.digest).or_insert_with(|| Stored {
+                image: RemovedImage {
+                    path: cache::blob_path(&self.cache, &entry.digest),
+                    digest: Some(entry.digest.clone()),
+                    aliases: index.aliases_of(&entry.digest),
+                    sources: Vec::new(),
+                    size_bytes: entry.size_bytes,
+                },
+                last_used: 0,
+            });
+            stored.image.sources.push(entry.source.clone());
+            stored.last_used = stored.last_used.max(entry.last_used);
+        }
+        let mut stored: Vec<_> = by_digest
+            .into_values()
+            .filter(|s| s.image.path.exists())
+            .collect();
+
+        if let Ok(mut dir) = tokio::fs::read_dir(&self.cache).await {
+            while let Some(entry) = dir.next_entry().await? {
+                let path = entry.path();
+                let metadata = entry.metadata().await?;
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if !metadata.is_file()
+                    || name == INDEX_FILE
+                    || name == INDEX_LOCK
+                    || name.ends_with(".tmp")
+                {
+                    continue;
+                }
+                stored.push(Stored {
+                    image: RemovedImage {
+                        path,
+                        digest: None,
+                        aliases: Vec::new(),
+                        sources: Vec::new(),
+                        size_bytes: metadata.len(),
+                    },
+                    last_used: modified(&metadata),
+                });
+            }
+        }
+        Ok(stored)
+    }
+
+    /// Delete `images` and drop them from the index.
+    async fn delete(&self, images: &[RemovedImage]) -> Result<()> {
+        for image in images {
+            match tokio::fs::remove_file(&image.path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {
+                    info!(path = %image.path.display(), size_bytes = image.size_bytes, "removed cached image")
+                }
+            }
+        }
+        ImageIndex::update(&self.cache, |index| {
+            for digest in images.iter().filter_map(|i| i.digest.as_ref()) {
+                index.images.retain(|e| &e.digest != digest);
+                index.aliases.retain(|_, d| d != digest);
+            }
+        })
+        .await
+    }
+
+    /// Delete partial downloads nobody has touched for [`STALE_DOWNLOAD_AGE`].
+    async fn prune_downloads(&self) -> Result<()> {
+        let Ok(mut dir) = tokio::fs::read_dir(self.cache.join("downloads")).await else {
+            return Ok(());
+        };
+        let cutoff = cache::now().saturating_sub(STALE_DOWNLOAD_AGE.as_secs());
+        while let Some(entry) = dir.next_entry().await? {
+            if modified(&entry.metadata().await?) < cutoff {
+                info!(path = %entry.path().display(), "removed abandoned download");
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Backing files of the overlays of `vms`, mapped to the VM using them.
+async fn in_use(vms: &[VmHandle]) -> Result<HashMap<PathBuf, String>> {
+    let mut in_use = HashMap::new();
+    for vm in vms {
+        let Some(ref overlay) = vm.overlay_path else {
+            continue;
+        };
+        match backing_file(overlay).await {
+            Ok(Some(backing)) => {
+                in_use.insert(canonical(&backing), vm.name.clone());
+            }
+            Ok(None) => {}
+            Err(e) => {
+                // Without knowing what backs the overlay, nothing can be removed safely.
+                warn!(vm = %vm.name, overlay = %overlay.display(), error = %e, "cannot read overlay");
+                return Err(e);
+            }
+        }
+    }
+    Ok(in_use)
+}
+
+/// `path` with symlinks resolved, so that different spellings of a path compare equal.
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Modification time of a file in seconds since the Unix epoch.
+fn modified(metadata: &std::fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::tests::qcow2_header;
+    use crate::image::{ChecksumAlgorithm, IndexEntry, content_hash};
+
+    const DAY: u64 = 24 * 3600;
+
+    /// Put an image with `content` into the cache in `dir`, last used `age` seconds ago.
+    async fn seed(dir: &Path, alias: Option<&str>, content: &[u8], age: u64) -> PathBuf {
+        let tmp = dir.join("seed.tmp");
+        std::fs::write(&tmp, content).unwrap();
+        let digest = Checksum {
+            algorithm: ChecksumAlgorithm::Sha256,
+            digest: content_hash(&tmp).await.unwrap(),
+        };
+        let path = cache::blob_path(dir, &digest);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+        let used = cache::now() - age;
+        ImageIndex::update(dir, |index| {
+            index.record(IndexEntry {
+                source: ImageOrigin::Url(format!("https://example.com/{}", digest.digest)),
+                digest: digest.clone(),
+                etag: None,
+                last_modified: None,
+                size_bytes: content.len() as u64,
+                pulled_at: used,
+                checked_at: 0,
+                last_used: used,
+            });
+            if let Some(alias) = alias {
+                index.alias(alias, &digest);
+            }
+        })
+        .await
+        .unwrap();
+        path
+    }
+
+    /// A VM whose QCOW2 overlay in `dir` is backed by `base`.
+    fn vm_on(dir: &Path, name: &str, base: &Path) -> VmHandle {
+        let overlay = dir.join(format!("{name}.qcow2"));
+        std::fs::write(&overlay, qcow2_header(base.to_str().unwrap())).unwrap();
+        let mut vm: VmHandle = serde_json::from_value(serde_json::json!({
+            "id": name,
+            "name": name,
+            "backend": "qemu",
+            "work_dir": dir,
+        }))
+        .unwrap();
+        vm.overlay_path = Some(overlay);
+        vm
+    }
+
+    fn paths(removed: &[RemovedImage]) -> Vec<&Path> {
+        removed.iter().map(|r| r.path.as_path()).collect()
+    }
+
+    #[tokio::test]
+    async fn prune_keeps_images_backing_vms() {
+        let dir = tempfile::tempdir().unwrap();
+        let vms_dir = tempfile::tempdir().unwrap();
+        let used = seed(dir.path(), Some("web"), b"web", 0).await;
+        let unused = seed(dir.path(), Some("old"), b"old", DAY).await;
+        let legacy = dir.path().join("web.img");
+        std::fs::write(&legacy, b"legacy").unwrap();
+        let vms = [vm_on(vms_dir.path(), "web", &used)];
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        let dry_run = PruneOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let removed = mgr.prune(&dry_run, &vms).await.unwrap();
+        assert_eq!(paths(&removed).len(), 2);
+        assert!(unused.exists() && legacy.exists());
+
+        let removed = mgr.prune(&PruneOptions::default(), &vms).await.unwrap();
+        assert_eq!(paths(&removed), [unused.as_path(), legacy.as_path()]);
+        assert_eq!(removed[0].aliases, ["old"]);
+        assert!(used.exists() && !unused.exists() && !legacy.exists());
+
+        let index = ImageIndex::load(dir.path()).await.unwrap();
+        assert_eq!(index.images.len(), 1);
+        assert_eq!(index.aliases.keys().collect::<Vec<_>>(), ["web"]);
+    }
+
+    #[tokio::test]
+    async fn prune_keep_referenced_only_removes_unaliased_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let aliased = seed(dir.path(), Some("web"), b"web", DAY).await;
+        let orphan = seed(dir.path(), None, b"orphan", DAY).await;
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        let options = PruneOptions {
+            keep_referenced: true,
+            ..Default::default()
+        };
+        let removed = mgr.prune(&options, &[]).await.unwrap();
+        assert_eq!(paths(&removed), [orphan.as_path()]);
+        assert!(aliased.exists());
+    }
+
+    #[tokio::test]
+    async fn prune_by_age_and_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let oldest = seed(dir.path(), Some("a"), &[1; 100], 40 * DAY).await;
+        let older = seed(dir.path(), Some("b"), &[2; 100], 20 * DAY).await;
+        let old = seed(dir.path(), Some("c"), &[3; 100], 10 * DAY).await;
+        let recent = seed(dir.path(), Some("d"), &[4; 100], 0).await;
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        let older_than = PruneOptions {
+            older_than: Some(Duration::from_secs(30 * DAY)),
+            dry_run: true,
+            ..Default::default()
+        };
+        let removed = mgr.prune(&older_than, &[]).await.unwrap();
+        assert_eq!(paths(&removed), [oldest.as_path()]);
+
+        let max_size = PruneOptions {
+            max_size: Some(250),
+            dry_run: true,
+            ..Default::default()
+        };
+        let removed = mgr.prune(&max_size, &[]).await.unwrap();
+        assert_eq!(paths(&removed), [oldest.as_path(), older.as_path()]);
+
+        let both = PruneOptions {
+            older_than: Some(Duration::from_secs(15 * DAY)),
+            max_size: Some(100),
+            ..Default::default()
+        };
+        let removed = mgr.prune(&both, &[]).await.unwrap();
+        assert_eq!(
+            paths(&removed),
+            [oldest.as_path(), older.as_path(), old.as_path()]
+        );
+        assert!(recent.exists());
+    }
+
+    #[tokio::test]
+    async fn remove_drops_alias_then_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = seed(dir.path(), Some("web"), b"abc", 0).await;
+        ImageIndex::update(dir.path(), |index| {
+            let digest = index.resolve("web").unwrap();
+            index.alias("db", &digest);
+        })
+        .await
+        .unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        assert_eq!(mgr.remove("web", &[]).await.unwrap(), None);
+        assert!(path.exists());
+        let removed = mgr.remove("db", &[]).await.unwrap().unwrap();
+        assert_eq!(removed.path, path);
+        assert_eq!(removed.aliases, ["db"]);
+        assert!(!path.exists());
+        assert!(mgr.list().await.unwrap().is_empty());
+
+        let err = mgr.remove("db", &[]).await.unwrap_err();
+        assert!(matches!(err, VmError::ImageNotFound { .. }), "got: {err}");
+    }
+
+    #[tokio::test]
+    async fn remove_refuses_images_backing_vms() {
+        let dir = tempfile::tempdir().unwrap();
+        let vms_dir = tempfile::tempdir().unwrap();
+        let path = seed(dir.path(), Some("web"), b"abc", 0).await;
+        let vms = [vm_on(vms_dir.path(), "web-1", &path)];
+        let mgr = ImageManager::with_cache_dir(dir.path().into());
+
+        let digest = format!("sha256:{}", content_hash(&path).await.unwrap());
+        let err = mgr.remove(&digest, &vms).await.unwrap_err();
+        assert!(
+            matches!(err, VmError::ImageInUse { ref vm, .. } if vm == "web-1"),
+            "got: {err}"
+        );
+        assert!(path.exists());
+    }
+}
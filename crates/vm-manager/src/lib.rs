@@ -1,7 +1,11 @@
 pub mod backends;
 pub mod cloudinit;
+pub mod config;
+pub mod console;
 pub mod error;
+pub mod host;
 pub mod image;
+pub mod lock;
 pub mod oci;
 pub mod provision;
 pub mod ssh;
@@ -0,0 +1,132 @@
+//! Advisory file locks that serialize work between vmctl processes.
+//!
+//! The locks are `flock`s on a lock file: they are held per open file, so two tasks of the
+//! same process exclude each other just like two processes do, and a crashed holder releases
+//! its lock with its file descriptors. The holder may remove the lock file; whoever waited
+//! for it then locks a new one.
+
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, VmError};
+
+/// Exclusive lock on a file, released when dropped.
+#[derive(Debug)]
+pub struct FileLock {
+    _file: std::fs::File,
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Lock `path`, creating it if needed, and wait until no one else holds it.
+    pub async fn acquire(path: &Path) -> Result<Self> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || match lock(&path, libc::LOCK_EX) {
+            Ok(Some(file)) => Ok(Self { _file: file, path }),
+            Ok(None) => unreachable!("a blocking flock only returns once it holds the lock"),
+            Err(e) => Err(e.into()),
+        })
+        .await
+        .map_err(|e| VmError::Io(std::io::Error::other(e)))?
+    }
+
+    /// Lock `path`, creating it if needed, unless someone else holds it already.
+    pub fn try_acquire(path: &Path) -> Result<Option<Self>> {
+        let file = lock(path, libc::LOCK_EX | libc::LOCK_NB)?;
+        Ok(file.map(|file| Self {
+            _file: file,
+            path: path.to_path_buf(),
+        }))
+    }
+
+    /// Remove the lock file and release the lock.
+    pub fn remove(self) -> Result<()> {
+        std::fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Lock the file at `path`, or return `None` if `operation` doesn't wait and it is held.
+fn lock(path: &Path, operation: libc::c_int) -> std::io::Result<Option<std::fs::File>> {
+    loop {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        // SAFETY: flock only takes a lock on the descriptor, which `file` keeps open
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+        // A lock on a file the previous holder removed excludes no one any more.
+        let locked = file.metadata()?;
+        match std::fs::metadata(path) {
+            Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {
+                return Ok(Some(file));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn lock_is_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.lock");
+        let first = FileLock::acquire(&path).await.unwrap();
+        assert!(FileLock::try_acquire(&path).unwrap().is_none());
+
+        let second = tokio::spawn({
+            let path = path.clone();
+            async move { FileLock::acquire(&path).await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!second.is_finished());
+
+        drop(first);
+        let second = tokio::time::timeout(Duration::from_secs(5), second)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        drop(second);
+        assert!(FileLock::try_acquire(&path).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn waiters_move_on_to_a_new_lock_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.lock");
+        let first = FileLock::acquire(&path).await.unwrap();
+
+        let second = tokio::spawn({
+            let path = path.clone();
+            async move { FileLock::acquire(&path).await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        first.remove().unwrap();
+        let second = tokio::time::timeout(Duration::from_secs(5), second)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // The waiter holds the lock file now at `path`, so others wait for it.
+        assert!(path.exists());
+        assert!(FileLock::try_acquire(&path).unwrap().is_none());
+        drop(second);
+    }
+}
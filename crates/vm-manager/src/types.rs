@@ -31,6 +31,9 @@ pub struct VmSpec {
     pub network: NetworkConfig,
     pub cloud_init: Option<CloudInitConfig>,
     pub ssh: Option<SshConfig>,
+    pub sandbox: SandboxConfig,
+    pub qemu: QemuExtra,
+    pub tpm: Option<TpmModel>,
 }
 
 /// Network configuration for a VM.
@@ -59,6 +62,83 @@ pub struct CloudInitConfig {
     pub hostname: Option<String>,
 }
 
+/// Hardening options for the hypervisor process.
+///
+/// Unset fields fall back to the global configuration and then to backend defaults, which enable
+/// everything the host and the QEMU build support.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SandboxConfig {
+    /// Confine QEMU with its seccomp filter (`-sandbox on`).
+    #[serde(default)]
+    pub seccomp: Option<bool>,
+    /// Drop privileges to this user once QEMU has initialised (requires root).
+    #[serde(default)]
+    pub user: Option<String>,
+    /// chroot QEMU into the VM work directory once it has initialised (requires root).
+    #[serde(default)]
+    pub chroot: Option<bool>,
+}
+
+impl SandboxConfig {
+    /// Fill unset fields from `fallback`.
+    pub fn or(&self, fallback: &SandboxConfig) -> SandboxConfig {
+        SandboxConfig {
+            seccomp: self.seccomp.or(fallback.seccomp),
+            user: self.user.clone().or_else(|| fallback.user.clone()),
+            chroot: self.chroot.or(fallback.chroot),
+        }
+    }
+}
+
+/// Emulated TPM 2.0 interface exposed to the guest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TpmModel {
+    /// Command Response Buffer interface (`tpm-crb`), the default on q35.
+    #[default]
+    Crb,
+    /// TPM Interface Specification (`tpm-tis`), for guests without CRB drivers.
+    Tis,
+}
+
+impl std::fmt::Display for TpmModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Crb => write!(f, "crb"),
+            Self::Tis => write!(f, "tis"),
+        }
+    }
+}
+
+/// Additional QEMU arguments from the VMFile `qemu` block, appended verbatim after the ones
+/// vmctl generates.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QemuExtra {
+    /// Raw command-line arguments.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Values for `-device`.
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// Values for `-global`.
+    #[serde(default)]
+    pub globals: Vec<String>,
+}
+
+impl QemuExtra {
+    /// Flatten into a QEMU argument list.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut out = self.args.clone();
+        for device in &self.devices {
+            out.extend(["-device".into(), device.clone()]);
+        }
+        for global in &self.globals {
+            out.extend(["-global".into(), global.clone()]);
+        }
+        out
+    }
+}
+
 /// SSH connection configuration.
 #[derive(Debug, Clone)]
 pub struct SshConfig {
@@ -113,6 +193,21 @@ pub struct VmHandle {
     /// MAC address assigned to this VM.
     #[serde(default)]
     pub mac_addr: Option<String>,
+    /// Transient systemd unit supervising the QEMU process, when started via `systemd-run`.
+    #[serde(default)]
+    pub systemd_unit: Option<String>,
+    /// Per-VM sandbox overrides from the VMFile.
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    /// Extra QEMU arguments from the VMFile.
+    #[serde(default)]
+    pub qemu: QemuExtra,
+    /// Emulated TPM backed by a per-VM swtpm process.
+    #[serde(default)]
+    pub tpm: Option<TpmModel>,
+    /// Address of the VM's propolis-server, e.g. "127.0.0.1:12401" (Propolis).
+    #[serde(default)]
+    pub propolis_addr: Option<String>,
 }
 
 fn default_vcpus() -> u16 {
@@ -4,10 +4,12 @@ use std::path::{Path, PathBuf};
 use kdl::KdlDocument;
 use tracing::info;
 
-use crate::cloudinit::build_cloud_config;
+use crate::cloudinit::{CloudInitQuirks, build_cloud_config_with};
 use crate::error::{Result, VmError};
-use crate::image::ImageManager;
-use crate::types::{CloudInitConfig, NetworkConfig, SshConfig, VmSpec};
+use crate::image::{Catalog, CatalogEntry, Checksum, ImageManager, RefreshPolicy};
+use crate::types::{
+    CloudInitConfig, NetworkConfig, QemuExtra, SandboxConfig, SshConfig, TpmModel, VmSpec,
+};
 
 // ---------------------------------------------------------------------------
 // Types
@@ -27,6 +29,10 @@ pub struct VmFile {
 pub struct VmDef {
     pub name: String,
     pub image: ImageSource,
+    /// Expected checksum of the `image-url` download.
+    pub image_checksum: Option<Checksum>,
+    /// When the cached `image-url` download is checked for a newer version.
+    pub image_refresh: RefreshPolicy,
     pub vcpus: u16,
     pub memory_mb: u64,
     pub disk_gb: Option<u32>,
@@ -34,6 +40,29 @@ pub struct VmDef {
     pub cloud_init: Option<CloudInitDef>,
     pub ssh: Option<SshDef>,
     pub provisions: Vec<ProvisionDef>,
+    pub sandbox: SandboxConfig,
+    pub qemu: QemuExtra,
+    pub tpm: Option<TpmModel>,
+}
+
+impl VmDef {
+    /// User to log in as: the one in the `ssh` block, or the one the catalog recommends for
+    /// the image.
+    pub fn ssh_user(&self) -> Option<&str> {
+        match (&self.ssh, &self.image) {
+            (Some(ssh), _) => Some(&ssh.user),
+            (None, ImageSource::Catalog(entry)) => entry.ssh_user.as_deref(),
+            (None, _) => None,
+        }
+    }
+
+    /// How cloud-init has to set up the user on the image.
+    fn cloud_init_quirks(&self) -> CloudInitQuirks {
+        match &self.image {
+            ImageSource::Catalog(entry) => entry.cloud_init.clone(),
+            _ => CloudInitQuirks::default(),
+        }
+    }
 }
 
 /// Where to source the VM image from.
@@ -42,6 +71,9 @@ pub enum ImageSource {
     Local(String),
     Url(String),
     Oci(String),
+    /// An alias like `ubuntu:24.04` from the [image catalog](crate::image::catalog), looked up
+    /// for this host's architecture.
+    Catalog(CatalogEntry),
 }
 
 /// Network mode as declared in the VMFile.
@@ -91,6 +123,33 @@ pub struct FileProvision {
     pub destination: String,
 }
 
+/// QEMU options generated by vmctl itself; passing them again via the `qemu` block would conflict.
+const MANAGED_QEMU_ARGS: &[&str] = &[
+    "-enable-kvm",
+    "-accel",
+    "-machine",
+    "-M",
+    "-cpu",
+    "-nodefaults",
+    "-smp",
+    "-m",
+    "-qmp",
+    "-serial",
+    "-vnc",
+    "-nographic",
+    "-daemonize",
+    "-pidfile",
+    "-sandbox",
+    "-runas",
+    "-chroot",
+    "-run-with",
+    "-chardev",
+    "-drive",
+    "-netdev",
+    "-name",
+    "-monitor",
+];
+
 // ---------------------------------------------------------------------------
 // Path helpers
 // ---------------------------------------------------------------------------
@@ -173,6 +232,8 @@ pub fn parse(path: &Path) -> Result<VmFile> {
 
     let mut vms = Vec::new();
     let mut seen_names = HashSet::new();
+    // Loaded on first use, so VMFiles without catalog images don't depend on it.
+    let mut catalog = None;
 
     for node in doc.nodes() {
         if node.name().to_string() != "vm" {
@@ -203,7 +264,7 @@ pub fn parse(path: &Path) -> Result<VmFile> {
             hint: "add configuration inside braces: vm \"name\" { ... }".into(),
         })?;
 
-        let vm_def = parse_vm_def(&name, children)?;
+        let vm_def = parse_vm_def(&name, children, &base_dir, &mut catalog)?;
         vms.push(vm_def);
     }
 
@@ -217,7 +278,12 @@ pub fn parse(path: &Path) -> Result<VmFile> {
     Ok(VmFile { base_dir, vms })
 }
 
-fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
+fn parse_vm_def(
+    name: &str,
+    doc: &KdlDocument,
+    base_dir: &Path,
+    catalog: &mut Option<Catalog>,
+) -> Result<VmDef> {
     // Image: local or URL
     let local_image = doc
         .get_arg("image")
@@ -229,6 +295,16 @@ fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
         .map(String::from);
 
     let image = match (local_image, url_image) {
+        // An existing file wins over a catalog alias of the same spelling, e.g. "golden:2024.qcow2".
+        (Some(alias), None)
+            if Catalog::is_alias(&alias) && !resolve_path(&alias, base_dir).exists() =>
+        {
+            if catalog.is_none() {
+                *catalog = Some(Catalog::load()?);
+            }
+            let catalog = catalog.as_ref().expect("catalog was just loaded");
+            ImageSource::Catalog(catalog.resolve(&alias)?.clone())
+        }
         (Some(path), None) => ImageSource::Local(path),
         (None, Some(url)) if url.starts_with("oci://") => ImageSource::Oci(url[6..].to_string()),
         (None, Some(url)) => ImageSource::Url(url),
@@ -248,6 +324,53 @@ fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
         }
     };
 
+    let image_checksum = match doc.get_arg("image-checksum") {
+        None => None,
+        Some(v) => {
+            let raw = v.as_string().ok_or_else(|| VmError::VmFileValidation {
+                vm: name.into(),
+                detail: "image-checksum must be a string".into(),
+                hint: "use image-checksum \"sha256:<hex digest>\"".into(),
+            })?;
+            if !matches!(image, ImageSource::Url(_) | ImageSource::Catalog(_)) {
+                return Err(VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: "image-checksum is only checked for image-url downloads".into(),
+                    hint: "remove image-checksum or download the image with image-url".into(),
+                });
+            }
+            let checksum = raw.parse().map_err(|e| VmError::VmFileValidation {
+                vm: name.into(),
+                detail: format!("invalid image-checksum: {e}"),
+                hint: "use image-checksum \"sha256:<hex digest>\" or \"sha512:<hex digest>\"".into(),
+            })?;
+            Some(checksum)
+        }
+    };
+
+    let image_refresh = match doc.get_arg("image-refresh") {
+        None => RefreshPolicy::default(),
+        Some(v) => {
+            let raw = v.as_string().ok_or_else(|| VmError::VmFileValidation {
+                vm: name.into(),
+                detail: "image-refresh must be a string".into(),
+                hint: "use image-refresh \"daily\"".into(),
+            })?;
+            if !matches!(image, ImageSource::Url(_) | ImageSource::Catalog(_)) {
+                return Err(VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: "image-refresh only applies to image-url downloads".into(),
+                    hint: "remove image-refresh or download the image with image-url".into(),
+                });
+            }
+            raw.parse().map_err(|e| VmError::VmFileValidation {
+                vm: name.into(),
+                detail: format!("invalid image-refresh: {e}"),
+                hint: "use image-refresh \"never\", \"always\", \"daily\" or \"weekly\"".into(),
+            })?
+        }
+    };
+
     let vcpus = doc
         .get_arg("vcpus")
         .and_then(|v| v.as_integer())
@@ -265,6 +388,24 @@ fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
         .and_then(|v| v.as_integer())
         .map(|v| v as u32);
 
+    // TPM: `tpm #true` for the default interface, or the interface name
+    let tpm = match doc.get_arg("tpm") {
+        None => None,
+        Some(v) => match (v.as_bool(), v.as_string()) {
+            (Some(true), _) => Some(TpmModel::default()),
+            (Some(false), _) => None,
+            (_, Some("crb")) => Some(TpmModel::Crb),
+            (_, Some("tis")) => Some(TpmModel::Tis),
+            _ => {
+                return Err(VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: format!("invalid tpm value: {v}"),
+                    hint: "use tpm #true, tpm \"crb\" or tpm \"tis\"".into(),
+                });
+            }
+        },
+    };
+
     // Network
     let network = if let Some(net_node) = doc.get("network") {
         let net_type = net_node
@@ -326,9 +467,14 @@ fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
             detail: "ssh block must have a body".into(),
             hint: "add at least a user: ssh { user \"vm\" }".into(),
         })?;
+        let default_user = match &image {
+            ImageSource::Catalog(entry) => entry.ssh_user.as_deref(),
+            _ => None,
+        };
         let user = ssh_doc
             .get_arg("user")
             .and_then(|v| v.as_string())
+            .or(default_user)
             .unwrap_or("vm")
             .to_string();
         let private_key = ssh_doc
@@ -340,6 +486,32 @@ fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
         None
     };
 
+    // Sandbox
+    let sandbox = match doc.get("sandbox") {
+        Some(node) => {
+            let sb_doc = node.children().ok_or_else(|| VmError::VmFileValidation {
+                vm: name.into(),
+                detail: "sandbox block must have a body".into(),
+                hint: "add options inside: sandbox { seccomp #true }".into(),
+            })?;
+            parse_sandbox(name, sb_doc)?
+        }
+        None => SandboxConfig::default(),
+    };
+
+    // QEMU passthrough
+    let qemu = match doc.get("qemu") {
+        Some(node) => {
+            let q_doc = node.children().ok_or_else(|| VmError::VmFileValidation {
+                vm: name.into(),
+                detail: "qemu block must have a body".into(),
+                hint: "add arguments inside: qemu { device \"virtio-gpu-pci\" }".into(),
+            })?;
+            parse_qemu_extra(name, q_doc)?
+        }
+        None => QemuExtra::default(),
+    };
+
     // Provisions
     let mut provisions = Vec::new();
     for node in doc.nodes() {
@@ -419,6 +591,8 @@ fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
     Ok(VmDef {
         name: name.to_string(),
         image,
+        image_checksum,
+        image_refresh,
         vcpus,
         memory_mb,
         disk_gb,
@@ -426,6 +600,90 @@ fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
         cloud_init,
         ssh,
         provisions,
+        sandbox,
+        qemu,
+        tpm,
+    })
+}
+
+/// Parse the body of a `qemu { ... }` block and reject options vmctl manages itself.
+fn parse_qemu_extra(name: &str, doc: &KdlDocument) -> Result<QemuExtra> {
+    let mut extra = QemuExtra::default();
+
+    for node in doc.nodes() {
+        let key = node.name().to_string();
+        let values: Vec<String> = node
+            .entries()
+            .iter()
+            .filter(|e| e.name().is_none())
+            .filter_map(|e| e.value().as_string())
+            .map(String::from)
+            .collect();
+        if values.is_empty() {
+            return Err(VmError::VmFileValidation {
+                vm: name.into(),
+                detail: format!("qemu {key} requires a string argument"),
+                hint: "quote the value: device \"virtio-gpu-pci\"".into(),
+            });
+        }
+
+        // QEMU accepts both -opt and --opt
+        if let Some(flag) = values
+            .iter()
+            .map(|v| v.strip_prefix('-').filter(|r| r.starts_with('-')).unwrap_or(v))
+            .find(|v| MANAGED_QEMU_ARGS.contains(v))
+        {
+            return Err(VmError::VmFileValidation {
+                vm: name.into(),
+                detail: format!("qemu {key} {flag} conflicts with an option managed by vmctl"),
+                hint: "use the corresponding VMFile setting (vcpus, memory, disk, network, sandbox, ...) instead".into(),
+            });
+        }
+
+        match key.as_str() {
+            "arg" => extra.args.extend(values),
+            "device" => extra.devices.extend(values),
+            "global" => extra.globals.extend(values),
+            other => {
+                return Err(VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: format!("unknown qemu option: {other}"),
+                    hint: "use \"arg\", \"device\", or \"global\"".into(),
+                });
+            }
+        }
+    }
+
+    Ok(extra)
+}
+
+/// Parse the body of a `sandbox { ... }` block. Shared with the global config file.
+pub(crate) fn parse_sandbox(name: &str, doc: &KdlDocument) -> Result<SandboxConfig> {
+    let flag = |key: &str| -> Result<Option<bool>> {
+        match doc.get_arg(key) {
+            None => Ok(None),
+            Some(v) => v
+                .as_bool()
+                .map(Some)
+                .ok_or_else(|| VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: format!("sandbox {key} must be a boolean"),
+                    hint: format!("use: {key} #true or {key} #false"),
+                }),
+        }
+    };
+
+    let seccomp = flag("seccomp")?;
+    let chroot = flag("chroot")?;
+    let user = doc
+        .get_arg("user")
+        .and_then(|v| v.as_string())
+        .map(String::from);
+
+    Ok(SandboxConfig {
+        seccomp,
+        user,
+        chroot,
     })
 }
 
@@ -450,13 +708,23 @@ pub async fn resolve(def: &VmDef, base_dir: &Path) -> Result<VmSpec> {
         }
         ImageSource::Url(url) => {
             info!(vm = %def.name, url = %url, "downloading image");
-            let mgr = ImageManager::new();
-            mgr.pull(url, Some(&def.name)).await?
+            let mgr = ImageManager::new().with_refresh(def.image_refresh);
+            mgr.pull_verified(url, Some(&def.name), def.image_checksum.as_ref())
+                .await?
         }
         ImageSource::Oci(oci_ref) => {
             let mgr = ImageManager::new();
             mgr.pull_oci(oci_ref, Some(&def.name)).await?
         }
+        ImageSource::Catalog(entry) => {
+            info!(vm = %def.name, image = %entry.alias, url = %entry.url, "downloading catalog image");
+            let mut mgr = ImageManager::new().with_refresh(def.image_refresh);
+            if let Some(checksum_url) = &entry.checksum_url {
+                mgr = mgr.with_checksum_url(checksum_url);
+            }
+            mgr.pull_verified(&entry.url, Some(&def.name), def.image_checksum.as_ref())
+                .await?
+        }
     };
 
     // Network
@@ -480,6 +748,9 @@ pub async fn resolve(def: &VmDef, base_dir: &Path) -> Result<VmSpec> {
         network,
         cloud_init,
         ssh,
+        sandbox: def.sandbox.clone(),
+        qemu: def.qemu.clone(),
+        tpm: def.tpm,
     })
 }
 
@@ -517,7 +788,8 @@ async fn resolve_cloud_init_and_ssh(
     def: &VmDef,
     base_dir: &Path,
 ) -> Result<(Option<CloudInitConfig>, Option<SshConfig>)> {
-    let ssh_user = def.ssh.as_ref().map(|s| s.user.as_str()).unwrap_or("vm");
+    let ssh_user = def.ssh_user().unwrap_or("vm");
+    let quirks = def.cloud_init_quirks();
     let hostname = def
         .cloud_init
         .as_ref()
@@ -560,7 +832,7 @@ async fn resolve_cloud_init_and_ssh(
                         hint: "check the ssh-key path".into(),
                     })?;
             let (user_data, _meta) =
-                build_cloud_config(ssh_user, pubkey.trim(), &def.name, hostname);
+                build_cloud_config_with(ssh_user, pubkey.trim(), &def.name, hostname, &quirks);
             let cloud_init = Some(CloudInitConfig {
                 user_data,
                 instance_id: Some(def.name.clone()),
@@ -577,7 +849,7 @@ async fn resolve_cloud_init_and_ssh(
         let (pub_openssh, priv_pem) = generate_ssh_keypair(&def.name)?;
 
         let (user_data, _meta) =
-            build_cloud_config(ssh_user, &pub_openssh, &def.name, hostname);
+            build_cloud_config_with(ssh_user, &pub_openssh, &def.name, hostname, &quirks);
         let cloud_init = Some(CloudInitConfig {
             user_data,
             instance_id: Some(def.name.clone()),
@@ -641,6 +913,125 @@ vm "test" {
         assert!(vm.cloud_init.is_none());
         assert!(vm.ssh.is_none());
         assert!(vm.provisions.is_empty());
+        assert_eq!(vm.sandbox, SandboxConfig::default());
+        assert!(vm.tpm.is_none());
+    }
+
+    #[test]
+    fn parse_sandbox_block() {
+        let kdl = r#"
+vm "locked" {
+    image "/tmp/test.qcow2"
+    sandbox {
+        seccomp #false
+        user "qemu"
+        chroot #true
+    }
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vmfile = parse(tmp.path()).unwrap();
+        let sandbox = &vmfile.vms[0].sandbox;
+        assert_eq!(sandbox.seccomp, Some(false));
+        assert_eq!(sandbox.user.as_deref(), Some("qemu"));
+        assert_eq!(sandbox.chroot, Some(true));
+    }
+
+    #[test]
+    fn parse_tpm() {
+        for (value, expected) in [
+            ("#true", Some(TpmModel::Crb)),
+            ("#false", None),
+            ("\"tis\"", Some(TpmModel::Tis)),
+        ] {
+            let kdl = format!("vm \"t\" {{\n    image \"/tmp/test.qcow2\"\n    tpm {value}\n}}\n");
+            let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+            std::fs::write(tmp.path(), kdl).unwrap();
+
+            let vmfile = parse(tmp.path()).unwrap();
+            assert_eq!(vmfile.vms[0].tpm, expected, "tpm {value}");
+        }
+    }
+
+    #[test]
+    fn parse_qemu_block() {
+        let kdl = r#"
+vm "gpu" {
+    image "/tmp/test.qcow2"
+    qemu {
+        arg "-device" "intel-iommu"
+        device "virtio-gpu-pci"
+        global "ICH9-LPC.disable_s3=1"
+    }
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vmfile = parse(tmp.path()).unwrap();
+        let qemu = &vmfile.vms[0].qemu;
+        assert_eq!(qemu.args, ["-device", "intel-iommu"]);
+        assert_eq!(
+            qemu.to_args(),
+            [
+                "-device",
+                "intel-iommu",
+                "-device",
+                "virtio-gpu-pci",
+                "-global",
+                "ICH9-LPC.disable_s3=1"
+            ]
+        );
+    }
+
+    #[test]
+    fn error_qemu_managed_arg() {
+        for line in [
+            r#"arg "-qmp" "unix:/tmp/x.sock""#,
+            r#"arg "--daemonize""#,
+            r#"arg "-accel" "tcg""#,
+            r#"arg "-nographic""#,
+            r#"arg "-drive" "file=/tmp/other.qcow2""#,
+            r#"arg "-netdev" "user,id=net1""#,
+            r#"arg "-chardev" "socket,id=c0,path=/tmp/c.sock""#,
+            r#"device "-monitor" "stdio""#,
+            r#"global "-name" "other""#,
+        ] {
+            let kdl = format!(
+                r#"
+vm "bad" {{
+    image "/tmp/test.qcow2"
+    qemu {{
+        {line}
+    }}
+}}
+"#
+            );
+            let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+            std::fs::write(tmp.path(), kdl).unwrap();
+
+            let err = parse(tmp.path()).unwrap_err();
+            assert!(err.to_string().contains("managed by vmctl"), "{line}: {err}");
+        }
+    }
+
+    #[test]
+    fn error_sandbox_non_boolean() {
+        let kdl = r#"
+vm "locked" {
+    image "/tmp/test.qcow2"
+    sandbox {
+        seccomp "yes"
+    }
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let err = parse(tmp.path()).unwrap_err();
+        assert!(err.to_string().contains("must be a boolean"), "got: {err}");
     }
 
     #[test]
@@ -738,6 +1129,52 @@ vm "cloud" {
         );
     }
 
+    #[test]
+    fn parse_image_checksum() {
+        let digest = "a".repeat(64);
+        let kdl = format!(
+            "vm \"cloud\" {{\n    image-url \"https://example.com/image.qcow2\"\n    image-checksum \"SHA256:{digest}\"\n}}\n"
+        );
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vmfile = parse(tmp.path()).unwrap();
+        let checksum = vmfile.vms[0].image_checksum.as_ref().unwrap();
+        assert_eq!(checksum.to_string(), format!("sha256:{digest}"));
+
+        for (image, checksum, expected) in [
+            ("image-url \"https://example.com/a.img\"", "md5:abc", "unsupported checksum"),
+            ("image-url \"https://example.com/a.img\"", "sha256:abc", "64 hex"),
+            ("image \"/tmp/a.img\"", "sha256:abc", "only checked for image-url"),
+        ] {
+            let kdl = format!("vm \"t\" {{\n    {image}\n    image-checksum \"{checksum}\"\n}}\n");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let err = parse(tmp.path()).unwrap_err();
+            assert!(err.to_string().contains(expected), "got: {err}");
+        }
+    }
+
+    #[test]
+    fn parse_image_refresh() {
+        let kdl = "vm \"cloud\" {\n    image-url \"https://example.com/image.qcow2\"\n    image-refresh \"daily\"\n}\n";
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vmfile = parse(tmp.path()).unwrap();
+        assert_eq!(vmfile.vms[0].image_refresh, RefreshPolicy::Daily);
+
+        for (image, refresh, expected) in [
+            ("image-url \"https://example.com/a.img\"", "hourly", "unknown refresh policy"),
+            ("image \"/tmp/a.img\"", "daily", "only applies to image-url"),
+            ("image-url \"oci://ghcr.io/a/b:latest\"", "daily", "only applies to image-url"),
+        ] {
+            let kdl = format!("vm \"t\" {{\n    {image}\n    image-refresh \"{refresh}\"\n}}\n");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let err = parse(tmp.path()).unwrap_err();
+            assert!(err.to_string().contains(expected), "got: {err}");
+        }
+    }
+
     #[test]
     fn error_no_image() {
         let kdl = r#"
@@ -802,6 +1239,108 @@ vm "ci" {
         );
     }
 
+    #[tokio::test]
+    async fn parse_catalog_image() {
+        let kdl = r#"
+vm "web" {
+    image "debian:12"
+    cloud-init {}
+}
+vm "db" {
+    image "debian:12"
+    ssh {
+        private-key "~/.ssh/id_ed25519"
+    }
+}
+vm "explicit" {
+    image "debian:12"
+    ssh {
+        user "admin"
+    }
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vmfile = parse(tmp.path()).unwrap();
+        let ImageSource::Catalog(ref entry) = vmfile.vms[0].image else {
+            panic!("expected a catalog image, got {:?}", vmfile.vms[0].image);
+        };
+        assert_eq!(entry.alias, "debian:12");
+        assert!(entry.url.contains("debian-12-genericcloud"));
+        assert_eq!(vmfile.vms[0].ssh_user(), Some("debian"));
+        assert_eq!(vmfile.vms[1].ssh.as_ref().unwrap().user, "debian");
+        assert_eq!(vmfile.vms[2].ssh_user(), Some("admin"));
+
+        let (cloud_init, ssh) = resolve_cloud_init_and_ssh(&vmfile.vms[0], &vmfile.base_dir)
+            .await
+            .unwrap();
+        let user_data = String::from_utf8(cloud_init.unwrap().user_data).unwrap();
+        assert!(user_data.contains("- name: debian"), "{user_data}");
+        assert_eq!(ssh.unwrap().user, "debian");
+
+        std::fs::write(tmp.path(), "vm \"t\" {\n    image \"nope:1\"\n}\n").unwrap();
+        let err = parse(tmp.path()).unwrap_err();
+        assert!(
+            matches!(err, VmError::UnknownImageAlias { ref alias, .. } if alias == "nope:1"),
+            "got: {err}"
+        );
+    }
+
+    #[test]
+    fn local_image_with_colon_is_not_an_alias() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("golden:2024.qcow2"), b"").unwrap();
+        let path = dir.path().join("VMFile.kdl");
+        std::fs::write(&path, "vm \"t\" {\n    image \"golden:2024.qcow2\"\n}\n").unwrap();
+
+        let vmfile = parse(&path).unwrap();
+        assert!(
+            matches!(vmfile.vms[0].image, ImageSource::Local(ref p) if p == "golden:2024.qcow2"),
+            "got: {:?}",
+            vmfile.vms[0].image
+        );
+    }
+
+    #[tokio::test]
+    async fn catalog_quirks_shape_cloud_config() {
+        let mut def = parse_vm_def(
+            "bsd",
+            &"image \"/tmp/a.img\"\ncloud-init {}\n".parse().unwrap(),
+            Path::new("."),
+            &mut None,
+        )
+        .unwrap();
+        def.image = ImageSource::Catalog(CatalogEntry {
+            alias: "freebsd:14".into(),
+            arch: None,
+            description: None,
+            url: "https://example.com/freebsd.qcow2.xz".into(),
+            checksum_url: None,
+            ssh_user: Some("freebsd".into()),
+            cloud_init: CloudInitQuirks {
+                shell: Some("/bin/sh".into()),
+                groups: Some(vec!["wheel".into()]),
+            },
+        });
+
+        let (cloud_init, _) = resolve_cloud_init_and_ssh(&def, Path::new("."))
+            .await
+            .unwrap();
+        let user_data = String::from_utf8(cloud_init.unwrap().user_data).unwrap();
+        assert!(user_data.contains("- name: freebsd"), "{user_data}");
+        assert!(user_data.contains("groups: [wheel]"), "{user_data}");
+        assert!(user_data.contains("shell: /bin/sh"), "{user_data}");
+
+        def.image = ImageSource::Local("/tmp/a.img".into());
+        let (cloud_init, _) = resolve_cloud_init_and_ssh(&def, Path::new("."))
+            .await
+            .unwrap();
+        let user_data = String::from_utf8(cloud_init.unwrap().user_data).unwrap();
+        assert!(user_data.contains("groups: [sudo]"), "{user_data}");
+        assert!(user_data.contains("shell: /bin/bash"), "{user_data}");
+    }
+
     #[test]
     fn expand_tilde_works() {
         let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/root"));
@@ -1,7 +1,13 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
 use clap::Args;
 use miette::{IntoDiagnostic, Result};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use vm_manager::{ConsoleEndpoint, Hypervisor, RouterHypervisor};
+use tokio::net::UnixStream;
+use vm_manager::console::{self, AttachMode, Broker};
+use vm_manager::{ConsoleEndpoint, Hypervisor, RouterHypervisor, VmHandle};
 
 use super::state;
 
@@ -9,6 +15,26 @@ use super::state;
 pub struct ConsoleArgs {
     /// VM name
     name: String,
+
+    /// Watch the console without being able to type into it
+    #[arg(long)]
+    read_only: bool,
+}
+
+/// Arguments of the hidden `console-broker` command that `vmctl console` spawns.
+#[derive(Args)]
+pub struct BrokerArgs {
+    /// QEMU console socket
+    #[arg(long)]
+    upstream: PathBuf,
+
+    /// Socket to accept console clients on
+    #[arg(long)]
+    listen: PathBuf,
+
+    /// Console log used to pre-fill the scrollback
+    #[arg(long)]
+    seed: Option<PathBuf>,
 }
 
 pub async fn run(args: ConsoleArgs) -> Result<()> {
@@ -19,17 +45,18 @@ pub async fn run(args: ConsoleArgs) -> Result<()> {
 
     let hv = RouterHypervisor::new(None, None);
     let endpoint = hv.console_endpoint(handle).into_diagnostic()?;
+    let mode = if args.read_only {
+        AttachMode::ReadOnly
+    } else {
+        AttachMode::ReadWrite
+    };
 
     match endpoint {
         ConsoleEndpoint::UnixSocket(path) => {
-            println!(
-                "Connecting to console at {} (Ctrl+] to detach)...",
-                path.display()
-            );
-            let mut sock = tokio::net::UnixStream::connect(&path)
-                .await
-                .into_diagnostic()?;
+            let mut sock = attach_via_broker(handle, &path, mode).await?;
+            announce(mode);
 
+            let raw = RawTerminal::enter();
             let mut stdin = tokio::io::stdin();
             let mut stdout = tokio::io::stdout();
 
@@ -43,11 +70,12 @@ pub async fn run(args: ConsoleArgs) -> Result<()> {
                     if n == 0 {
                         break;
                     }
-                    // Check for Ctrl+] (0x1d) to detach
-                    if buf[..n].contains(&0x1d) {
+                    if buf[..n].contains(&console::DETACH_KEY) {
                         break;
                     }
-                    write_half.write_all(&buf[..n]).await?;
+                    if mode == AttachMode::ReadWrite {
+                        write_half.write_all(&buf[..n]).await?;
+                    }
                 }
                 Ok::<_, std::io::Error>(())
             };
@@ -70,11 +98,18 @@ pub async fn run(args: ConsoleArgs) -> Result<()> {
                 r = from_sock => { let _ = r; }
             }
 
+            drop(raw);
             println!("\nDetached from console.");
         }
         ConsoleEndpoint::WebSocket(url) => {
-            println!("Console available at WebSocket: {url}");
-            println!("Use a WebSocket client to connect.");
+            announce(mode);
+            let raw = RawTerminal::enter();
+            let result =
+                console::websocket::bridge(&url, mode, tokio::io::stdin(), tokio::io::stdout())
+                    .await;
+            drop(raw);
+            result.map_err(miette::Report::new)?;
+            println!("\nDetached from console.");
         }
         ConsoleEndpoint::None => {
             println!("No console available for this backend.");
@@ -83,3 +118,116 @@ pub async fn run(args: ConsoleArgs) -> Result<()> {
 
     Ok(())
 }
+
+fn announce(mode: AttachMode) {
+    match mode {
+        AttachMode::ReadOnly => println!("Watching console read-only (Ctrl+] to detach)..."),
+        AttachMode::ReadWrite => println!("Connected to console (Ctrl+] to detach)..."),
+    }
+}
+
+/// Puts the controlling terminal into raw mode until dropped, so keys (including Ctrl+] and
+/// Ctrl+C) reach the guest unprocessed. Does nothing when stdin is not a terminal.
+struct RawTerminal {
+    saved: Option<libc::termios>,
+}
+
+impl RawTerminal {
+    fn enter() -> Self {
+        // SAFETY: termios calls on fd 0 with a properly sized, initialized struct.
+        unsafe {
+            let mut saved: libc::termios = std::mem::zeroed();
+            if libc::isatty(libc::STDIN_FILENO) != 1
+                || libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0
+            {
+                return Self { saved: None };
+            }
+            // The equivalent of cfmakeraw(3), which not every libc provides.
+            let mut raw = saved;
+            raw.c_iflag &= !(libc::IGNBRK
+                | libc::BRKINT
+                | libc::PARMRK
+                | libc::ISTRIP
+                | libc::INLCR
+                | libc::IGNCR
+                | libc::ICRNL
+                | libc::IXON);
+            raw.c_oflag &= !libc::OPOST;
+            raw.c_lflag &= !(libc::ECHO | libc::ECHONL | libc::ICANON | libc::ISIG | libc::IEXTEN);
+            raw.c_cflag &= !(libc::CSIZE | libc::PARENB);
+            raw.c_cflag |= libc::CS8;
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Self { saved: None };
+            }
+            Self { saved: Some(saved) }
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(ref saved) = self.saved {
+            // SAFETY: restores the attributes read in `enter`.
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved);
+            }
+        }
+    }
+}
+
+/// Attach through the VM's console broker, starting the broker if it is not running yet.
+async fn attach_via_broker(
+    handle: &VmHandle,
+    upstream: &Path,
+    mode: AttachMode,
+) -> Result<UnixStream> {
+    let socket = console::broker_socket(handle);
+    if let Ok(sock) = console::broker::attach(&socket, mode).await {
+        return Ok(sock);
+    }
+
+    // The broker outlives this process and exits by itself when the VM stops.
+    let log =
+        std::fs::File::create(handle.work_dir.join("console-broker.log")).into_diagnostic()?;
+    let mut cmd = std::process::Command::new(std::env::current_exe().into_diagnostic()?);
+    cmd.arg("console-broker")
+        .arg("--upstream")
+        .arg(upstream)
+        .arg("--listen")
+        .arg(&socket)
+        .arg("--seed")
+        .arg(handle.work_dir.join("console.log"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(log);
+    {
+        use std::os::unix::process::CommandExt;
+        // Keep the broker out of our process group so Ctrl+C on this terminal doesn't end it.
+        cmd.process_group(0);
+    }
+    cmd.spawn().into_diagnostic()?;
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        match console::broker::attach(&socket, mode).await {
+            Ok(sock) => return Ok(sock),
+            Err(e) if tokio::time::Instant::now() >= deadline => {
+                return Err(miette::Report::new(e).wrap_err(format!(
+                    "console broker did not start, see {}",
+                    handle.work_dir.join("console-broker.log").display()
+                )));
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+}
+
+pub async fn run_broker(args: BrokerArgs) -> Result<()> {
+    let mut broker = Broker::new(args.upstream, args.listen);
+    if let Some(seed) = args.seed {
+        broker = broker.seed_from(seed);
+    }
+    broker.run().await.into_diagnostic()
+}
@@ -3,6 +3,7 @@ use std::path::PathBuf;
 use clap::Args;
 use miette::{IntoDiagnostic, Result};
 use tracing::info;
+use vm_manager::image::{Catalog, Checksum};
 use vm_manager::{CloudInitConfig, Hypervisor, NetworkConfig, RouterHypervisor, SshConfig, VmSpec};
 
 use super::state;
@@ -13,7 +14,7 @@ pub struct CreateArgs {
     #[arg(long)]
     name: String,
 
-    /// Path to a local disk image
+    /// Path to a local disk image, or a catalog alias like ubuntu:24.04 (see `vmctl image search`)
     #[arg(long)]
     image: Option<PathBuf>,
 
@@ -21,6 +22,10 @@ pub struct CreateArgs {
     #[arg(long)]
     image_url: Option<String>,
 
+    /// Expected checksum of the --image-url download, e.g. sha256:<hex>
+    #[arg(long, requires = "image_url")]
+    checksum: Option<Checksum>,
+
     /// Number of vCPUs
     #[arg(long, default_value = "1")]
     vcpus: u16,
@@ -82,7 +87,28 @@ pub async fn run(args: CreateArgs) -> Result<()> {
     }
 
     // Resolve image
-    let image_path = if let Some(ref path) = args.image {
+    let alias = args
+        .image
+        .as_deref()
+        .filter(|path| !path.exists())
+        .and_then(|path| path.to_str())
+        .filter(|path| Catalog::is_alias(path));
+    let catalog_entry = match alias {
+        Some(alias) => {
+            let catalog = Catalog::load().map_err(miette::Report::new)?;
+            Some(catalog.resolve(alias).map_err(miette::Report::new)?.clone())
+        }
+        None => None,
+    };
+    let image_path = if let Some(ref entry) = catalog_entry {
+        let mut mgr = vm_manager::image::ImageManager::new();
+        if let Some(ref checksum_url) = entry.checksum_url {
+            mgr = mgr.with_checksum_url(checksum_url);
+        }
+        mgr.pull_verified(&entry.url, Some(&args.name), None)
+            .await
+            .map_err(miette::Report::new)?
+    } else if let Some(ref path) = args.image {
         if !path.exists() {
             miette::bail!(
                 severity = miette::Severity::Error,
@@ -95,16 +121,27 @@ pub async fn run(args: CreateArgs) -> Result<()> {
         path.clone()
     } else if let Some(ref url) = args.image_url {
         let mgr = vm_manager::image::ImageManager::new();
-        mgr.pull(url, Some(&args.name)).await.into_diagnostic()?
+        mgr.pull_verified(url, Some(&args.name), args.checksum.as_ref())
+            .await
+            .map_err(miette::Report::new)?
     } else {
         miette::bail!(
             severity = miette::Severity::Error,
             code = "vmctl::create::no_image",
-            help = "provide --image for a local file or --image-url to download one",
+            help = "provide --image with a file or catalog alias, or --image-url to download one",
             "either --image or --image-url must be specified"
         );
     };
 
+    // Catalog images recommend a user and how cloud-init has to create it
+    let user = catalog_entry
+        .as_ref()
+        .and_then(|entry| entry.ssh_user.clone())
+        .unwrap_or_else(|| "vm".to_string());
+    let quirks = catalog_entry
+        .map(|entry| entry.cloud_init)
+        .unwrap_or_default();
+
     // Build cloud-init config if user-data or ssh key provided
     let cloud_init = if args.cloud_init.is_some() || args.ssh_key.is_some() {
         let user_data = if let Some(ref path) = args.cloud_init {
@@ -113,11 +150,12 @@ pub async fn run(args: CreateArgs) -> Result<()> {
             let pubkey = tokio::fs::read_to_string(key_path)
                 .await
                 .into_diagnostic()?;
-            let (ud, _) = vm_manager::cloudinit::build_cloud_config(
-                "vm",
+            let (ud, _) = vm_manager::cloudinit::build_cloud_config_with(
+                &user,
                 pubkey.trim(),
                 &args.name,
                 &args.name,
+                &quirks,
             );
             ud
         } else {
@@ -135,7 +173,7 @@ pub async fn run(args: CreateArgs) -> Result<()> {
 
     // Build SSH config if key provided
     let ssh = args.ssh_key.as_ref().map(|key_path| SshConfig {
-        user: "vm".into(),
+        user: user.clone(),
         public_key: None,
         private_key_path: Some(key_path.clone()),
         private_key_pem: None,
@@ -157,6 +195,9 @@ pub async fn run(args: CreateArgs) -> Result<()> {
         network,
         cloud_init,
         ssh,
+        sandbox: Default::default(),
+        qemu: Default::default(),
+        tpm: None,
     };
 
     let hv = RouterHypervisor::new(None, None);
@@ -0,0 +1,50 @@
+use clap::Args;
+use miette::Result;
+use vm_manager::host::{self, ProbeOptions, Status};
+
+#[derive(Args)]
+pub struct DoctorArgs {
+    /// Bridge to check the QEMU bridge helper ACL for
+    #[arg(long, default_value = "br0")]
+    bridge: String,
+}
+
+pub async fn run(args: DoctorArgs) -> Result<()> {
+    let opts = ProbeOptions {
+        bridge: Some(args.bridge),
+        ..Default::default()
+    };
+    let report = host::probe_with(&opts).await;
+
+    for finding in &report.findings {
+        println!(
+            "[{:<4}] {:<14} {}",
+            finding.status, finding.check, finding.summary
+        );
+    }
+
+    let problems: Vec<_> = report.problems().cloned().collect();
+    for finding in &problems {
+        eprintln!("{:?}", miette::Report::new(finding.clone()));
+    }
+
+    match report.status() {
+        Status::Error => {
+            let count = problems
+                .iter()
+                .filter(|f| f.status == Status::Error)
+                .count();
+            Err(miette::miette!(
+                "{count} check(s) failed — vmctl will not be able to run VMs until they are fixed"
+            ))
+        }
+        Status::Warning => {
+            println!("\nCore checks passed; some optional features are unavailable.");
+            Ok(())
+        }
+        Status::Ok => {
+            println!("\nAll checks passed.");
+            Ok(())
+        }
+    }
+}
@@ -1,7 +1,15 @@
+use std::cmp::min;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::{Args, Subcommand};
 use miette::{IntoDiagnostic, Result};
+use vm_manager::image::{
+    Catalog, Checksum, Compression, ExportOptions, ImageFormat, PruneOptions, RefreshPolicy,
+};
+use vm_manager::{Hypervisor, RouterHypervisor, VmHandle, VmState};
+
+use super::state;
 
 #[derive(Args)]
 pub struct ImageCommand {
@@ -15,8 +23,16 @@ enum ImageAction {
     Pull(PullArgs),
     /// List cached images
     List,
+    /// Remove an image alias, and the image once no alias is left
+    Rm(RmArgs),
+    /// Remove cached images no VM uses
+    Prune(PruneArgs),
     /// Show image format and details
     Inspect(InspectArgs),
+    /// List images in the catalog, usable by alias like ubuntu:24.04
+    Search(SearchArgs),
+    /// Flatten a VM's disk into a standalone image in the cache
+    Export(ExportArgs),
 }
 
 #[derive(Args)]
@@ -24,9 +40,42 @@ struct PullArgs {
     /// URL to download
     url: String,
 
-    /// Name to save as in the cache
+    /// Alias to record the image under (default: the file name from the URL)
     #[arg(long)]
     name: Option<String>,
+
+    /// Expected checksum of the download, e.g. sha256:<hex>
+    #[arg(long)]
+    checksum: Option<Checksum>,
+
+    /// Check whether a cached image changed upstream and download it again if so
+    #[arg(long)]
+    refresh: bool,
+}
+
+#[derive(Args)]
+struct RmArgs {
+    /// Alias or digest (sha256:<hex>) of the image
+    name: String,
+}
+
+#[derive(Args)]
+struct PruneArgs {
+    /// Keep images an alias points at; only remove images no alias refers to
+    #[arg(long)]
+    keep_referenced: bool,
+
+    /// Remove least recently used images until the cache fits, e.g. 50G
+    #[arg(long, value_parser = super::parse_size)]
+    max_size: Option<u64>,
+
+    /// Only remove images unused for this long, e.g. 30d
+    #[arg(long, value_parser = super::parse_duration)]
+    older_than: Option<Duration>,
+
+    /// Show what would be removed without removing anything
+    #[arg(long)]
+    dry_run: bool,
 }
 
 #[derive(Args)]
@@ -35,54 +84,311 @@ struct InspectArgs {
     path: PathBuf,
 }
 
+#[derive(Args)]
+struct SearchArgs {
+    /// Only show images whose alias or description contains this
+    query: Option<String>,
+
+    /// Show images for this architecture instead of the host's, e.g. aarch64
+    #[arg(long)]
+    arch: Option<String>,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// VM name
+    vm: String,
+
+    /// Alias to record the image under (default: the VM name)
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Format of the exported image: qcow2 or raw
+    #[arg(long, default_value = "qcow2", value_parser = parse_export_format)]
+    format: ImageFormat,
+
+    /// Compression of the file written with --output: zstd, xz, gzip or bzip2
+    #[arg(long, requires = "output")]
+    compress: Option<Compression>,
+
+    /// Also write the image to this file
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Shut a running VM down instead of pausing it while its disk is read
+    #[arg(long)]
+    stop: bool,
+
+    /// Graceful shutdown timeout in seconds, with --stop
+    #[arg(long, default_value = "30")]
+    timeout: u64,
+}
+
 pub async fn run(args: ImageCommand) -> Result<()> {
     match args.action {
         ImageAction::Pull(pull) => {
-            let mgr = vm_manager::image::ImageManager::new();
+            let mut mgr = vm_manager::image::ImageManager::new();
+            if pull.refresh {
+                mgr = mgr.with_refresh(RefreshPolicy::Always);
+            }
             let path = mgr
-                .pull(&pull.url, pull.name.as_deref())
+                .pull_verified(&pull.url, pull.name.as_deref(), pull.checksum.as_ref())
                 .await
-                .into_diagnostic()?;
+                .map_err(miette::Report::new)?;
             println!("Image cached at: {}", path.display());
         }
         ImageAction::List => {
             let mgr = vm_manager::image::ImageManager::new();
-            let images = mgr.list().await.into_diagnostic()?;
+            let images = mgr.list().await.map_err(miette::Report::new)?;
 
             if images.is_empty() {
                 println!("No cached images.");
                 return Ok(());
             }
 
-            println!("{:<40} {:<12} PATH", "NAME", "SIZE");
-            println!("{}", "-".repeat(80));
+            println!(
+                "{:<24} {:<19} {:<10} {:<10} {:<10} SOURCE",
+                "ALIASES", "DIGEST", "SIZE", "PULLED", "LAST USED"
+            );
+            println!("{}", "-".repeat(100));
 
+            let now = vm_manager::image::cache::now();
             for img in images {
-                let size = if img.size_bytes >= 1_073_741_824 {
-                    format!("{:.1} GB", img.size_bytes as f64 / 1_073_741_824.0)
+                let aliases = if img.aliases.is_empty() {
+                    "-".to_string()
+                } else {
+                    img.aliases.join(",")
+                };
+                let digest = img.entry.digest.to_string();
+                println!(
+                    "{:<24} {:<19} {:<10} {:<10} {:<10} {}",
+                    aliases,
+                    &digest[..min(digest.len(), 19)],
+                    format_size(img.entry.size_bytes),
+                    format_age(now, img.entry.pulled_at),
+                    format_age(now, img.entry.last_used),
+                    img.entry.source
+                );
+            }
+        }
+        ImageAction::Rm(rm) => {
+            let vms = vms().await?;
+            let mgr = vm_manager::image::ImageManager::new();
+            match mgr
+                .remove(&rm.name, &vms)
+                .await
+                .map_err(miette::Report::new)?
+            {
+                Some(image) => println!(
+                    "Removed {} ({})",
+                    image.path.display(),
+                    format_size(image.size_bytes)
+                ),
+                None => println!("Removed alias '{}'", rm.name),
+            }
+        }
+        ImageAction::Prune(prune) => {
+            let vms = vms().await?;
+            let mgr = vm_manager::image::ImageManager::new();
+            let options = PruneOptions {
+                keep_referenced: prune.keep_referenced,
+                older_than: prune.older_than,
+                max_size: prune.max_size,
+                dry_run: prune.dry_run,
+            };
+            let removed = mgr
+                .prune(&options, &vms)
+                .await
+                .map_err(miette::Report::new)?;
+
+            let verb = if prune.dry_run {
+                "Would remove"
+            } else {
+                "Removed"
+            };
+            for image in &removed {
+                let name = if image.aliases.is_empty() {
+                    image.path.display().to_string()
                 } else {
-                    format!("{:.1} MB", img.size_bytes as f64 / 1_048_576.0)
+                    image.aliases.join(",")
                 };
-                println!("{:<40} {:<12} {}", img.name, size, img.path.display());
+                println!("{verb} {name} ({})", format_size(image.size_bytes));
             }
+            let freed: u64 = removed.iter().map(|i| i.size_bytes).sum();
+            println!("{verb} {} image(s), {}", removed.len(), format_size(freed));
         }
         ImageAction::Inspect(inspect) => {
             let fmt = vm_manager::image::detect_format(&inspect.path)
                 .await
                 .into_diagnostic()?;
-            println!("Format: {}", fmt);
-            println!("Path:   {}", inspect.path.display());
+            println!("Format:  {}", fmt);
+            println!("Path:    {}", inspect.path.display());
 
             if let Ok(meta) = tokio::fs::metadata(&inspect.path).await {
-                let size = meta.len();
-                if size >= 1_073_741_824 {
-                    println!("Size:   {:.1} GB", size as f64 / 1_073_741_824.0);
-                } else {
-                    println!("Size:   {:.1} MB", size as f64 / 1_048_576.0);
+                println!("Size:    {}", format_size(meta.len()));
+            }
+            let info = vm_manager::image::format::inspect(&inspect.path)
+                .await
+                .map_err(miette::Report::new)?;
+            if let Some(info) = info {
+                println!("Disk:    {}", format_size(info.virtual_size));
+                if let Some(backing) = info.backing_file {
+                    match info.backing_format {
+                        Some(format) => println!("Backing: {} ({format})", backing.display()),
+                        None => println!("Backing: {}", backing.display()),
+                    }
                 }
             }
         }
+        ImageAction::Search(search) => {
+            let catalog = Catalog::load().map_err(miette::Report::new)?;
+            let arch = search
+                .arch
+                .unwrap_or_else(|| std::env::consts::ARCH.to_string());
+            let query = search.query.map(|q| q.to_lowercase());
+            let mut entries: Vec<_> = catalog
+                .entries
+                .iter()
+                .filter(|e| e.arch.is_none() || e.arch.as_deref() == Some(arch.as_str()))
+                .filter(|e| {
+                    query.as_deref().is_none_or(|q| {
+                        e.alias.contains(q)
+                            || e.description
+                                .as_deref()
+                                .is_some_and(|d| d.to_lowercase().contains(q))
+                    })
+                })
+                .collect();
+            entries.sort_by(|a, b| a.alias.cmp(&b.alias));
+
+            if entries.is_empty() {
+                println!("No catalog images for {arch}.");
+                return Ok(());
+            }
+
+            println!(
+                "{:<16} {:<8} {:<10} DESCRIPTION",
+                "ALIAS", "ARCH", "SSH USER"
+            );
+            println!("{}", "-".repeat(70));
+            for entry in entries {
+                println!(
+                    "{:<16} {:<8} {:<10} {}",
+                    entry.alias,
+                    entry.arch.as_deref().unwrap_or("any"),
+                    entry.ssh_user.as_deref().unwrap_or("-"),
+                    entry.description.as_deref().unwrap_or("")
+                );
+            }
+        }
+        ImageAction::Export(export) => export_vm(export).await?,
+    }
+
+    Ok(())
+}
+
+/// Export the disk of a VM, pausing or stopping it first if it is running.
+async fn export_vm(args: ExportArgs) -> Result<()> {
+    let mut store = state::load_store().await?;
+    let handle = store
+        .get(&args.vm)
+        .ok_or_else(|| miette::miette!("VM '{}' not found", args.vm))?
+        .clone();
+    let disk = handle
+        .overlay_path
+        .clone()
+        .ok_or_else(|| miette::miette!("VM '{}' has no disk to export", args.vm))?;
+
+    let hv = RouterHypervisor::new(None, None);
+    let running = hv.state(&handle).await.into_diagnostic()? == VmState::Running;
+    let paused = running && !args.stop;
+    if running && args.stop {
+        println!("Stopping VM '{}'...", args.vm);
+        let updated = hv
+            .stop(&handle, Duration::from_secs(args.timeout))
+            .await
+            .into_diagnostic()?;
+        store.insert(args.vm.clone(), updated);
+        state::save_store(&store).await?;
+    } else if paused {
+        println!("Pausing VM '{}' while its disk is exported...", args.vm);
+        hv.suspend(&handle).await.into_diagnostic()?;
     }
 
+    let options = ExportOptions {
+        name: args.name.unwrap_or_else(|| args.vm.clone()),
+        format: args.format,
+        output: args.output,
+        compression: args.compress.unwrap_or(Compression::None),
+        shared: paused,
+    };
+    let mgr = vm_manager::image::ImageManager::new();
+    let exported = mgr.export(&args.vm, &disk, &options).await;
+    let resumed = if paused {
+        hv.resume(&handle).await
+    } else {
+        Ok(handle)
+    };
+    let image = match (exported, resumed) {
+        (Ok(image), Ok(_)) => image,
+        (Err(e), Ok(_)) => return Err(miette::Report::new(e)),
+        (exported, Err(resume)) => {
+            let outcome = match exported {
+                Ok(image) => format!("the export succeeded ({})", image.path.display()),
+                Err(e) => format!("the export failed as well: {e}"),
+            };
+            miette::bail!(
+                severity = miette::Severity::Error,
+                code = "vmctl::image::resume_failed",
+                help = "the VM is still paused; resume it with `vmctl resume {vm}`",
+                "could not resume VM '{vm}' after exporting its disk: {resume}; {outcome}",
+                vm = args.vm
+            );
+        }
+    };
+
+    println!(
+        "Exported VM '{}' as image '{}' ({}, {})",
+        args.vm,
+        options.name,
+        image.entry.digest,
+        format_size(image.entry.size_bytes)
+    );
+    println!("Image cached at: {}", image.path.display());
+    if let Some(output) = options.output {
+        println!("Image written to: {}", output.display());
+    }
     Ok(())
 }
+
+/// Image formats an export can produce.
+fn parse_export_format(s: &str) -> std::result::Result<ImageFormat, String> {
+    match s.parse()? {
+        ImageFormat::Vmdk => Err("images can be exported as qcow2 or raw".to_string()),
+        format => Ok(format),
+    }
+}
+
+/// VMs in the state store, whose base images must be kept.
+async fn vms() -> Result<Vec<VmHandle>> {
+    Ok(state::load_store().await?.into_values().collect())
+}
+
+fn format_size(bytes: u64) -> String {
+    if bytes >= 1_073_741_824 {
+        format!("{:.1} GB", bytes as f64 / 1_073_741_824.0)
+    } else {
+        format!("{:.1} MB", bytes as f64 / 1_048_576.0)
+    }
+}
+
+/// How long before `now` the Unix timestamp `then` was, e.g. `3d ago`.
+fn format_age(now: u64, then: u64) -> String {
+    let secs = now.saturating_sub(then);
+    match secs {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
@@ -16,6 +16,10 @@ pub struct LogArgs {
     #[arg(long)]
     provision: bool,
 
+    /// Show only the QEMU log (command line and startup errors)
+    #[arg(long)]
+    qemu: bool,
+
     /// Show the last N lines (0 = all)
     #[arg(long, short = 'n', default_value = "0")]
     tail: usize,
@@ -27,9 +31,15 @@ pub async fn run(args: LogArgs) -> Result<()> {
         .get(&args.name)
         .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
 
-    // If neither flag is set, show both
-    let show_console = args.console || !args.provision;
-    let show_provision = args.provision || !args.console;
+    // If no flag is set, show console and provision logs
+    let any = args.console || args.provision || args.qemu;
+    let show_console = args.console || !any;
+    let show_provision = args.provision || !any;
+
+    if args.qemu {
+        let path = handle.work_dir.join("qemu.log");
+        print_log("qemu", &path, args.tail).await?;
+    }
 
     if show_console {
         let path = handle.work_dir.join("console.log");
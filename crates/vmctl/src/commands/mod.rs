@@ -1,6 +1,7 @@
 pub mod console;
 pub mod create;
 pub mod destroy;
+pub mod doctor;
 pub mod down;
 pub mod image;
 pub mod list;
@@ -13,6 +14,7 @@ pub mod state;
 pub mod status;
 pub mod stop;
 pub mod up;
+pub mod wait;
 
 use clap::{Parser, Subcommand};
 use miette::Result;
@@ -59,6 +61,13 @@ enum Command {
     Provision(provision_cmd::ProvisionArgs),
     /// Show VM console and provision logs
     Log(log::LogArgs),
+    /// Wait until a VM's console prints a pattern
+    Wait(wait::WaitArgs),
+    /// Check the host for everything vmctl needs
+    Doctor(doctor::DoctorArgs),
+    /// Serve a VM's serial console to multiple clients (started by `vmctl console`)
+    #[command(hide = true)]
+    ConsoleBroker(console::BrokerArgs),
 }
 
 impl Cli {
@@ -80,10 +89,62 @@ impl Cli {
             Command::Reload(args) => reload::run(args).await,
             Command::Provision(args) => provision_cmd::run(args).await,
             Command::Log(args) => log::run(args).await,
+            Command::Wait(args) => wait::run(args).await,
+            Command::Doctor(args) => doctor::run(args).await,
+            Command::ConsoleBroker(args) => console::run_broker(args).await,
         }
     }
 }
 
+/// Parse a duration such as `90`, `90s`, `5m`, `1h` or `30d` (bare numbers are seconds).
+fn parse_duration(s: &str) -> std::result::Result<std::time::Duration, String> {
+    let s = s.trim();
+    let (digits, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid duration '{s}', expected e.g. 90s, 5m or 1h"))?;
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => {
+            return Err(format!(
+                "unknown unit '{unit}' in duration '{s}', use s, m, h, d or w"
+            ));
+        }
+    };
+    value
+        .checked_mul(scale)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| format!("duration '{s}' is too long"))
+}
+
+/// Parse a size such as `512M` or `50G` (binary units; bare numbers are bytes).
+fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    let (digits, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size '{s}', expected e.g. 512M or 50G"))?;
+    let shift = match unit.to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => {
+            return Err(format!(
+                "unknown unit '{unit}' in size '{s}', use K, M, G or T"
+            ));
+        }
+    };
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{s}' is too large"))
+}
+
 /// Determine the SSH port for a VM handle: use the forwarded host port for user-mode networking,
 /// or 22 for all other network types.
 fn ssh_port_for_handle(handle: &VmHandle) -> u16 {
@@ -55,7 +55,7 @@ fn lookup_vmfile(
     let vmfile = vm_manager::vmfile::parse(&path).ok()?;
     let def = vmfile.vms.iter().find(|d| d.name == vm_name)?;
     Some(VmFileInfo {
-        user: def.ssh.as_ref().map(|s| s.user.clone()),
+        user: def.ssh_user().map(String::from),
     })
 }
 
@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+use clap::Args;
+use miette::Result;
+use regex::Regex;
+use vm_manager::BackendTag;
+
+use super::state;
+
+#[derive(Args)]
+pub struct WaitArgs {
+    /// VM name
+    name: String,
+
+    /// Wait until the serial console prints text matching this regex
+    #[arg(long, value_name = "REGEX")]
+    console: String,
+
+    /// Give up after this long (e.g. 90s, 5m, 1h)
+    #[arg(long, default_value = "5m", value_parser = super::parse_duration)]
+    timeout: Duration,
+}
+
+pub async fn run(args: WaitArgs) -> Result<()> {
+    let store = state::load_store().await?;
+    let handle = store
+        .get(&args.name)
+        .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
+
+    if handle.backend == BackendTag::Propolis {
+        miette::bail!("waiting for console output is not supported for Propolis VMs");
+    }
+
+    let pattern =
+        Regex::new(&args.console).map_err(|e| miette::miette!("invalid --console pattern: {e}"))?;
+
+    let matched = vm_manager::console::wait_for_console(handle, &pattern, args.timeout)
+        .await
+        .map_err(miette::Report::new)?;
+    println!("{matched}");
+    Ok(())
+}